@@ -0,0 +1,695 @@
+//! Alerting rules with webhook notifications.
+//!
+//! A [`Rule`] watches the logs of one user: too many logs of a level within a window, a message
+//! matching a regex, or no log at all for a while. Logs stored by the services are passed to
+//! [`Alerts::observe`] and absences are checked every [`CHECK_INTERVAL`] by
+//! [`Alerts::check_absences`]. A firing rule POSTs an [`AlertEvent`] as JSON to its webhook, then
+//! stays quiet for its cooldown.
+//!
+//! Rules are managed through the admin routes of [`routes`] and, if the services are given a
+//! rules file, saved to it on every change and loaded at startup.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use warp::{http::StatusCode, reply::Response, Filter, Reply};
+
+use crate::{
+    auth::{self, Auth},
+    encoding,
+    server::{self, Routes},
+    IsLog, LogLevel,
+};
+
+/// How often the services look for absences.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Webhooks taking longer than this are abandoned.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Notifications waiting for their webhook, further ones are dropped.
+const PENDING_NOTIFICATIONS: usize = 1024;
+
+fn default_cooldown() -> u64 {
+    300
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+/// What a rule fires on.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    /// At least `count` logs of `level` within `window_secs`.
+    Threshold { level: LogLevel, count: u64, window_secs: u64 },
+    /// A message matching `regex`, only of `level` if given.
+    Pattern {
+        regex: String,
+        #[serde(default)]
+        level: Option<LogLevel>,
+    },
+    /// No log at all for `window_secs`.
+    Absence { window_secs: u64 },
+}
+
+/// A rule as created or updated through the API.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RuleSpec {
+    pub user_id: i64,
+    pub condition: Condition,
+    /// URL receiving the [`AlertEvent`]s.
+    pub webhook: String,
+    /// Minimum time between two notifications of the rule.
+    #[serde(default = "default_cooldown")]
+    pub cooldown_secs: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Rule {
+    pub id: u64,
+    #[serde(flatten)]
+    pub spec: RuleSpec,
+}
+
+/// The log that fired a pattern rule.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AlertLog {
+    pub level: LogLevel,
+    pub message: String,
+}
+
+/// Body of the webhook requests.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AlertEvent {
+    pub rule_id: u64,
+    pub user_id: i64,
+    pub condition: Condition,
+    pub fired_at: i64,
+    /// Logs counted by a threshold rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+    /// Log matched by a pattern rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<AlertLog>,
+    /// Last log seen by an absence rule, `None` if there was none since the rule was created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_log: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum RuleError {
+    Invalid(String),
+    NotFound,
+    /// The rules file could not be written.
+    Io(io::Error),
+}
+
+impl Reply for RuleError {
+    fn into_response(self) -> Response {
+        let (message, status) = match self {
+            Self::Invalid(error) => (error, StatusCode::BAD_REQUEST),
+            Self::NotFound => ("unknown rule".to_string(), StatusCode::NOT_FOUND),
+            Self::Io(error) => (error.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+        };
+
+        warp::reply::with_status(message, status).into_response()
+    }
+}
+
+impl RuleSpec {
+    fn validate(&self) -> Result<Option<Regex>, RuleError> {
+        let invalid = |error: String| Err(RuleError::Invalid(error));
+
+        match reqwest::Url::parse(&self.webhook) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => return invalid(format!("webhook {} is not an http(s) URL", self.webhook)),
+        }
+
+        match &self.condition {
+            Condition::Threshold { count: 0, .. } => invalid("threshold count must be positive".to_string()),
+            Condition::Threshold { window_secs: 0, .. } | Condition::Absence { window_secs: 0 } => {
+                invalid("window must be positive".to_string())
+            }
+            Condition::Pattern { regex, .. } => Regex::new(regex)
+                .map(Some)
+                .map_err(|error| RuleError::Invalid(error.to_string())),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// A rule and what it has seen so far.
+struct Armed {
+    rule: Rule,
+    regex: Option<Regex>,
+    /// Times of the logs counted by a threshold rule, within its window.
+    recent: VecDeque<i64>,
+    /// Time of the last log, or of the creation of the rule.
+    last_seen: i64,
+    last_log: Option<i64>,
+    last_fired: Option<i64>,
+    /// An absence rule already fired for the current silence.
+    silent: bool,
+}
+
+impl Armed {
+    fn new(rule: Rule, regex: Option<Regex>, time: i64) -> Self {
+        Self {
+            rule,
+            regex,
+            recent: VecDeque::new(),
+            last_seen: time,
+            last_log: None,
+            last_fired: None,
+            silent: false,
+        }
+    }
+
+    fn event(&self, time: i64) -> AlertEvent {
+        AlertEvent {
+            rule_id: self.rule.id,
+            user_id: self.rule.spec.user_id,
+            condition: self.rule.spec.condition.clone(),
+            fired_at: time,
+            count: None,
+            log: None,
+            last_log: None,
+        }
+    }
+
+    /// Records the firing at `time` unless the rule is cooling down.
+    fn fire(&mut self, time: i64) -> bool {
+        let cooled_down = self
+            .last_fired
+            .is_none_or(|fired| time - fired >= self.rule.spec.cooldown_secs as i64);
+
+        if cooled_down {
+            self.last_fired = Some(time);
+        }
+
+        cooled_down
+    }
+
+    fn observe(&mut self, time: i64, level: &LogLevel, message: &str) -> Option<AlertEvent> {
+        self.last_seen = self.last_seen.max(time);
+        self.last_log = Some(self.last_seen);
+        self.silent = false;
+
+        match &self.rule.spec.condition {
+            Condition::Threshold { level: watched, count, window_secs } if watched == level => {
+                let (count, window_secs) = (*count, *window_secs as i64);
+
+                self.recent.push_back(time);
+                while self.recent.front().is_some_and(|oldest| *oldest <= time - window_secs) {
+                    self.recent.pop_front();
+                }
+
+                if (self.recent.len() as u64) < count || !self.fire(time) {
+                    return None;
+                }
+
+                // The logs of this burst don't count towards the next one.
+                let counted = self.recent.drain(..).count() as u64;
+                Some(AlertEvent {
+                    count: Some(counted),
+                    ..self.event(time)
+                })
+            }
+            Condition::Pattern { level: watched, .. } => {
+                let matches = watched.as_ref().is_none_or(|watched| watched == level)
+                    && self.regex.as_ref().is_some_and(|regex| regex.is_match(message));
+
+                (matches && self.fire(time)).then(|| AlertEvent {
+                    log: Some(AlertLog {
+                        level: level.clone(),
+                        message: message.to_string(),
+                    }),
+                    ..self.event(time)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn check_absence(&mut self, time: i64) -> Option<AlertEvent> {
+        let Condition::Absence { window_secs } = self.rule.spec.condition else {
+            return None;
+        };
+
+        if self.silent || time - self.last_seen < window_secs as i64 || !self.fire(time) {
+            return None;
+        }
+
+        self.silent = true;
+        Some(AlertEvent {
+            last_log: self.last_log,
+            ..self.event(time)
+        })
+    }
+}
+
+struct Rules {
+    next_id: u64,
+    armed: BTreeMap<u64, Armed>,
+}
+
+impl Rules {
+    fn list(&self) -> Vec<Rule> {
+        self.armed.values().map(|armed| armed.rule.clone()).collect()
+    }
+}
+
+pub struct Alerts {
+    rules: Mutex<Rules>,
+    /// Held while a change is saved, so that changes are saved in order and only applied once
+    /// saved. The rules stay locked only to read and apply them.
+    changing: tokio::sync::Mutex<()>,
+    /// File the rules are saved to.
+    path: Option<PathBuf>,
+    notifications: mpsc::Sender<(String, AlertEvent)>,
+}
+
+impl Alerts {
+    /// Rules loaded from `path`, if given and existing, notifying their webhooks from a task
+    /// spawned on the current runtime.
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let saved = match &path {
+            Some(path) => match std::fs::read(path) {
+                Ok(saved) => serde_json::from_slice::<Vec<Rule>>(&saved)?,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => vec![],
+                Err(error) => return Err(error),
+            },
+            None => vec![],
+        };
+
+        let time = now();
+        let mut rules = Rules {
+            next_id: 1,
+            armed: BTreeMap::new(),
+        };
+
+        for rule in saved {
+            let regex = rule
+                .spec
+                .validate()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("rule {}: {:?}", rule.id, error)))?;
+
+            rules.next_id = rules.next_id.max(rule.id + 1);
+            rules.armed.insert(rule.id, Armed::new(rule, regex, time));
+        }
+
+        let (notifications, pending) = mpsc::channel(PENDING_NOTIFICATIONS);
+        tokio::spawn(deliver(pending));
+
+        Ok(Self {
+            rules: Mutex::new(rules),
+            changing: tokio::sync::Mutex::new(()),
+            path,
+            notifications,
+        })
+    }
+
+    /// Writes `rules` to the rules file, if any, on the blocking pool.
+    async fn save(&self, rules: Vec<Rule>) -> Result<(), RuleError> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let partial = path.with_extension("partial");
+            std::fs::write(&partial, serde_json::to_vec_pretty(&rules).unwrap()).and_then(|()| std::fs::rename(partial, path))
+        })
+        .await
+        .unwrap_or_else(|error| Err(io::Error::other(error)))
+        .map_err(RuleError::Io)
+    }
+
+    fn notify(&self, armed: &Armed, event: AlertEvent) {
+        if self.notifications.try_send((armed.rule.spec.webhook.clone(), event)).is_err() {
+            eprintln!("alerts: dropping notification of rule {}, too many pending", armed.rule.id);
+        }
+    }
+
+    pub fn list(&self) -> Vec<Rule> {
+        self.rules.lock().unwrap().list()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Rule> {
+        let rules = self.rules.lock().unwrap();
+        rules.armed.get(&id).map(|armed| armed.rule.clone())
+    }
+
+    pub async fn create(&self, spec: RuleSpec) -> Result<Rule, RuleError> {
+        let regex = spec.validate()?;
+        let _changing = self.changing.lock().await;
+
+        let (rule, mut saved) = {
+            let rules = self.rules.lock().unwrap();
+            (Rule { id: rules.next_id, spec }, rules.list())
+        };
+        saved.push(rule.clone());
+        self.save(saved).await?;
+
+        let mut rules = self.rules.lock().unwrap();
+        rules.next_id += 1;
+        rules.armed.insert(rule.id, Armed::new(rule.clone(), regex, now()));
+
+        Ok(rule)
+    }
+
+    /// Replaces the rule `id`, which starts over as if it was just created.
+    pub async fn update(&self, id: u64, spec: RuleSpec) -> Result<Rule, RuleError> {
+        let regex = spec.validate()?;
+        let _changing = self.changing.lock().await;
+        let rule = Rule { id, spec };
+
+        let saved = {
+            let rules = self.rules.lock().unwrap();
+            if !rules.armed.contains_key(&id) {
+                return Err(RuleError::NotFound);
+            }
+
+            let saved = rules.armed.values().map(|armed| match armed.rule.id == id {
+                true => rule.clone(),
+                false => armed.rule.clone(),
+            });
+            saved.collect()
+        };
+        self.save(saved).await?;
+
+        let mut rules = self.rules.lock().unwrap();
+        rules.armed.insert(id, Armed::new(rule.clone(), regex, now()));
+
+        Ok(rule)
+    }
+
+    pub async fn delete(&self, id: u64) -> Result<(), RuleError> {
+        let _changing = self.changing.lock().await;
+
+        let saved = {
+            let rules = self.rules.lock().unwrap();
+            if !rules.armed.contains_key(&id) {
+                return Err(RuleError::NotFound);
+            }
+
+            rules.list().into_iter().filter(|rule| rule.id != id).collect()
+        };
+        self.save(saved).await?;
+
+        self.rules.lock().unwrap().armed.remove(&id);
+        Ok(())
+    }
+
+    /// Whether rules watch the logs of `user_id`, for the services to keep a copy of a log to
+    /// [`observe`](Self::observe) once it is stored.
+    pub fn watches(&self, user_id: i64) -> bool {
+        let rules = self.rules.lock().unwrap();
+        rules.armed.values().any(|armed| armed.rule.spec.user_id == user_id)
+    }
+
+    /// Evaluates the threshold and pattern rules of `user_id` against a log stored now.
+    pub fn observe<L: IsLog>(&self, user_id: i64, log: &L) {
+        self.observe_at(user_id, now(), log)
+    }
+
+    fn observe_at<L: IsLog>(&self, user_id: i64, time: i64, log: &L) {
+        let mut rules = self.rules.lock().unwrap();
+        if !rules.armed.values().any(|armed| armed.rule.spec.user_id == user_id) {
+            return;
+        }
+
+        let (level, message) = (log.level(), log.message());
+
+        for armed in rules.armed.values_mut().filter(|armed| armed.rule.spec.user_id == user_id) {
+            if let Some(event) = armed.observe(time, &level, &message) {
+                self.notify(armed, event)
+            }
+        }
+    }
+
+    /// Fires the absence rules whose user has been silent for their window at `time`.
+    pub fn check_absences(&self, time: i64) {
+        let mut rules = self.rules.lock().unwrap();
+
+        for armed in rules.armed.values_mut() {
+            if let Some(event) = armed.check_absence(time) {
+                self.notify(armed, event)
+            }
+        }
+    }
+
+    /// Checks the absences every [`CHECK_INTERVAL`], forever.
+    pub async fn run_checks(&self) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            self.check_absences(now());
+        }
+    }
+}
+
+async fn deliver(mut pending: mpsc::Receiver<(String, AlertEvent)>) {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .unwrap();
+
+    while let Some((webhook, event)) = pending.recv().await {
+        let sent = client
+            .post(&webhook)
+            .json(&event)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        if let Err(e) = sent {
+            eprintln!("alerts: webhook of rule {} failed: {}", event.rule_id, e);
+        }
+    }
+}
+
+fn with_alerts(alerts: Arc<Alerts>) -> impl Filter<Extract = (Arc<Alerts>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || alerts.clone())
+}
+
+fn rule_reply(result: Result<Rule, RuleError>, status: StatusCode) -> Response {
+    match result {
+        Ok(rule) => warp::reply::with_status(serde_json::to_string(&rule).unwrap(), status).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+/// Admin CRUD of the rules: `GET`/`POST /alerts` and `GET`/`PUT`/`DELETE /alerts/{id}`.
+pub fn routes(alerts: Arc<Alerts>, auth: Arc<Auth>) -> Routes {
+    let list = warp::path!("alerts")
+        .and(warp::get())
+        .and(with_alerts(alerts.clone()))
+        .map(|alerts: Arc<Alerts>| {
+            warp::reply::with_status(serde_json::to_string(&alerts.list()).unwrap(), StatusCode::OK).into_response()
+        });
+
+    let create = warp::path!("alerts")
+        .and(warp::post())
+        .and(encoding::json())
+        .and(with_alerts(alerts.clone()))
+        .then(|spec: RuleSpec, alerts: Arc<Alerts>| async move { rule_reply(alerts.create(spec).await, StatusCode::CREATED) });
+
+    let get = warp::path!("alerts" / u64)
+        .and(warp::get())
+        .and(with_alerts(alerts.clone()))
+        .map(|id, alerts: Arc<Alerts>| rule_reply(alerts.get(id).ok_or(RuleError::NotFound), StatusCode::OK));
+
+    let update = warp::path!("alerts" / u64)
+        .and(warp::put())
+        .and(encoding::json())
+        .and(with_alerts(alerts.clone()))
+        .then(|id, spec: RuleSpec, alerts: Arc<Alerts>| async move { rule_reply(alerts.update(id, spec).await, StatusCode::OK) });
+
+    let delete = warp::path!("alerts" / u64)
+        .and(warp::delete())
+        .and(with_alerts(alerts))
+        .then(|id, alerts: Arc<Alerts>| async move {
+            match alerts.delete(id).await {
+                Ok(()) => warp::reply::with_status("success", StatusCode::OK).into_response(),
+                Err(error) => error.into_response(),
+            }
+        });
+
+    let crud = list
+        .or(create)
+        .unify()
+        .or(get)
+        .unify()
+        .or(update)
+        .unify()
+        .or(delete)
+        .unify();
+
+    server::routes(auth::admin(auth).and(crud))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    use super::{AlertEvent, Alerts, Condition, RuleError, RuleSpec};
+    use crate::{
+        auth::Auth,
+        test::{log, TestLog},
+        LogLevel,
+    };
+
+    /// Alerts notifying a local webhook, with the events it receives.
+    async fn webhook() -> (Alerts, String, mpsc::UnboundedReceiver<AlertEvent>) {
+        let (received, events) = mpsc::unbounded_channel();
+        let hook = warp::post().and(warp::body::json()).map(move |event: AlertEvent| {
+            received.send(event).unwrap();
+            "ok"
+        });
+
+        let (addr, server) = warp::serve(hook).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        (Alerts::open(None).unwrap(), format!("http://{}/hook", addr), events)
+    }
+
+    fn spec(webhook: &str, condition: Condition) -> RuleSpec {
+        RuleSpec {
+            user_id: 1,
+            condition,
+            webhook: webhook.to_string(),
+            cooldown_secs: 60,
+        }
+    }
+
+    #[tokio::test]
+    async fn fires_with_cooldown() {
+        let (alerts, webhook, mut events) = webhook().await;
+
+        let threshold = alerts
+            .create(spec(&webhook, Condition::Threshold { level: LogLevel::Error, count: 3, window_secs: 10 }))
+            .await
+            .unwrap();
+        let pattern = alerts
+            .create(spec(&webhook, Condition::Pattern { regex: "^disk (full|failure)".to_string(), level: None }))
+            .await
+            .unwrap();
+
+        // Errors spread over more than the window, then a burst.
+        for time in [100, 110, 120, 121, 122] {
+            alerts.observe_at::<TestLog>(1, time, &log(LogLevel::Error, "boom"));
+        }
+        alerts.observe_at::<TestLog>(2, 122, &log(LogLevel::Error, "disk full"));
+        alerts.observe_at::<TestLog>(1, 123, &log(LogLevel::Debug, "disk full"));
+        alerts.observe_at::<TestLog>(1, 124, &log(LogLevel::Debug, "disk failure"));
+
+        let fired = events.recv().await.unwrap();
+        assert_eq!((fired.rule_id, fired.fired_at, fired.count), (threshold.id, 122, Some(3)));
+
+        let fired = events.recv().await.unwrap();
+        assert_eq!((fired.rule_id, fired.fired_at), (pattern.id, 123));
+        assert_eq!(fired.log.unwrap().message, "disk full");
+
+        // Another burst within the cooldown, then after it.
+        for time in [130, 131, 132, 190, 191, 192] {
+            alerts.observe_at::<TestLog>(1, time, &log(LogLevel::Error, "boom"));
+        }
+
+        let fired = events.recv().await.unwrap();
+        assert_eq!((fired.rule_id, fired.fired_at), (threshold.id, 192));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn fires_once_per_absence() {
+        let (alerts, webhook, mut events) = webhook().await;
+        let rule = alerts
+            .create(RuleSpec {
+                cooldown_secs: 0,
+                ..spec(&webhook, Condition::Absence { window_secs: 600 })
+            })
+            .await
+            .unwrap();
+        // Past the creation of the rule, so that the clock doesn't matter.
+        let start = super::now() + 1000;
+        alerts.observe_at::<TestLog>(1, start, &log(LogLevel::Debug, "last"));
+
+        alerts.check_absences(start + 599);
+        alerts.check_absences(start + 600);
+        alerts.check_absences(start + 700);
+        alerts.observe_at::<TestLog>(1, start + 800, &log(LogLevel::Debug, "back"));
+        alerts.check_absences(start + 1400);
+
+        let fired = events.recv().await.unwrap();
+        assert_eq!((fired.rule_id, fired.fired_at, fired.last_log), (rule.id, start + 600, Some(start)));
+
+        let fired = events.recv().await.unwrap();
+        assert_eq!((fired.fired_at, fired.last_log), (start + 1400, Some(start + 800)));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn crud_routes() {
+        let path = std::env::temp_dir().join(format!("zephyr-alerts-{}.json", std::process::id()));
+        let alerts = Arc::new(Alerts::open(Some(path.clone())).unwrap());
+        let routes = super::routes(alerts, Arc::new(Auth::disabled()));
+
+        let created = warp::test::request()
+            .method("POST")
+            .path("/alerts")
+            .body(r#"{"user_id":1,"condition":{"kind":"absence","window_secs":60},"webhook":"http://localhost/hook"}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(created.status(), 201);
+        assert_eq!(
+            std::str::from_utf8(created.body()).unwrap(),
+            r#"{"id":1,"user_id":1,"condition":{"kind":"absence","window_secs":60},"webhook":"http://localhost/hook","cooldown_secs":300}"#
+        );
+
+        let invalid = warp::test::request()
+            .method("PUT")
+            .path("/alerts/1")
+            .body(r#"{"user_id":1,"condition":{"kind":"pattern","regex":"("},"webhook":"http://localhost/hook"}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(invalid.status(), 400);
+
+        // Saved rules are loaded back.
+        let reopened = Alerts::open(Some(path.clone())).unwrap();
+        assert_eq!(reopened.list().len(), 1);
+
+        let deleted = warp::test::request().method("DELETE").path("/alerts/1").reply(&routes).await;
+        assert_eq!(deleted.status(), 200);
+        let missing = warp::test::request().path("/alerts/1").reply(&routes).await;
+        assert_eq!(missing.status(), 404);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_unsaved_changes() {
+        // The rules file cannot be written, its directory doesn't exist.
+        let path = std::env::temp_dir().join(format!("zephyr-alerts-{}", std::process::id())).join("rules.json");
+        let alerts = Alerts::open(Some(path)).unwrap();
+        let spec = spec("http://localhost/hook", Condition::Absence { window_secs: 60 });
+
+        assert!(matches!(alerts.create(spec.clone()).await, Err(RuleError::Io(_))));
+        assert!(alerts.list().is_empty());
+        assert!(!alerts.watches(1));
+        assert!(matches!(alerts.update(1, spec).await, Err(RuleError::NotFound)));
+    }
+}
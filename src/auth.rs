@@ -0,0 +1,244 @@
+//! Token authentication for the HTTP routes.
+//!
+//! Tokens are `{subject}.{expires}.{signature}` where the subject is either a user id or `admin`,
+//! `expires` the Unix time after which the token is refused and the signature the hex HMAC-SHA256
+//! of `{subject}.{expires}` under the service secret. A user token can only read and write its
+//! own logs, the admin token can do anything.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use warp::{http::StatusCode, reject::Reject, Filter, Rejection, Reply};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ADMIN_SUBJECT: &str = "admin";
+
+/// Lifetime of the issued tokens unless set, 30 days.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    User(i64),
+    Admin,
+    /// Authentication is disabled, everything is allowed.
+    Anonymous,
+}
+
+impl Scope {
+    fn subject(&self) -> String {
+        match self {
+            Self::User(user_id) => user_id.to_string(),
+            _ => ADMIN_SUBJECT.to_string(),
+        }
+    }
+
+    /// Whether the bearer may read and write the logs of `user_id`.
+    pub fn can_access(&self, user_id: i64) -> bool {
+        match self {
+            Self::User(owner) => *owner == user_id,
+            Self::Admin | Self::Anonymous => true,
+        }
+    }
+
+    fn is_admin(&self) -> bool {
+        matches!(self, Self::Admin | Self::Anonymous)
+    }
+}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl Reject for Forbidden {}
+
+pub struct Auth {
+    secret: Option<Vec<u8>>,
+    token_ttl: Duration,
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Auth {
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        Self {
+            secret: Some(secret.as_ref().to_vec()),
+            token_ttl: DEFAULT_TOKEN_TTL,
+        }
+    }
+
+    /// Every request is allowed. Only meant for local development.
+    pub fn disabled() -> Self {
+        Self {
+            secret: None,
+            token_ttl: DEFAULT_TOKEN_TTL,
+        }
+    }
+
+    /// Lifetime of the tokens issued from now on.
+    pub fn with_token_ttl(mut self, token_ttl: Duration) -> Self {
+        self.token_ttl = token_ttl;
+        self
+    }
+
+    fn mac(secret: &[u8], signed: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(signed.as_bytes());
+        mac
+    }
+
+    /// Signs a token for `scope` expiring after the token lifetime, `None` if authentication is
+    /// disabled.
+    pub fn issue(&self, scope: Scope) -> Option<String> {
+        self.issue_at(scope, now())
+    }
+
+    fn issue_at(&self, scope: Scope, now: u64) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let signed = format!("{}.{}", scope.subject(), now.saturating_add(self.token_ttl.as_secs()));
+        let signature = Self::mac(secret, &signed).finalize().into_bytes();
+
+        Some(format!("{}.{}", signed, hex::encode(signature)))
+    }
+
+    pub fn verify(&self, token: Option<&str>) -> Option<Scope> {
+        self.verify_at(token, now())
+    }
+
+    fn verify_at(&self, token: Option<&str>, now: u64) -> Option<Scope> {
+        let Some(secret) = &self.secret else {
+            return Some(Scope::Anonymous);
+        };
+
+        let (signed, signature) = token?.rsplit_once('.')?;
+        let signature = hex::decode(signature).ok()?;
+        Self::mac(secret, signed).verify_slice(&signature).ok()?;
+
+        let (subject, expires) = signed.rsplit_once('.')?;
+        if expires.parse::<u64>().ok()? < now {
+            return None;
+        }
+
+        if subject == ADMIN_SUBJECT {
+            Some(Scope::Admin)
+        } else {
+            subject.parse().ok().map(Scope::User)
+        }
+    }
+}
+
+/// Extracts the scope of the bearer token, rejecting requests without a valid one.
+pub fn scope(auth: Arc<Auth>) -> impl Filter<Extract = (Scope,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+        let auth = auth.clone();
+
+        async move {
+            let token = header
+                .as_deref()
+                .and_then(|header| header.strip_prefix("Bearer "));
+
+            auth.verify(token)
+                .ok_or_else(|| warp::reject::custom(Unauthorized))
+        }
+    })
+}
+
+/// Passes `user_id` through if `scope` can access it. Meant for `.and_then` after
+/// a path extracting the user id and [`scope`].
+pub async fn user(user_id: i64, scope: Scope) -> Result<i64, Rejection> {
+    if scope.can_access(user_id) {
+        Ok(user_id)
+    } else {
+        Err(warp::reject::custom(Forbidden))
+    }
+}
+
+/// Rejects requests without an admin token.
+pub fn admin(auth: Arc<Auth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    scope(auth)
+        .and_then(|scope: Scope| async move {
+            if scope.is_admin() {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(Forbidden))
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Serialize)]
+struct AuthError {
+    error: &'static str,
+}
+
+/// Turns auth rejections into 401/403 responses.
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    let (error, status) = if rejection.find::<Unauthorized>().is_some() {
+        ("missing or invalid token", StatusCode::UNAUTHORIZED)
+    } else if rejection.find::<Forbidden>().is_some() {
+        ("token not allowed for this resource", StatusCode::FORBIDDEN)
+    } else {
+        return Err(rejection);
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&AuthError { error }),
+        status,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use hmac::Mac;
+
+    use super::{Auth, Scope};
+
+    #[test]
+    fn tokens_expire() {
+        let auth = Auth::new("secret").with_token_ttl(Duration::from_secs(60));
+        let token = auth.issue_at(Scope::User(7), 1_000).unwrap();
+        assert!(token.starts_with("7.1060."), "{}", token);
+
+        assert_eq!(auth.verify_at(Some(&token), 1_060), Some(Scope::User(7)));
+        assert_eq!(auth.verify_at(Some(&token), 1_061), None);
+        assert_eq!(auth.verify_at(Some(&token.replace(".1060.", ".9999.")), 1_000), None);
+
+        // Tokens without an expiry are refused.
+        let legacy = format!("7.{}", hex::encode(Auth::mac(b"secret", "7").finalize().into_bytes()));
+        assert_eq!(auth.verify_at(Some(&legacy), 1_000), None);
+    }
+
+    #[test]
+    fn tokens_are_scoped() {
+        let auth = Auth::new("secret");
+        let user = auth.issue(Scope::User(7)).unwrap();
+        let admin = auth.issue(Scope::Admin).unwrap();
+
+        assert_eq!(auth.verify(Some(&user)), Some(Scope::User(7)));
+        assert_eq!(auth.verify(Some(&admin)), Some(Scope::Admin));
+        assert_eq!(auth.verify(Some(&user.replace("7.", "8."))), None);
+        assert_eq!(auth.verify(None), None);
+        assert_eq!(Auth::new("other").verify(Some(&user)), None);
+
+        assert!(Scope::User(7).can_access(7));
+        assert!(!Scope::User(7).can_access(8));
+        assert!(!Scope::User(7).is_admin());
+    }
+}
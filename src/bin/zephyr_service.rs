@@ -1,8 +1,38 @@
-use std::sync::Arc;
-
-use multiuser_logging_service::{IsLog, LogLevel, LoggerMemory};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use multiuser_logging_service::{
+    alerts::{self, Alerts},
+    auth::{self, Scope},
+    compress::Compression,
+    config::{Config, Service},
+    encoding,
+    export::{self, Format},
+    health,
+    limits::Limiter,
+    metrics::{self, Metrics},
+    otlp,
+    payload::base64_data,
+    server,
+    subscribers::{self, Subscribers},
+    syslog,
+    tcp,
+    wal::{Fsync, Wal},
+    ContentType, FieldFilter, HistogramQuery, Fields, IsLog, LogLevel, LogSource,
+    LoggerMemory, LoggerStorage, ServiceLog, TraceContext,
+};
+use bincode::Options;
+use bytes::Bytes;
+use futures_util::FutureExt;
 use serde::{Deserialize, Serialize};
-use warp::{reject::Rejection, reply::WithStatus, Filter};
+use warp::{
+    reject::Rejection,
+    reply::{Response, WithStatus},
+    Filter, Reply,
+};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 struct LogClientRequest {
@@ -13,7 +43,50 @@ struct LogClientRequest {
 pub struct ZephyrLog {
     pub level: LogLevel,
     pub message: String,
+    #[serde(with = "base64_data")]
     pub data: Option<Vec<u8>>,
+    #[serde(default)]
+    pub fields: Fields,
+    #[serde(default)]
+    pub content_type: Option<ContentType>,
+    #[serde(default)]
+    pub source: Option<LogSource>,
+    #[serde(default)]
+    pub trace: Option<TraceContext>,
+}
+
+/// `ZephyrLog` as encoded by the clients that predate fields, content types, sources and traces.
+/// Bincode has no optional trailing fields, so their payloads don't decode as the current layout.
+#[derive(Deserialize)]
+struct LegacyZephyrLog {
+    level: LogLevel,
+    message: String,
+    data: Option<Vec<u8>>,
+}
+
+impl ZephyrLog {
+    /// Decodes the bincode `serialized` payload of an ingested log, in the current layout or
+    /// the legacy one.
+    fn decode(serialized: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(serialized).or_else(|error| {
+            // A malformed payload in the current layout must not pass for a legacy one.
+            let legacy = bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .reject_trailing_bytes()
+                .deserialize::<LegacyZephyrLog>(serialized)
+                .map_err(|_| error)?;
+
+            Ok(Self {
+                level: legacy.level,
+                message: legacy.message,
+                data: legacy.data,
+                fields: Fields::new(),
+                content_type: None,
+                source: None,
+                trace: None,
+            })
+        })
+    }
 }
 
 impl IsLog for ZephyrLog {
@@ -28,6 +101,22 @@ impl IsLog for ZephyrLog {
     fn level(&self) -> LogLevel {
         self.level.clone()
     }
+
+    fn fields(&self) -> Fields {
+        self.fields.clone()
+    }
+
+    fn content_type(&self) -> Option<ContentType> {
+        self.content_type.clone()
+    }
+
+    fn source(&self) -> Option<LogSource> {
+        self.source.clone()
+    }
+
+    fn trace(&self) -> Option<TraceContext> {
+        self.trace.clone()
+    }
 }
 
 fn with_db(
@@ -37,89 +126,490 @@ fn with_db(
     warp::any().map(move || db.clone())
 }
 
+fn with_metrics(
+    metrics: Arc<Metrics>,
+) -> impl Filter<Extract = (Arc<Metrics>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || metrics.clone())
+}
+
+fn with_alerts(
+    alerts: Arc<Alerts>,
+) -> impl Filter<Extract = (Arc<Alerts>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || alerts.clone())
+}
+
+fn with_limiter(
+    limiter: Arc<Limiter>,
+) -> impl Filter<Extract = (Arc<Limiter>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+/// Reply to a write the WAL couldn't journal, it wasn't applied.
+fn wal_unavailable(error: std::io::Error) -> WithStatus<String> {
+    warp::reply::with_status(format!("wal error: {}", error), warp::http::StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// Writes `log`, then passes it to the alerting rules if it was stored.
+async fn write_observed(
+    logger: &LoggerMemory<ZephyrLog>,
+    alerts: &Alerts,
+    user_id: i64,
+    log: ZephyrLog,
+) -> std::io::Result<()> {
+    let watched = alerts.watches(user_id).then(|| log.clone());
+
+    if logger.write_log(user_id, log).await? {
+        if let Some(log) = watched {
+            alerts.observe(user_id, &log)
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
-    let logger: LoggerMemory<ZephyrLog> = LoggerMemory::new();
+    let config = Config::load(SocketAddr::from(([0, 0, 0, 0], 8082)), Service::Memory);
+
+    let metrics = Arc::new(Metrics::new());
+    let subscribers = Arc::new(Subscribers::default());
+    let mut logger: LoggerMemory<ZephyrLog> = LoggerMemory::new()
+        .with_metrics(metrics.clone())
+        .with_subscribers(subscribers.clone());
+
+    if let Some(level) = config.compression_level {
+        logger = logger.with_compression(Compression {
+            level,
+            ..Default::default()
+        });
+    }
+
+    if let Some(wal) = &config.wal {
+        match Wal::open(&wal.dir, wal.options.clone()) {
+            Ok(wal) => logger = logger.with_wal(wal),
+            Err(e) => {
+                eprintln!("cannot open wal {}: {}", wal.dir.display(), e);
+                std::process::exit(1)
+            }
+        }
+    }
+
+    let mut wal_segment = 0;
+    if let Some(snapshot) = &config.snapshot {
+        match logger.restore_snapshot(&snapshot.path).await {
+            Ok(Some(restored)) => {
+                eprintln!("restored {} users from {}", restored.users, snapshot.path.display());
+                wal_segment = restored.wal_segment;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("cannot restore snapshot {}: {}", snapshot.path.display(), e);
+                std::process::exit(1)
+            }
+        }
+    }
+
+    match logger.replay_wal(wal_segment).await {
+        Ok(0) => {}
+        Ok(replayed) => eprintln!("replayed {} wal records", replayed),
+        Err(e) => {
+            eprintln!("cannot replay wal: {}", e);
+            std::process::exit(1)
+        }
+    }
+
     let arc = Arc::new(logger);
+    let limiter = Arc::new(Limiter::new(config.limits.clone()));
+
+    tokio::spawn({
+        let limiter = limiter.clone();
+
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+            loop {
+                interval.tick().await;
+                limiter.evict_idle().await;
+            }
+        }
+    });
+
+    let auth = Arc::new(config.auth());
+
+    let alerts = match Alerts::open(config.alert_rules.clone()) {
+        Ok(alerts) => Arc::new(alerts),
+        Err(e) => {
+            eprintln!("cannot load alert rules: {}", e);
+            std::process::exit(1)
+        }
+    };
+
+    tokio::spawn({
+        let alerts = alerts.clone();
+        async move { alerts.run_checks().await }
+    });
+
+    if let Some(syslog_config) = config.syslog.clone() {
+        let mut messages = match syslog::listen(syslog_config, metrics.clone()).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                eprintln!("cannot listen for syslog: {}", e);
+                std::process::exit(1)
+            }
+        };
+
+        let (logger, limiter, alerts, metrics) = (arc.clone(), limiter.clone(), alerts.clone(), metrics.clone());
+        tokio::spawn(async move {
+            while let Some((user_id, message)) = messages.recv().await {
+                let log = ZephyrLog {
+                    level: message.level(),
+                    fields: message.fields(),
+                    source: Some(message.source()),
+                    message: message.message,
+                    data: None,
+                    content_type: None,
+                    trace: None,
+                };
+
+                if limiter.check(user_id, &log).await.is_err() {
+                    metrics.log_rejected("limit_exceeded");
+                    continue;
+                }
+
+                // Failures are counted and reported by the logger, syslog cannot be told.
+                let _ = write_observed(&logger, &alerts, user_id, log).await;
+            }
+        });
+    }
+
+    if let Some(snapshot) = &config.snapshot {
+        if let Some(period) = snapshot.interval {
+            let logger = arc.clone();
+            let path = snapshot.path.clone();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                // The first tick completes immediately.
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+
+                    if let Err(e) = logger.save_snapshot(&path).await {
+                        eprintln!("snapshot error: {}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    if let Some(Fsync::Periodic(period)) = config.wal.as_ref().map(|wal| wal.options.fsync) {
+        let logger = arc.clone();
+
+        // Appends only sync once the interval has passed, this syncs the last records when no more come.
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+
+                let logger = logger.clone();
+                match tokio::task::spawn_blocking(move || logger.sync_wal()).await {
+                    Ok(Err(e)) => eprintln!("wal error: {}", e),
+                    Err(e) => eprintln!("wal sync task failed: {}", e),
+                    Ok(Ok(())) => {}
+                }
+            }
+        });
+    }
+
+    if let Some(retention) = config.retention {
+        let logger = arc.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+            loop {
+                interval.tick().await;
+
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                logger.prune(now.saturating_sub(retention).as_secs() as i64).await;
+            }
+        });
+    }
 
     let get_errors = warp::path!("error" / i64)
         .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(warp::query::<FieldFilter>())
+        .and(export::format())
         .and(with_db(arc.clone()))
         .and_then(
-            move |user_id, state: Arc<LoggerMemory<ZephyrLog>>| async move {
-                let errors = state.read_errros(user_id).await;
-
-                Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
-                    serde_json::to_string(&errors).unwrap(),
-                    warp::http::StatusCode::OK,
-                ))
+            move |user_id, filter: FieldFilter, format: Format, state: Arc<LoggerMemory<ZephyrLog>>| async move {
+                let (logs, codecs) = (state.snapshot(user_id, LogLevel::Error).await, state.codecs());
+                let logs = logs
+                    .into_logs()
+                    .filter(move |log| log.matches(&filter))
+                    .map(move |log| ServiceLog::render(&log, &codecs));
+
+                Ok::<Response, Rejection>(export::reply(format, logs))
             },
         );
 
     let get_warning = warp::path!("warning" / i64)
         .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(warp::query::<FieldFilter>())
+        .and(export::format())
         .and(with_db(arc.clone()))
         .and_then(
-            move |user_id, state: Arc<LoggerMemory<ZephyrLog>>| async move {
-                let errors = state.read_warning(user_id).await;
-
-                Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
-                    serde_json::to_string(&errors).unwrap(),
-                    warp::http::StatusCode::OK,
-                ))
+            move |user_id, filter: FieldFilter, format: Format, state: Arc<LoggerMemory<ZephyrLog>>| async move {
+                let (logs, codecs) = (state.snapshot(user_id, LogLevel::Warning).await, state.codecs());
+                let logs = logs
+                    .into_logs()
+                    .filter(move |log| log.matches(&filter))
+                    .map(move |log| ServiceLog::render(&log, &codecs));
+
+                Ok::<Response, Rejection>(export::reply(format, logs))
             },
         );
 
     let get_debug = warp::path!("debug" / i64)
         .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(warp::query::<FieldFilter>())
+        .and(export::format())
         .and(with_db(arc.clone()))
         .and_then(
-            move |user_id, state: Arc<LoggerMemory<ZephyrLog>>| async move {
-                let errors = state.read_debug(user_id).await;
-
-                Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
-                    serde_json::to_string(&errors).unwrap(),
-                    warp::http::StatusCode::OK,
-                ))
+            move |user_id, filter: FieldFilter, format: Format, state: Arc<LoggerMemory<ZephyrLog>>| async move {
+                let (logs, codecs) = (state.snapshot(user_id, LogLevel::Debug).await, state.codecs());
+                let logs = logs
+                    .into_logs()
+                    .filter(move |log| log.matches(&filter))
+                    .map(move |log| ServiceLog::render(&log, &codecs));
+
+                Ok::<Response, Rejection>(export::reply(format, logs))
             },
         );
 
     let add_log = warp::path!("log" / i64)
         .and(warp::post())
-        .and(warp::body::json())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(encoding::json())
         .and(with_db(arc.clone()))
+        .and(with_limiter(limiter.clone()))
+        .and(with_metrics(metrics.clone()))
+        .and(with_alerts(alerts.clone()))
         .and_then(
-            move |user_id, log: LogClientRequest, state: Arc<LoggerMemory<ZephyrLog>>| async move {
-                let deserialized = bincode::deserialize(&log.serialized).unwrap();
-                state.write_log(user_id, deserialized).await;
-
-                Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
-                    "success".into(),
-                    warp::http::StatusCode::CREATED,
-                ))
+            move |user_id,
+                  log: LogClientRequest,
+                  state: Arc<LoggerMemory<ZephyrLog>>,
+                  limiter: Arc<Limiter>,
+                  metrics: Arc<Metrics>,
+                  alerts: Arc<Alerts>| async move {
+                let Ok(deserialized) = ZephyrLog::decode(&log.serialized) else {
+                    metrics.log_rejected("invalid_payload");
+                    return Ok::<Response, Rejection>(
+                        warp::reply::with_status("invalid payload", warp::http::StatusCode::BAD_REQUEST)
+                            .into_response(),
+                    );
+                };
+
+                if let Err(exceeded) = limiter.check(user_id, &deserialized).await {
+                    metrics.log_rejected("limit_exceeded");
+                    return Ok::<Response, Rejection>(exceeded.into_response());
+                }
+
+                if let Err(e) = write_observed(&state, &alerts, user_id, deserialized).await {
+                    return Ok::<Response, Rejection>(wal_unavailable(e).into_response());
+                }
+
+                Ok::<Response, Rejection>(
+                    warp::reply::with_status("success", warp::http::StatusCode::CREATED)
+                        .into_response(),
+                )
             },
         );
 
+    let otlp_logs = warp::path!("v1" / "logs")
+        .and(warp::post())
+        .and(auth::scope(auth.clone()))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(encoding::body())
+        .and(with_db(arc.clone()))
+        .and(with_limiter(limiter.clone()))
+        .and(with_metrics(metrics.clone()))
+        .and(with_alerts(alerts.clone()))
+        .and_then({
+            let user_id_attribute = config
+                .otlp_user_attribute
+                .clone()
+                .unwrap_or_else(|| otlp::DEFAULT_USER_ID_ATTRIBUTE.to_string());
+            move |scope: Scope,
+                  content_type: Option<String>,
+                  body: Bytes,
+                  state: Arc<LoggerMemory<ZephyrLog>>,
+                  limiter: Arc<Limiter>,
+                  metrics: Arc<Metrics>,
+                  alerts: Arc<Alerts>| {
+                let user_id_attribute = user_id_attribute.clone();
+                async move {
+                    let Some(format) = content_type.as_deref().and_then(otlp::Format::from_content_type) else {
+                        return Ok::<Response, Rejection>(
+                            warp::reply::with_status(
+                                "expected application/x-protobuf or application/json",
+                                warp::http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                            )
+                            .into_response(),
+                        );
+                    };
+
+                    let request = match format.decode(&body) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            metrics.log_rejected("invalid_payload");
+                            return Ok(format.error(warp::http::StatusCode::BAD_REQUEST, e));
+                        }
+                    };
+
+                    let (records, unassigned) = otlp::records(request, &user_id_attribute);
+                    if let Some(record) = records.iter().find(|record| !scope.can_access(record.user_id)) {
+                        return Ok(format.error(
+                            warp::http::StatusCode::FORBIDDEN,
+                            format!("cannot write the logs of user {}", record.user_id),
+                        ));
+                    }
+
+                    let mut errors = Vec::new();
+                    if unassigned > 0 {
+                        (0..unassigned).for_each(|_| metrics.log_rejected("otlp_unknown_user"));
+                        errors.push(format!("{} records without a {} resource attribute", unassigned, user_id_attribute));
+                    }
+
+                    let mut exceeded = 0;
+                    for record in records {
+                        let log = ZephyrLog {
+                            level: record.level,
+                            message: record.message,
+                            data: None,
+                            fields: record.fields,
+                            content_type: None,
+                            source: Some(record.source),
+                            trace: record.trace,
+                        };
+
+                        if limiter.check(record.user_id, &log).await.is_err() {
+                            metrics.log_rejected("limit_exceeded");
+                            exceeded += 1;
+                            continue;
+                        }
+
+                        if let Err(e) = write_observed(&state, &alerts, record.user_id, log).await {
+                            return Ok(format.error(warp::http::StatusCode::SERVICE_UNAVAILABLE, format!("wal error: {}", e)));
+                        }
+                    }
+                    if exceeded > 0 {
+                        errors.push(format!("{} records over the user limits", exceeded));
+                    }
+
+                    Ok(format.accepted(unassigned + exceeded, errors.join(", ")))
+                }
+            }
+        });
+
     let get_logs = warp::path!("log" / i64)
         .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(warp::query::<FieldFilter>())
+        .and(export::format())
         .and(with_db(arc.clone()))
         .and_then(
-            move |user_id, state: Arc<LoggerMemory<ZephyrLog>>| async move {
-                let logs = state.read_log(user_id).await;
+            move |user_id, filter: FieldFilter, format: Format, state: Arc<LoggerMemory<ZephyrLog>>| async move {
+                let logs = state.stream_log_where(user_id, filter).await;
+
+                Ok::<Response, Rejection>(export::reply(format, logs))
+            },
+        );
+
+    let get_trace = warp::path!("trace" / String)
+        .and(warp::get())
+        .and(auth::admin(auth.clone()))
+        .and(export::format())
+        .and(with_db(arc.clone()))
+        .and_then(
+            move |trace_id: String, format: Format, state: Arc<LoggerMemory<ZephyrLog>>| async move {
+                let logs = state.read_trace(&trace_id).await;
+
+                Ok::<Response, Rejection>(export::reply(format, logs))
+            },
+        );
+
+    let get_user_stats = warp::path!("users" / i64 / "stats")
+        .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(with_db(arc.clone()))
+        .and_then(move |user_id, state: Arc<LoggerMemory<ZephyrLog>>| async move {
+            let reply = match state.user_stats(user_id).await {
+                Some(stats) => warp::reply::with_status(serde_json::to_string(&stats).unwrap(), warp::http::StatusCode::OK),
+                None => warp::reply::with_status("unknown user".to_string(), warp::http::StatusCode::NOT_FOUND),
+            };
+
+            Ok::<WithStatus<String>, Rejection>(reply)
+        });
+
+    let get_histogram = warp::path!("users" / i64 / "histogram")
+        .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(warp::query::<HistogramQuery>())
+        .and(with_db(arc.clone()))
+        .and_then(
+            move |user_id, query: HistogramQuery, state: Arc<LoggerMemory<ZephyrLog>>| async move {
+                if let Err(error) = query.validate() {
+                    return Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
+                        error.to_string(),
+                        warp::http::StatusCode::BAD_REQUEST,
+                    ));
+                }
+
+                let buckets = state.histogram(user_id, &query).await;
 
                 Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
-                    serde_json::to_string(&logs).unwrap(),
+                    serde_json::to_string(&buckets).unwrap(),
                     warp::http::StatusCode::OK,
                 ))
             },
         );
 
+    let get_quota = warp::path!("quota" / i64)
+        .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(with_limiter(limiter.clone()))
+        .and_then(move |user_id, limiter: Arc<Limiter>| async move {
+            let usage = limiter.usage(user_id).await;
+
+            Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
+                serde_json::to_string(&usage).unwrap(),
+                warp::http::StatusCode::OK,
+            ))
+        });
+
     let is_logging = warp::path!("logging" / i64)
         .and(warp::post())
+        .and(auth::admin(auth.clone()))
         .and(with_db(arc.clone()))
         .and_then(
             move |user_id, state: Arc<LoggerMemory<ZephyrLog>>| async move {
-                state.is_logging(user_id).await;
+                if let Err(e) = state.is_logging(user_id).await {
+                    return Ok::<WithStatus<String>, Rejection>(wal_unavailable(e));
+                }
 
                 Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
                     "success".into(),
@@ -130,10 +620,13 @@ async fn main() {
 
     let is_not_logging = warp::path!("not_logging" / i64)
         .and(warp::post())
+        .and(auth::admin(auth.clone()))
         .and(with_db(arc.clone()))
         .and_then(
             move |user_id, state: Arc<LoggerMemory<ZephyrLog>>| async move {
-                state.is_not_logging(user_id).await;
+                if let Err(e) = state.is_not_logging(user_id).await {
+                    return Ok::<WithStatus<String>, Rejection>(wal_unavailable(e));
+                }
 
                 Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
                     "success".into(),
@@ -142,11 +635,64 @@ async fn main() {
             },
         );
 
+    let issue_token = warp::path!("token" / i64)
+        .and(warp::post())
+        .and(auth::admin(auth.clone()))
+        .map({
+            let auth = auth.clone();
+            move |user_id| {
+                warp::reply::with_status(
+                    serde_json::to_string(&auth.issue(Scope::User(user_id))).unwrap(),
+                    warp::http::StatusCode::CREATED,
+                )
+            }
+        });
+
+    let get_metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(auth::admin(auth.clone()))
+        .and(with_db(arc.clone()))
+        .and(with_metrics(metrics.clone()))
+        .and_then(
+            move |state: Arc<LoggerMemory<ZephyrLog>>, metrics: Arc<Metrics>| async move {
+                let rendered = metrics.render(&state.stats().await);
+
+                Ok::<_, Rejection>(warp::reply::with_header(
+                    rendered,
+                    "content-type",
+                    metrics::CONTENT_TYPE,
+                ))
+            },
+        );
+
+    let save_snapshot = warp::path!("snapshot")
+        .and(warp::post())
+        .and(auth::admin(auth.clone()))
+        .and(with_db(arc.clone()))
+        .and_then({
+            let path = config.snapshot.as_ref().map(|snapshot| snapshot.path.clone());
+            move |state: Arc<LoggerMemory<ZephyrLog>>| {
+                let path = path.clone();
+                async move {
+                    let (message, status) = match path {
+                        Some(path) => match state.save_snapshot(&path).await {
+                            Ok(()) => ("success".to_string(), warp::http::StatusCode::OK),
+                            Err(e) => (e.to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR),
+                        },
+                        None => ("no snapshot path configured".to_string(), warp::http::StatusCode::NOT_FOUND),
+                    };
+
+                    Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(message, status))
+                }
+            }
+        });
+
     let get_users = warp::path!("users")
         .and(warp::get())
+        .and(auth::admin(auth.clone()))
         .and(with_db(arc.clone()))
         .and_then(move |state: Arc<LoggerMemory<ZephyrLog>>| async move {
-            let users = state.read_users().await;
+            let users = state.users_stats().await;
 
             Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
                 serde_json::to_string(&users).unwrap(),
@@ -154,16 +700,127 @@ async fn main() {
             ))
         });
 
-    let routes = warp::post()
-        .and(add_log)
-        .or(get_debug)
+    let ingest = warp::post().and(add_log.or(otlp_logs).unify());
+
+    let read = get_debug
         .or(get_warning)
         .or(get_errors)
         .or(get_logs)
-        .or(is_logging)
-        .or(is_not_logging)
-        .or(get_users);
-    warp::serve(routes).run(([0, 0, 0, 0], 8082)).await;
+        .or(get_trace)
+        .or(get_quota)
+        .or(get_user_stats)
+        .or(get_histogram);
+
+    let admin = is_logging.or(is_not_logging).or(get_users).or(save_snapshot).or(get_metrics);
+
+    let admin = if config.features.token_endpoint {
+        server::routes(admin.or(issue_token))
+    } else {
+        server::routes(admin)
+    };
+    let admin = server::routes(
+        admin
+            .or(alerts::routes(alerts.clone(), auth.clone()))
+            .unify()
+            .or(subscribers::routes(subscribers, auth.clone()))
+            .unify(),
+    );
+
+    let probes = health::routes({
+        let arc = arc.clone();
+        move || {
+            let arc = arc.clone();
+            async move { arc.readiness().await }
+        }
+    });
+
+    let tcp_ingest = match config.tcp_ingest {
+        Some(addr) => match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                eprintln!("cannot listen for tcp ingestion on {}: {}", addr, e);
+                std::process::exit(1)
+            }
+        },
+        None => None,
+    };
+
+    let ingest_log: tcp::Ingest = {
+        let (state, limiter, metrics, alerts) = (arc.clone(), limiter.clone(), metrics.clone(), alerts.clone());
+        Arc::new(move |user_id, serialized| {
+            let (state, limiter, metrics, alerts) = (state.clone(), limiter.clone(), metrics.clone(), alerts.clone());
+            async move {
+                let Ok(log) = ZephyrLog::decode(&serialized) else {
+                    metrics.log_rejected("invalid_payload");
+                    return Err("invalid_payload");
+                };
+
+                if limiter.check(user_id, &log).await.is_err() {
+                    metrics.log_rejected("limit_exceeded");
+                    return Err("limit_exceeded");
+                }
+
+                write_observed(&state, &alerts, user_id, log).await.map_err(|_| "wal_error")
+            }
+            .boxed()
+        })
+    };
+
+    // Both servers drain on the same signal.
+    let shutdown = server::shutdown_signal().boxed().shared();
+
+    let served = config.listeners.serve(
+        server::routes(ingest),
+        server::routes(read),
+        admin,
+        probes,
+        metrics,
+        shutdown.clone(),
+    );
+
+    tokio::join!(
+        async {
+            if let Err(e) = served.await {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        },
+        async {
+            if let Some(listener) = tcp_ingest {
+                tcp::serve(listener, auth, ingest_log, shutdown).await
+            }
+        },
+    );
+
+    if let Err(e) = arc.sync_wal() {
+        eprintln!("wal error: {}", e);
+    }
+
+    if let Some(snapshot) = &config.snapshot {
+        match arc.save_snapshot(&snapshot.path).await {
+            Ok(()) => eprintln!("saved snapshot to {}", snapshot.path.display()),
+            Err(e) => eprintln!("snapshot error: {}", e),
+        }
+    }
+
+    if config.shutdown.flush_db {
+        let storage = match LoggerStorage::new(config.db.as_ref().unwrap()).await {
+            Ok(storage) => storage,
+            Err(e) => {
+                eprintln!("cannot connect to Postgres: {}", e);
+                std::process::exit(1)
+            }
+        };
+        if let Err(e) = storage.db_setup_project(false).await {
+            eprintln!("cannot set up the logs table: {}", e);
+            std::process::exit(1)
+        }
+
+        match arc.flush_to_storage(&storage).await {
+            Ok(flushed) => eprintln!("flushed {} logs to Postgres", flushed),
+            Err(e) => eprintln!("flush error: {}", e),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -171,35 +828,50 @@ mod test_utils {
     use crate::ZephyrLog;
 
     #[test]
-    fn sample_error_log_serialize() {
-        let log = ZephyrLog {
-            level: crate::LogLevel::Error,
-            message: "test".into(),
-            data: None,
-        };
-
-        println!("{:?}", bincode::serialize(&log).unwrap())
-    }
-
-    #[test]
-    fn sample_debug_log_serialize() {
-        let log = ZephyrLog {
-            level: crate::LogLevel::Debug,
-            message: "test".into(),
-            data: None,
-        };
-
-        println!("{:?}", bincode::serialize(&log).unwrap())
+    fn logs_round_trip() {
+        for level in [crate::LogLevel::Error, crate::LogLevel::Warning, crate::LogLevel::Debug] {
+            let log = ZephyrLog {
+                level,
+                message: "test".into(),
+                data: Some(vec![1, 2]),
+                fields: [("ledger".to_string(), multiuser_logging_service::FieldValue::Integer(10))].into(),
+                content_type: None,
+                source: None,
+                trace: Some(crate::TraceContext {
+                    trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".into()),
+                    ..Default::default()
+                }),
+            };
+            let json = serde_json::to_value(&log).unwrap();
+
+            let decoded = ZephyrLog::decode(&bincode::serialize(&log).unwrap()).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+
+            let decoded: ZephyrLog = serde_json::from_value(json.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+        }
     }
 
     #[test]
-    fn sample_warning_log_serialize() {
-        let log = ZephyrLog {
-            level: crate::LogLevel::Warning,
-            message: "test".into(),
-            data: None,
+    fn decodes_legacy_payloads() {
+        // `{ level: Error, message: "test", data: Some([1, 2]) }` encoded by the first clients.
+        let legacy = [
+            2, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, b't', b'e', b's', b't', 1, 2, 0, 0, 0, 0, 0, 0, 0, 1, 2,
+        ];
+
+        let log = ZephyrLog::decode(&legacy).unwrap();
+        assert_eq!(log.level, crate::LogLevel::Error);
+        assert_eq!((log.message.as_str(), log.data.as_deref()), ("test", Some(&[1, 2][..])));
+        assert!(log.fields.is_empty() && log.source.is_none() && log.trace.is_none());
+
+        let current = ZephyrLog {
+            fields: [("ledger".to_string(), multiuser_logging_service::FieldValue::Integer(10))].into(),
+            ..log
         };
+        let decoded = ZephyrLog::decode(&bincode::serialize(&current).unwrap()).unwrap();
+        assert_eq!(decoded.fields, current.fields);
 
-        println!("{:?}", bincode::serialize(&log).unwrap())
+        // Trailing bytes after a legacy layout are not silently dropped.
+        assert!(ZephyrLog::decode(&[&legacy[..], &[0]].concat()).is_err());
     }
 }
@@ -1,54 +1,364 @@
-use multiuser_logging_service::{LoggerStorage, MercuryLog};
-use serde::{Deserialize, Serialize};
-use warp::{reject::Rejection, reply::WithStatus, Filter};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
+use multiuser_logging_service::{
+    alerts::{self, Alerts},
+    auth::{self, Scope},
+    config::{Config, Service},
+    encoding,
+    export::{self, Format},
+    health,
+    limits::Limiter,
+    metrics::{self, Metrics},
+    server,
+    FieldFilter, HistogramQuery, LoggerStorage, MercuryLog, ServiceLog, TracedLog,
+};
+use futures_util::TryStreamExt;
+use warp::{
+    reject::Rejection,
+    reply::{Response, WithStatus},
+    Filter, Reply,
+};
+
+fn with_db(
+    db: Arc<LoggerStorage>,
+) -> impl Filter<Extract = (Arc<LoggerStorage>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || db.clone())
+}
+
+fn with_metrics(
+    metrics: Arc<Metrics>,
+) -> impl Filter<Extract = (Arc<Metrics>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || metrics.clone())
+}
+
+fn with_alerts(
+    alerts: Arc<Alerts>,
+) -> impl Filter<Extract = (Arc<Alerts>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || alerts.clone())
+}
+
+fn with_limiter(
+    limiter: Arc<Limiter>,
+) -> impl Filter<Extract = (Arc<Limiter>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+/// Reply to a request Postgres failed, 503 while the connection is down.
+fn db_error(error: tokio_postgres::Error) -> WithStatus<String> {
+    eprintln!("database error: {}", error);
+
+    let status = match error.is_closed() {
+        true => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        false => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warp::reply::with_status("database error".to_string(), status)
+}
 
 #[tokio::main]
 async fn main() {
-    let logs = LoggerStorage::new(std::env::var("DB").unwrap()).await;
-    logs.db_setup_project().await;
+    let config = Config::load(SocketAddr::from(([0, 0, 0, 0], 8088)), Service::Storage);
+
+    let metrics = Arc::new(Metrics::new());
+    let logs = match LoggerStorage::new(config.db.as_ref().unwrap()).await {
+        Ok(logs) => logs.with_metrics(metrics.clone()),
+        Err(e) => {
+            eprintln!("cannot connect to Postgres: {}", e);
+            std::process::exit(1)
+        }
+    };
+    if let Err(e) = logs.db_setup_project(config.features.clear_on_startup).await {
+        eprintln!("cannot set up the logs table: {}", e);
+        std::process::exit(1)
+    }
+    let logs = Arc::new(logs);
+
+    let limiter = Arc::new(Limiter::new(config.limits.clone()));
+    let auth = Arc::new(config.auth());
+
+    let alerts = match Alerts::open(config.alert_rules.clone()) {
+        Ok(alerts) => Arc::new(alerts),
+        Err(e) => {
+            eprintln!("cannot load alert rules: {}", e);
+            std::process::exit(1)
+        }
+    };
+
+    tokio::spawn({
+        let alerts = alerts.clone();
+        async move { alerts.run_checks().await }
+    });
+
+    if let Some(retention) = config.retention {
+        let logs = logs.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+            loop {
+                interval.tick().await;
+
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                match logs.delete_older_than(now.saturating_sub(retention).as_secs() as i64).await {
+                    // Retried on the next tick once the connection is back.
+                    Err(e) if e.is_closed() => eprintln!("retention skipped, database unavailable: {}", e),
+                    Err(e) => eprintln!("retention error: {}", e),
+                    Ok(_) => {}
+                }
+            }
+        });
+    }
 
     let add_log = warp::path!("logs" / i64)
         .and(warp::post())
-        .and(warp::body::json())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(encoding::json())
+        .and(with_db(logs.clone()))
+        .and(with_limiter(limiter.clone()))
+        .and(with_metrics(metrics.clone()))
+        .and(with_alerts(alerts.clone()))
         .and_then(
-            move |user_id, log: MercuryLog| async move {
-                let logs = LoggerStorage::new(std::env::var("DB").unwrap()).await;
-                logs.write_log(user_id, log).await.unwrap();
+            move |user_id, log: MercuryLog, logs: Arc<LoggerStorage>, limiter: Arc<Limiter>, metrics: Arc<Metrics>, alerts: Arc<Alerts>| async move {
+                if let Err(exceeded) = limiter.check(user_id, &log).await {
+                    metrics.log_rejected("limit_exceeded");
+                    return Ok::<Response, Rejection>(exceeded.into_response());
+                }
 
-                Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
-                    "success".into(),
+                let watched = alerts.watches(user_id).then(|| log.clone());
+                if let Err(e) = logs.write_log(user_id, log).await {
+                    return Ok::<Response, Rejection>(db_error(e).into_response());
+                }
+                if let Some(log) = watched {
+                    alerts.observe(user_id, &log)
+                }
+
+                Ok::<Response, Rejection>(warp::reply::with_status(
+                    "success",
                     warp::http::StatusCode::CREATED,
-                ))
+                ).into_response())
             },
         );
 
     let get_logs = warp::path!("logs" / i64)
         .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(warp::query::<FieldFilter>())
+        .and(export::format())
+        .and(with_db(logs.clone()))
+        .and_then(
+            move |user_id, filter: FieldFilter, format: Format, logs: Arc<LoggerStorage>| async move {
+                let codecs = logs.codecs();
+                let logs = match logs.read_user_logs_where(user_id, &filter).await {
+                    Ok(logs) => logs,
+                    Err(e) => return Ok::<Response, Rejection>(db_error(e).into_response()),
+                };
+
+                let logs = logs
+                    .map_ok(move |log| ServiceLog::render(&log, &codecs))
+                    .inspect_err(|e| eprintln!("database error: {}", e));
+                Ok::<Response, Rejection>(export::reply_stream(format, logs))
+            },
+        );
+
+    let get_trace = warp::path!("traces" / String)
+        .and(warp::get())
+        .and(auth::admin(auth.clone()))
+        .and(export::format())
+        .and(with_db(logs.clone()))
+        .and_then(
+            move |trace_id: String, format: Format, logs: Arc<LoggerStorage>| async move {
+                let codecs = logs.codecs();
+                let logs = match logs.read_trace_logs(&trace_id).await {
+                    Ok(logs) => logs,
+                    Err(e) => return Ok::<Response, Rejection>(db_error(e).into_response()),
+                };
+
+                let logs = logs
+                    .map_ok(move |log| TracedLog {
+                        user_id: log.user_id,
+                        log: ServiceLog::render(&log.log, &codecs),
+                    })
+                    .inspect_err(|e| eprintln!("database error: {}", e));
+                Ok::<Response, Rejection>(export::reply_stream(format, logs))
+            },
+        );
+
+    let get_user_stats = warp::path!("users" / i64 / "stats")
+        .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(with_db(logs.clone()))
+        .and_then(
+            move |user_id, logs: Arc<LoggerStorage>| async move {
+                let reply = match logs.user_stats(user_id).await {
+                    Ok(Some(stats)) => warp::reply::with_status(serde_json::to_string(&stats).unwrap(), warp::http::StatusCode::OK),
+                    Ok(None) => warp::reply::with_status("unknown user".to_string(), warp::http::StatusCode::NOT_FOUND),
+                    Err(e) => db_error(e),
+                };
+
+                Ok::<WithStatus<String>, Rejection>(reply)
+            },
+        );
+
+    let get_users = warp::path!("users")
+        .and(warp::get())
+        .and(auth::admin(auth.clone()))
+        .and(with_db(logs.clone()))
+        .and_then(
+            move |logs: Arc<LoggerStorage>| async move {
+                let users = match logs.users_stats().await {
+                    Ok(users) => users,
+                    Err(e) => return Ok::<WithStatus<String>, Rejection>(db_error(e)),
+                };
+
+                Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
+                    serde_json::to_string(&users).unwrap(),
+                    warp::http::StatusCode::OK,
+                ))
+            },
+        );
+
+    let get_histogram = warp::path!("users" / i64 / "histogram")
+        .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(warp::query::<HistogramQuery>())
+        .and(with_db(logs.clone()))
         .and_then(
-            move |user_id| async move {
-                let logs = LoggerStorage::new(std::env::var("DB").unwrap()).await;
-                let logs = logs.read_user_logs(user_id).await.unwrap();
-                
+            move |user_id, query: HistogramQuery, logs: Arc<LoggerStorage>| async move {
+                if let Err(error) = query.validate() {
+                    return Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
+                        error.to_string(),
+                        warp::http::StatusCode::BAD_REQUEST,
+                    ));
+                }
+
+                let buckets = match logs.histogram(user_id, &query).await {
+                    Ok(buckets) => buckets,
+                    Err(e) => return Ok::<WithStatus<String>, Rejection>(db_error(e)),
+                };
+
                 Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
-                    serde_json::to_string(&logs).unwrap(),
+                    serde_json::to_string(&buckets).unwrap(),
                     warp::http::StatusCode::OK,
                 ))
             },
         );
 
-    let routes = warp::post()
-        .and(add_log)
-        .or(get_logs);
-    warp::serve(routes).run(([0, 0, 0, 0], 8088)).await;
+    let get_quota = warp::path!("quota" / i64)
+        .and(warp::get())
+        .and(auth::scope(auth.clone()))
+        .and_then(auth::user)
+        .and(with_limiter(limiter.clone()))
+        .and_then(
+            move |user_id, limiter: Arc<Limiter>| async move {
+                let usage = limiter.usage(user_id).await;
+
+                Ok::<WithStatus<String>, Rejection>(warp::reply::with_status(
+                    serde_json::to_string(&usage).unwrap(),
+                    warp::http::StatusCode::OK,
+                ))
+            },
+        );
+
+    let get_metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(auth::admin(auth.clone()))
+        .and(with_db(logs.clone()))
+        .and(with_metrics(metrics.clone()))
+        .and_then(
+            move |logs: Arc<LoggerStorage>, metrics: Arc<Metrics>| async move {
+                let stored = logs.stats().await.unwrap_or_else(|e| {
+                    eprintln!("metrics error: {}", e);
+                    Default::default()
+                });
+
+                Ok::<_, Rejection>(warp::reply::with_header(
+                    metrics.render(&stored),
+                    "content-type",
+                    metrics::CONTENT_TYPE,
+                ))
+            },
+        );
+
+    let issue_token = warp::path!("token" / i64)
+        .and(warp::post())
+        .and(auth::admin(auth.clone()))
+        .map({
+            let auth = auth.clone();
+            move |user_id| {
+                warp::reply::with_status(
+                    serde_json::to_string(&auth.issue(Scope::User(user_id))).unwrap(),
+                    warp::http::StatusCode::CREATED,
+                )
+            }
+        });
+
+    let ingest = warp::post().and(add_log);
+
+    let read = get_logs
+        .or(get_trace)
+        .or(get_quota)
+        .or(get_user_stats)
+        .or(get_histogram);
+
+    let admin = if config.features.token_endpoint {
+        server::routes(get_metrics.or(get_users).or(issue_token))
+    } else {
+        server::routes(get_metrics.or(get_users))
+    };
+    let admin = server::routes(admin.or(alerts::routes(alerts, auth.clone())).unify());
+
+    let probes = health::routes({
+        let logs = logs.clone();
+        move || {
+            let logs = logs.clone();
+            async move { logs.readiness().await }
+        }
+    });
+
+    let served = config
+        .listeners
+        .serve(
+            server::routes(ingest),
+            server::routes(read),
+            admin,
+            probes,
+            metrics,
+            server::shutdown_signal(),
+        )
+        .await;
+
+    if let Err(e) = served {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    }
 }
 
 
-#[test]
-fn t() {
-    println!("{}", serde_json::to_string(&MercuryLog {
-        level: multiuser_logging_service::LogLevel::Error,
-        message: "Test log".try_into().unwrap(),
-        data: None
-    }).unwrap())
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use multiuser_logging_service::{LogLevel, MercuryLog};
+
+    #[test]
+    fn logs_round_trip() {
+        let log = MercuryLog {
+            level: LogLevel::Error,
+            message: "Test log".into(),
+            data: Some(vec![1, 2]),
+            fields: Default::default(),
+            content_type: None,
+            source: None,
+            trace: None,
+        };
+        let json = serde_json::to_value(&log).unwrap();
+
+        let decoded: MercuryLog = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+    }
+}
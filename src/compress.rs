@@ -0,0 +1,362 @@
+//! Compressed in-memory logs.
+//!
+//! With compression on, every chunk of a [`LogChunks`](crate::LogChunks) that fills up is sealed:
+//! its logs are bincode encoded and compressed with zstd, then decoded again whenever they are
+//! read. Each level of a user has a dictionary of the strings interned while sealing its first
+//! chunk (messages, field names and text values, most frequent last), which every chunk of the
+//! level is compressed against so that repeated strings shrink to references into it.
+
+use std::{collections::HashMap, fmt, io, sync::Arc};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    limits::log_bytes,
+    logs::{Filled, LogWrapper},
+    FieldValue, IsLog, LoggerMemory,
+};
+
+/// How sealed chunks are compressed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compression {
+    /// zstd level, from 1 (fastest) to 22 (smallest).
+    pub level: i32,
+    /// Upper bound of the dictionary of each level.
+    pub dictionary_bytes: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            level: 3,
+            dictionary_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// Estimated memory taken by stored logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct MemoryUsage {
+    pub logs: u64,
+    pub sealed_logs: u64,
+    /// Bytes the logs would take uncompressed: the log structs with their messages and payloads.
+    pub raw_bytes: u64,
+    /// Bytes they take: the open logs, the sealed chunks and the dictionaries.
+    pub stored_bytes: u64,
+}
+
+impl MemoryUsage {
+    pub(crate) fn add(&mut self, other: MemoryUsage) {
+        self.logs += other.logs;
+        self.sealed_logs += other.sealed_logs;
+        self.raw_bytes += other.raw_bytes;
+        self.stored_bytes += other.stored_bytes;
+    }
+
+    /// Estimated bytes of an uncompressed log.
+    pub(crate) fn open_log<L: IsLog>(log: &LogWrapper<L>) -> u64 {
+        std::mem::size_of::<LogWrapper<L>>() as u64 + log_bytes(&log.inner)
+    }
+}
+
+/// Interned strings of a level, used as a zstd raw content dictionary.
+pub(crate) struct Dictionary {
+    raw: Vec<u8>,
+}
+
+impl Dictionary {
+    /// Dictionary of the strings repeated in `logs`, keeping the most frequent ones that fit in
+    /// `max_bytes`.
+    pub(crate) fn intern<L: IsLog>(logs: &[LogWrapper<L>], max_bytes: usize) -> Self {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+
+        for log in logs {
+            let fields = log.inner.fields();
+            let values = fields.into_iter().flat_map(|(key, value)| match value {
+                FieldValue::String(value) => vec![key, value],
+                _ => vec![key],
+            });
+
+            for string in std::iter::once(log.inner.message()).chain(values) {
+                *counts.entry(string).or_default() += 1
+            }
+        }
+
+        let mut strings = counts
+            .into_iter()
+            .filter(|(string, count)| *count > 1 && !string.is_empty())
+            .collect::<Vec<_>>();
+        strings.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+
+        let mut size = 0;
+        let kept = strings
+            .iter()
+            .take_while(|(string, _)| {
+                size += string.len();
+                size <= max_bytes
+            })
+            .count();
+
+        // zstd references the end of the dictionary most cheaply.
+        let raw = strings[..kept]
+            .iter()
+            .rev()
+            .flat_map(|(string, _)| string.as_bytes())
+            .copied()
+            .collect();
+
+        Self { raw }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.raw.len()
+    }
+}
+
+type Decode<L> = fn(&[u8]) -> bincode::Result<Vec<LogWrapper<L>>>;
+
+/// Compression settings with the encoder and decoder of the logger's log type, so that sealing
+/// and reading chunks don't require `L: Serialize` on every [`LoggerMemory`] method.
+pub(crate) struct Codec<L> {
+    pub(crate) compression: Compression,
+    encode: fn(&[LogWrapper<L>]) -> bincode::Result<Vec<u8>>,
+    decode: Decode<L>,
+}
+
+impl<L: IsLog> Codec<L> {
+    pub(crate) fn seal(&self, logs: &[LogWrapper<L>], dictionary: &Arc<Dictionary>) -> io::Result<Sealed<L>> {
+        let encoded = (self.encode)(logs).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let bytes = zstd::bulk::Compressor::with_dictionary(self.compression.level, &dictionary.raw)?
+            .compress(&encoded)?;
+
+        Ok(Sealed {
+            len: logs.len(),
+            encoded_len: encoded.len(),
+            raw_bytes: logs.iter().map(MemoryUsage::open_log).sum(),
+            log_bytes: logs.iter().map(|log| log_bytes(&log.inner)).sum(),
+            first: logs.iter().map(|log| log.time).min().unwrap_or(i64::MAX),
+            last: logs.iter().map(|log| log.time).max().unwrap_or(i64::MIN),
+            bytes: bytes.into_boxed_slice(),
+            dictionary: dictionary.clone(),
+            decode: self.decode,
+        })
+    }
+}
+
+/// Compressed logs of a full chunk.
+pub(crate) struct Sealed<L> {
+    len: usize,
+    encoded_len: usize,
+    raw_bytes: u64,
+    /// Bytes of the logs as counted in the stats, and their time bounds, so that retention can
+    /// drop or keep the chunk without decoding it.
+    log_bytes: u64,
+    first: i64,
+    last: i64,
+    bytes: Box<[u8]>,
+    dictionary: Arc<Dictionary>,
+    decode: Decode<L>,
+}
+
+impl<L> Sealed<L> {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn log_bytes(&self) -> u64 {
+        self.log_bytes
+    }
+
+    /// Times of the oldest and newest logs.
+    pub(crate) fn bounds(&self) -> (i64, i64) {
+        (self.first, self.last)
+    }
+
+    pub(crate) fn dictionary(&self) -> &Arc<Dictionary> {
+        &self.dictionary
+    }
+
+    /// Decompressed logs.
+    pub(crate) fn logs(&self) -> Vec<LogWrapper<L>> {
+        let encoded = zstd::bulk::Decompressor::with_dictionary(&self.dictionary.raw)
+            .and_then(|mut decompressor| decompressor.decompress(&self.bytes, self.encoded_len))
+            .expect("sealed chunks decompress");
+
+        (self.decode)(&encoded).expect("sealed chunks decode")
+    }
+
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            logs: self.len as u64,
+            sealed_logs: self.len as u64,
+            raw_bytes: self.raw_bytes,
+            stored_bytes: (std::mem::size_of::<Self>() + self.bytes.len()) as u64,
+        }
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary").field("bytes", &self.raw.len()).finish()
+    }
+}
+
+impl<L> fmt::Debug for Sealed<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sealed")
+            .field("len", &self.len)
+            .field("bytes", &self.bytes.len())
+            .finish()
+    }
+}
+
+impl<L: IsLog + Serialize + DeserializeOwned> LoggerMemory<L> {
+    /// Seals and compresses every chunk of logs that fills up, including the restored ones.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(Arc::new(Codec {
+            compression,
+            encode: |logs| bincode::serialize(logs),
+            decode: |bytes| bincode::deserialize(bytes),
+        }));
+        self
+    }
+}
+
+impl<L: IsLog + Send + Sync + 'static> LoggerMemory<L> {
+    /// Compresses a chunk filled up by a write on the blocking pool, then swaps it in. The state
+    /// is only locked for the swap, writes keep going to the next chunk in the meantime.
+    pub(crate) async fn seal_filled(&self, user_id: i64, filled: Filled<L>) {
+        let Some(codec) = self.compression.clone() else {
+            return;
+        };
+
+        let (logs, dictionary) = (filled.logs.clone(), filled.dictionary.clone());
+        let sealed = tokio::task::spawn_blocking(move || {
+            let dictionary = dictionary
+                .unwrap_or_else(|| Arc::new(Dictionary::intern(&logs, codec.compression.dictionary_bytes)));
+            codec.seal(&logs, &dictionary)
+        })
+        .await
+        .unwrap_or_else(|error| Err(io::Error::other(error)));
+
+        match sealed {
+            Ok(sealed) => {
+                if let Some(user_logs) = self.state.lock().await.get_mut(&user_id) {
+                    user_logs.replace_sealed(&filled, sealed)
+                }
+            }
+            Err(e) => eprintln!("cannot compress logs: {}", e),
+        }
+    }
+}
+
+impl<L: IsLog> LoggerMemory<L> {
+    /// Estimated memory taken by the logs of every user.
+    pub async fn memory_usage(&self) -> MemoryUsage {
+        let state = self.state.lock().await;
+        let mut usage = MemoryUsage::default();
+
+        for user_logs in state.values() {
+            usage.add(user_logs.memory_usage())
+        }
+
+        usage
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, Dictionary};
+    use crate::{
+        logs::LogWrapper,
+        test::{log, logging_users, TestLog},
+        LogLevel, LoggerMemory, CHUNK_SIZE,
+    };
+
+    /// Log `index` of a service repeating a few messages, with an occasional unique one.
+    fn repetitive(index: usize) -> TestLog {
+        let messages = [
+            "payment declined: insufficient funds",
+            "connection to billing-db reset by peer, retrying",
+            "cache miss for customer profile",
+            "request handled in under 100ms",
+        ];
+        let message = match index % 8 {
+            7 => format!("unexpected status 503 from upstream {}", index),
+            _ => messages[index % messages.len()].to_string(),
+        };
+
+        log([LogLevel::Error, LogLevel::Warning, LogLevel::Debug][index % 3].clone(), &message)
+    }
+
+    #[test]
+    fn interns_repeated_strings() {
+        let logs = ["retrying", "connected", "retrying", "", "", "retrying", "connected", "once"]
+            .map(|message| LogWrapper {
+                time: 0,
+                inner: log(LogLevel::Debug, message),
+            });
+
+        assert_eq!(Dictionary::intern(&logs, 1024).raw, b"connectedretrying");
+        assert_eq!(Dictionary::intern(&logs, 8).raw, b"retrying");
+    }
+
+    #[tokio::test]
+    async fn compresses_full_chunks() {
+        let logger = logging_users(&[1]).await.with_compression(Compression::default());
+        let uncompressed = logging_users(&[1]).await;
+
+        for index in 0..3 * CHUNK_SIZE + 10 {
+            logger.write_log(1, repetitive(index)).await.unwrap();
+            uncompressed.write_log(1, repetitive(index)).await.unwrap();
+        }
+
+        let usage = logger.memory_usage().await;
+        let raw = uncompressed.memory_usage().await;
+
+        assert_eq!(usage.logs, raw.logs);
+        assert_eq!(usage.sealed_logs, 3 * CHUNK_SIZE as u64);
+        assert_eq!(usage.raw_bytes, raw.stored_bytes);
+        assert_eq!(raw.sealed_logs, 0);
+        assert!(
+            usage.stored_bytes * 4 < raw.stored_bytes,
+            "{} bytes compressed to {}",
+            raw.stored_bytes,
+            usage.stored_bytes
+        );
+
+        // Reads decode the sealed chunks transparently.
+        let messages = |logs: Vec<LogWrapper<TestLog>>| logs.into_iter().map(|log| log.inner.message).collect::<Vec<_>>();
+        assert_eq!(messages(logger.read_errros(1).await), messages(uncompressed.read_errros(1).await));
+        assert_eq!(
+            logger.stream_log_where(1, Default::default()).await.count(),
+            3 * CHUNK_SIZE + 10
+        );
+
+        // Dictionaries outlive pruning, for the next chunks.
+        logger.prune(i64::MAX).await;
+        let pruned = logger.memory_usage().await;
+        assert_eq!((pruned.logs, pruned.raw_bytes), (0, 0));
+        let stats = logger.user_stats(1).await.unwrap();
+        assert_eq!((stats.error + stats.warning + stats.debug, stats.bytes), (0, 0));
+        assert!(pruned.stored_bytes <= 3 * Compression::default().dictionary_bytes as u64);
+    }
+
+    #[tokio::test]
+    async fn seals_restored_chunks() {
+        let path = std::env::temp_dir().join(format!("zephyr-compress-{}", std::process::id()));
+
+        let logger = logging_users(&[1]).await.with_compression(Compression::default());
+        for index in 0..CHUNK_SIZE * 3 {
+            logger.write_log(1, repetitive(3 * index)).await.unwrap();
+        }
+        logger.save_snapshot(&path).await.unwrap();
+
+        let restored = LoggerMemory::<TestLog>::new().with_compression(Compression::default());
+        restored.restore_snapshot(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.memory_usage().await, logger.memory_usage().await);
+        assert_eq!(restored.read_errros(1).await.len(), CHUNK_SIZE * 3);
+    }
+}
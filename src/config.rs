@@ -0,0 +1,759 @@
+//! Configuration of the service binaries.
+//!
+//! Settings are resolved from the built-in defaults, then the TOML file given with `--config`
+//! (or `ZEPHYR_CONFIG`), then environment variables, then command line flags. Every invalid
+//! setting is reported at once by [`Config::load`] before exiting.
+//!
+//! ```toml
+//! db = "postgres://localhost/logs"
+//! auth_secret = "..."
+//! token_ttl_secs = 2592000
+//!
+//! [listen]
+//! addr = "0.0.0.0:8082"
+//! admin = "unix:/run/zephyr/admin.sock"
+//! unix_socket_mode = "600"
+//! tcp_ingest = "0.0.0.0:8089"
+//!
+//! [retention]
+//! max_age_secs = 604800
+//!
+//! [limits.defaults.rate]
+//! burst = 100
+//! per_second = 10
+//!
+//! [features]
+//! clear_on_startup = false
+//!
+//! [snapshot]
+//! path = "/var/lib/zephyr/logs.snapshot"
+//! interval_secs = 300
+//!
+//! [wal]
+//! dir = "/var/lib/zephyr/wal"
+//! fsync = "100ms"
+//!
+//! [alerts]
+//! rules_file = "/var/lib/zephyr/alerts.json"
+//!
+//! [compression]
+//! level = 3
+//!
+//! [syslog]
+//! udp = "0.0.0.0:514"
+//! tcp = "0.0.0.0:601"
+//! user_id_param = "user_id"
+//! allowed_sources = ["10.0.0.0/8"]
+//!
+//! [[syslog.apps]]
+//! app = "billing-*"
+//! user_id = 7
+//!
+//! [otlp]
+//! user_id_attribute = "zephyr.user_id"
+//! ```
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Parser;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::{
+    auth::{Auth, DEFAULT_TOKEN_TTL},
+    limits::LimitsConfig,
+    server::{BindAddr, Listeners},
+    syslog::{AppRule, SyslogConfig, DEFAULT_USER_ID_PARAM},
+};
+
+#[cfg(feature = "memory")]
+use crate::wal::WalOptions;
+
+/// Longest retention accepted, 100 years.
+pub const MAX_RETENTION_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Command line flags. Each one can also be set through the environment variable in brackets.
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "Multi-user logging service")]
+pub struct Args {
+    /// TOML configuration file.
+    #[arg(long, short, env = "ZEPHYR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address of every route group without a specific one (`host:port` or `unix:{path}`).
+    #[arg(long, env = "LISTEN_ADDR")]
+    pub listen: Option<String>,
+
+    #[arg(long, env = "INGEST_ADDR")]
+    pub ingest_addr: Option<String>,
+
+    #[arg(long, env = "READ_ADDR")]
+    pub read_addr: Option<String>,
+
+    #[arg(long, env = "ADMIN_ADDR")]
+    pub admin_addr: Option<String>,
+
+    /// Address of the raw TCP ingestion protocol, disabled if missing (memory service).
+    #[arg(long, env = "TCP_INGEST_ADDR")]
+    pub tcp_ingest_addr: Option<SocketAddr>,
+
+    /// Octal permissions of the Unix socket files.
+    #[arg(long, env = "UNIX_SOCKET_MODE")]
+    pub unix_socket_mode: Option<String>,
+
+    /// Postgres connection string.
+    #[arg(long, env = "DB", hide_env_values = true)]
+    pub db: Option<String>,
+
+    /// Secret signing the auth tokens, required unless `--insecure-no-auth` is set.
+    #[arg(long, env = "AUTH_SECRET", hide_env_values = true)]
+    pub auth_secret: Option<String>,
+
+    /// Serve every request without authentication when no auth secret is set. Only meant for
+    /// local development.
+    #[arg(long, env = "INSECURE_NO_AUTH")]
+    pub insecure_no_auth: bool,
+
+    /// Lifetime of the issued tokens, 30 days by default.
+    #[arg(long, env = "TOKEN_TTL_SECS")]
+    pub token_ttl_secs: Option<u64>,
+
+    /// JSON limits file, replacing the `[limits]` of the configuration file.
+    #[arg(long, env = "LIMITS")]
+    pub limits: Option<PathBuf>,
+
+    /// Logs older than this many seconds are deleted.
+    #[arg(long, env = "RETENTION_SECS")]
+    pub retention_secs: Option<u64>,
+
+    /// Delete the stored logs at startup.
+    #[arg(long, env = "CLEAR_ON_STARTUP")]
+    pub clear_on_startup: Option<bool>,
+
+    /// Serve `POST /token/{user_id}`.
+    #[arg(long, env = "TOKEN_ENDPOINT")]
+    pub token_endpoint: Option<bool>,
+
+    /// Snapshot of the in-memory logs, restored at startup and saved on shutdown (memory service).
+    #[arg(long, env = "SNAPSHOT_PATH")]
+    pub snapshot: Option<PathBuf>,
+
+    /// Also save the snapshot every this many seconds.
+    #[arg(long, env = "SNAPSHOT_INTERVAL_SECS")]
+    pub snapshot_interval_secs: Option<u64>,
+
+    /// Directory of the write-ahead log of the in-memory logs (memory service).
+    #[cfg(feature = "memory")]
+    #[arg(long, env = "WAL_DIR")]
+    pub wal_dir: Option<PathBuf>,
+
+    /// Size after which a new WAL segment is started.
+    #[cfg(feature = "memory")]
+    #[arg(long, env = "WAL_SEGMENT_BYTES")]
+    pub wal_segment_bytes: Option<u64>,
+
+    /// When WAL records are flushed to disk: `always`, `never` or at most every `{n}ms`.
+    #[cfg(feature = "memory")]
+    #[arg(long, env = "WAL_FSYNC")]
+    pub wal_fsync: Option<String>,
+
+    /// Copy the in-memory logs to Postgres on shutdown (memory service).
+    #[arg(long, env = "SHUTDOWN_FLUSH_DB")]
+    pub shutdown_flush_db: Option<bool>,
+
+    /// JSON file the alerting rules are saved to and loaded from.
+    #[arg(long, env = "ALERT_RULES")]
+    pub alert_rules: Option<PathBuf>,
+
+    /// Compress full chunks of in-memory logs with zstd at this level, 1 to 22 (memory service).
+    #[arg(long, env = "COMPRESSION_LEVEL")]
+    pub compression_level: Option<i32>,
+
+    /// Receive syslog messages on this UDP address (memory service).
+    #[arg(long, env = "SYSLOG_UDP_ADDR")]
+    pub syslog_udp: Option<SocketAddr>,
+
+    /// Receive syslog messages on this TCP address (memory service).
+    #[arg(long, env = "SYSLOG_TCP_ADDR")]
+    pub syslog_tcp: Option<SocketAddr>,
+
+    /// Resource attribute holding the user id of OTLP logs (memory service).
+    #[arg(long, env = "OTLP_USER_ATTRIBUTE")]
+    pub otlp_user_attribute: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenFile {
+    pub addr: Option<String>,
+    pub ingest: Option<String>,
+    pub read: Option<String>,
+    pub admin: Option<String>,
+    pub unix_socket_mode: Option<String>,
+    pub tcp_ingest: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionFile {
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesFile {
+    pub clear_on_startup: Option<bool>,
+    pub token_endpoint: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotFile {
+    pub path: Option<PathBuf>,
+    pub interval_secs: Option<u64>,
+}
+
+#[cfg(feature = "memory")]
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalFile {
+    pub dir: Option<PathBuf>,
+    pub segment_bytes: Option<u64>,
+    pub fsync: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownFile {
+    pub flush_db: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertsFile {
+    pub rules_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionFile {
+    pub level: Option<i32>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogFile {
+    pub udp: Option<SocketAddr>,
+    pub tcp: Option<SocketAddr>,
+    pub user_id_param: Option<String>,
+    pub apps: Vec<AppRule>,
+    pub allow_unauthenticated: Option<bool>,
+    pub allowed_sources: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpFile {
+    pub user_id_attribute: Option<String>,
+}
+
+/// Contents of the TOML configuration file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub listen: ListenFile,
+    pub db: Option<String>,
+    pub auth_secret: Option<String>,
+    pub insecure_no_auth: Option<bool>,
+    pub token_ttl_secs: Option<u64>,
+    pub retention: RetentionFile,
+    pub limits: Option<LimitsConfig>,
+    pub features: FeaturesFile,
+    pub snapshot: SnapshotFile,
+    #[cfg(feature = "memory")]
+    pub wal: WalFile,
+    pub shutdown: ShutdownFile,
+    pub alerts: AlertsFile,
+    pub compression: CompressionFile,
+    pub syslog: SyslogFile,
+    pub otlp: OtlpFile,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Features {
+    /// Delete the stored logs at startup (storage service).
+    pub clear_on_startup: bool,
+    /// Serve `POST /token/{user_id}`.
+    pub token_endpoint: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            clear_on_startup: true,
+            token_endpoint: true,
+        }
+    }
+}
+
+/// Where and how often the memory service snapshots its logs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub interval: Option<Duration>,
+}
+
+/// Write-ahead log of the memory service.
+#[cfg(feature = "memory")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalConfig {
+    pub dir: PathBuf,
+    pub options: WalOptions,
+}
+
+/// What the memory service saves before exiting, besides the snapshot.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Shutdown {
+    /// Copy the logs to the Postgres database in `db`.
+    pub flush_db: bool,
+}
+
+/// Binary a configuration is resolved for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Service {
+    /// Keeps the logs in memory, the only one with the syslog, OTLP and raw TCP ingestion.
+    Memory,
+    /// Keeps the logs in Postgres, the connection string is mandatory.
+    Storage,
+}
+
+/// Resolved and validated configuration.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub listeners: Listeners,
+    /// Address of the raw TCP ingestion protocol of the memory service.
+    pub tcp_ingest: Option<SocketAddr>,
+    pub db: Option<String>,
+    /// Requests are not authenticated if `None`, only allowed with `insecure_no_auth`.
+    pub auth_secret: Option<String>,
+    pub token_ttl: Duration,
+    pub retention: Option<Duration>,
+    pub limits: LimitsConfig,
+    pub features: Features,
+    pub snapshot: Option<Snapshot>,
+    #[cfg(feature = "memory")]
+    pub wal: Option<WalConfig>,
+    pub shutdown: Shutdown,
+    /// Where the alerting rules are kept, in memory only if `None`.
+    pub alert_rules: Option<PathBuf>,
+    /// zstd level of the sealed in-memory chunks, uncompressed if `None`.
+    pub compression_level: Option<i32>,
+    /// Syslog listeners of the memory service, disabled if `None`.
+    pub syslog: Option<SyslogConfig>,
+    /// Resource attribute holding the user id of OTLP logs, `zephyr.user_id` if `None`.
+    pub otlp_user_attribute: Option<String>,
+}
+
+impl Config {
+    /// Resolves the configuration from the process arguments and environment, printing the
+    /// errors and exiting if it's invalid for `service`.
+    pub fn load(default_addr: SocketAddr, service: Service) -> Self {
+        match Self::resolve(Args::parse(), default_addr, service) {
+            Ok(config) => config,
+            Err(errors) => {
+                eprintln!("invalid configuration:");
+                for error in errors {
+                    eprintln!("  - {}", error);
+                }
+                std::process::exit(1)
+            }
+        }
+    }
+
+    pub fn resolve(args: Args, default_addr: SocketAddr, service: Service) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let file = match &args.config {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(contents) => toml::from_str(&contents).unwrap_or_else(|error| {
+                    errors.push(format!("{}: {}", path.display(), error));
+                    ConfigFile::default()
+                }),
+                Err(error) => {
+                    errors.push(format!("cannot read {}: {}", path.display(), error));
+                    ConfigFile::default()
+                }
+            },
+            None => ConfigFile::default(),
+        };
+
+        let mut bind_addr = |name: &str, addr: Option<String>| match addr {
+            Some(addr) => addr.parse::<BindAddr>().unwrap_or_else(|error| {
+                errors.push(format!("{}: {}", name, error));
+                BindAddr::Tcp(default_addr)
+            }),
+            None => BindAddr::Tcp(default_addr),
+        };
+
+        let listen = args.listen.or(file.listen.addr);
+        let ingest = bind_addr("ingest address", args.ingest_addr.or(file.listen.ingest).or(listen.clone()));
+        let read = bind_addr("read address", args.read_addr.or(file.listen.read).or(listen.clone()));
+        let admin = bind_addr("admin address", args.admin_addr.or(file.listen.admin).or(listen));
+
+        let mut listeners = Listeners {
+            ingest,
+            read,
+            admin,
+            ..Listeners::single(default_addr)
+        };
+
+        if let Some(mode) = args.unix_socket_mode.or(file.listen.unix_socket_mode) {
+            match u32::from_str_radix(&mode, 8) {
+                Ok(mode) if mode <= 0o777 => listeners.socket_mode = mode,
+                _ => errors.push(format!("unix socket mode: {} is not an octal mode", mode)),
+            }
+        }
+
+        let tcp_ingest = args.tcp_ingest_addr.or(file.listen.tcp_ingest);
+
+        let db = args.db.or(file.db);
+        if service == Service::Storage && db.is_none() {
+            errors.push("db: a Postgres connection string is required (--db or DB)".to_string());
+        }
+
+        let auth_secret = args.auth_secret.or(file.auth_secret);
+        if auth_secret.as_ref().is_some_and(|secret| secret.is_empty()) {
+            errors.push("auth secret: must not be empty".to_string());
+        }
+        let insecure_no_auth = args.insecure_no_auth || file.insecure_no_auth.unwrap_or_default();
+        if auth_secret.is_none() && !insecure_no_auth {
+            errors.push(
+                "auth secret: required (--auth-secret or AUTH_SECRET), or run without authentication with \
+                 --insecure-no-auth"
+                    .to_string(),
+            );
+        }
+        let token_ttl = match args.token_ttl_secs.or(file.token_ttl_secs) {
+            Some(0) => {
+                errors.push("token ttl: must be positive".to_string());
+                DEFAULT_TOKEN_TTL
+            }
+            ttl => ttl.map_or(DEFAULT_TOKEN_TTL, Duration::from_secs),
+        };
+
+        let retention = match args.retention_secs.or(file.retention.max_age_secs) {
+            Some(0) => {
+                errors.push("retention: max age must be positive".to_string());
+                None
+            }
+            Some(max_age) if max_age > MAX_RETENTION_SECS => {
+                errors.push(format!("retention: max age must be at most {} seconds", MAX_RETENTION_SECS));
+                None
+            }
+            max_age => max_age.map(Duration::from_secs),
+        };
+
+        let limits = match &args.limits {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|error| error.to_string())
+                .and_then(|limits| serde_json::from_str(&limits).map_err(|error| error.to_string()))
+                .unwrap_or_else(|error| {
+                    errors.push(format!("limits file {}: {}", path.display(), error));
+                    LimitsConfig::default()
+                }),
+            None => file.limits.unwrap_or_default(),
+        };
+        errors.extend(limits.validate());
+
+        let defaults = Features::default();
+        let features = Features {
+            clear_on_startup: args
+                .clear_on_startup
+                .or(file.features.clear_on_startup)
+                .unwrap_or(defaults.clear_on_startup),
+            token_endpoint: args
+                .token_endpoint
+                .or(file.features.token_endpoint)
+                .unwrap_or(defaults.token_endpoint),
+        };
+
+        let snapshot_interval = match args.snapshot_interval_secs.or(file.snapshot.interval_secs) {
+            Some(0) => {
+                errors.push("snapshot: interval must be positive".to_string());
+                None
+            }
+            interval => interval.map(Duration::from_secs),
+        };
+        let snapshot = match args.snapshot.or(file.snapshot.path) {
+            Some(path) => Some(Snapshot {
+                path,
+                interval: snapshot_interval,
+            }),
+            None => {
+                if snapshot_interval.is_some() {
+                    errors.push("snapshot: an interval needs a snapshot path".to_string());
+                }
+                None
+            }
+        };
+
+        #[cfg(feature = "memory")]
+        let wal = {
+            let mut wal_options = WalOptions::default();
+            if let Some(segment_bytes) = args.wal_segment_bytes.or(file.wal.segment_bytes) {
+                match segment_bytes {
+                    0 => errors.push("wal: segment size must be positive".to_string()),
+                    segment_bytes => wal_options.segment_bytes = segment_bytes,
+                }
+            }
+            if let Some(fsync) = args.wal_fsync.or(file.wal.fsync) {
+                match fsync.parse() {
+                    Ok(fsync) => wal_options.fsync = fsync,
+                    Err(error) => errors.push(format!("wal: {}", error)),
+                }
+            }
+            args.wal_dir.or(file.wal.dir).map(|dir| WalConfig {
+                dir,
+                options: wal_options,
+            })
+        };
+
+        let shutdown = Shutdown {
+            flush_db: args.shutdown_flush_db.or(file.shutdown.flush_db).unwrap_or_default(),
+        };
+        if shutdown.flush_db && db.is_none() {
+            errors.push("shutdown: flushing to Postgres needs a connection string (--db or DB)".to_string());
+        }
+
+        let alert_rules = args.alert_rules.or(file.alerts.rules_file);
+
+        let compression_level = args.compression_level.or(file.compression.level);
+        if compression_level.is_some_and(|level| !(1..=22).contains(&level)) {
+            errors.push("compression: level must be between 1 and 22".to_string());
+        }
+
+        let syslog = SyslogConfig {
+            udp: args.syslog_udp.or(file.syslog.udp),
+            tcp: args.syslog_tcp.or(file.syslog.tcp),
+            user_id_param: file
+                .syslog
+                .user_id_param
+                .unwrap_or_else(|| DEFAULT_USER_ID_PARAM.to_string()),
+            apps: file.syslog.apps,
+            allow_unauthenticated: file.syslog.allow_unauthenticated.unwrap_or_default(),
+            allowed_sources: file
+                .syslog
+                .allowed_sources
+                .iter()
+                .filter_map(|source| {
+                    // A single address is a network of one.
+                    let net = source.parse().or_else(|_| source.parse::<IpAddr>().map(IpNet::from));
+                    if net.is_err() {
+                        errors.push(format!("syslog: {} is not an address or CIDR network", source));
+                    }
+                    net.ok()
+                })
+                .collect(),
+        };
+        let syslog = match (syslog.udp, syslog.tcp) {
+            (None, None) => {
+                if !syslog.apps.is_empty() || !syslog.allowed_sources.is_empty() {
+                    errors.push("syslog: app rules and allowed sources need a udp or tcp address".to_string());
+                }
+                None
+            }
+            _ => {
+                if auth_secret.is_some() && !syslog.allow_unauthenticated && syslog.allowed_sources.is_empty() {
+                    errors.push(
+                        "syslog: messages are not authenticated, set allowed_sources or allow_unauthenticated \
+                         to listen while authentication is enabled"
+                            .to_string(),
+                    );
+                }
+                Some(syslog)
+            }
+        };
+
+        let otlp_user_attribute = args.otlp_user_attribute.or(file.otlp.user_id_attribute);
+        if otlp_user_attribute.as_ref().is_some_and(|attribute| attribute.is_empty()) {
+            errors.push("otlp: user id attribute must not be empty".to_string());
+        }
+
+        if service == Service::Storage {
+            let memory_only = [
+                ("tcp_ingest", tcp_ingest.is_some()),
+                ("syslog", syslog.is_some()),
+                ("otlp", otlp_user_attribute.is_some()),
+            ];
+            for (setting, _) in memory_only.iter().filter(|(_, set)| *set) {
+                errors.push(format!("{}: only supported by the memory service", setting));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Self {
+            listeners,
+            tcp_ingest,
+            db,
+            auth_secret,
+            token_ttl,
+            retention,
+            limits,
+            features,
+            snapshot,
+            #[cfg(feature = "memory")]
+            wal,
+            shutdown,
+            alert_rules,
+            compression_level,
+            syslog,
+            otlp_user_attribute,
+        })
+    }
+
+    pub fn auth(&self) -> Auth {
+        match &self.auth_secret {
+            Some(secret) => Auth::new(secret).with_token_ttl(self.token_ttl),
+            None => {
+                eprintln!("--insecure-no-auth: authentication is disabled, every request is allowed");
+                Auth::disabled()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Args, Config, Service};
+    use crate::{auth::DEFAULT_TOKEN_TTL, server::BindAddr};
+
+    #[test]
+    fn flags_override_file() {
+        let path = std::env::temp_dir().join(format!("zephyr-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            db = "postgres://file"
+
+            [listen]
+            addr = "127.0.0.1:9000"
+            admin = "unix:/tmp/admin.sock"
+
+            [limits.users.42.quota]
+            daily_count = 10
+
+            [features]
+            clear_on_startup = false
+
+            [syslog]
+            udp = "127.0.0.1:5514"
+
+            [[syslog.apps]]
+            app = "nginx"
+            user_id = 8
+            "#,
+        )
+        .unwrap();
+
+        let args = Args {
+            config: Some(path.clone()),
+            read_addr: Some("127.0.0.1:9001".into()),
+            db: Some("postgres://flag".into()),
+            syslog_tcp: Some("127.0.0.1:5601".parse().unwrap()),
+            insecure_no_auth: true,
+            ..Default::default()
+        };
+
+        let config = Config::resolve(args, ([0, 0, 0, 0], 8088).into(), Service::Memory).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(config.listeners.ingest, "127.0.0.1:9000".parse::<BindAddr>().unwrap());
+        assert_eq!(config.listeners.read, "127.0.0.1:9001".parse::<BindAddr>().unwrap());
+        assert_eq!(config.listeners.admin, BindAddr::Unix("/tmp/admin.sock".into()));
+        assert_eq!(config.db.as_deref(), Some("postgres://flag"));
+        assert_eq!(config.limits.for_user(42).quota.daily_count, Some(10));
+        assert!(!config.features.clear_on_startup);
+        assert!(config.features.token_endpoint);
+        assert_eq!((config.auth_secret, config.token_ttl), (None, DEFAULT_TOKEN_TTL));
+
+        let syslog = config.syslog.unwrap();
+        assert_eq!(syslog.udp, Some("127.0.0.1:5514".parse().unwrap()));
+        assert_eq!(syslog.tcp, Some("127.0.0.1:5601".parse().unwrap()));
+        assert_eq!((syslog.user_id_param.as_str(), syslog.apps[0].user_id), ("user_id", 8));
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let args = Args {
+            ingest_addr: Some("nowhere".into()),
+            unix_socket_mode: Some("999".into()),
+            retention_secs: Some(0),
+            shutdown_flush_db: Some(true),
+            compression_level: Some(0),
+            ..Default::default()
+        };
+
+        let errors = Config::resolve(args, ([0, 0, 0, 0], 8088).into(), Service::Storage).unwrap_err();
+
+        assert_eq!(errors.len(), 7, "{:?}", errors);
+    }
+
+    #[test]
+    fn requires_auth() {
+        let errors = Config::resolve(Args::default(), ([0, 0, 0, 0], 8082).into(), Service::Memory).unwrap_err();
+        assert!(errors[0].contains("--insecure-no-auth"), "{:?}", errors);
+
+        let args = Args {
+            auth_secret: Some("secret".into()),
+            token_ttl_secs: Some(3600),
+            ..Default::default()
+        };
+        let config = Config::resolve(args, ([0, 0, 0, 0], 8082).into(), Service::Memory).unwrap();
+        assert_eq!(config.token_ttl, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn bounds_retention() {
+        let args = Args {
+            retention_secs: Some(u64::MAX),
+            insecure_no_auth: true,
+            ..Default::default()
+        };
+
+        let errors = Config::resolve(args, ([0, 0, 0, 0], 8082).into(), Service::Memory).unwrap_err();
+        assert_eq!(errors, ["retention: max age must be at most 3153600000 seconds"]);
+    }
+
+    #[test]
+    fn guards_unauthenticated_ingestion() {
+        let syslog = Args {
+            auth_secret: Some("secret".into()),
+            syslog_udp: Some("127.0.0.1:5514".parse().unwrap()),
+            ..Default::default()
+        };
+        let errors = Config::resolve(syslog, ([0, 0, 0, 0], 8082).into(), Service::Memory).unwrap_err();
+        assert!(errors[0].contains("allow_unauthenticated"), "{:?}", errors);
+
+        let storage = Args {
+            db: Some("postgres://flag".into()),
+            tcp_ingest_addr: Some("127.0.0.1:8089".parse().unwrap()),
+            syslog_tcp: Some("127.0.0.1:5601".parse().unwrap()),
+            insecure_no_auth: true,
+            ..Default::default()
+        };
+        let errors = Config::resolve(storage, ([0, 0, 0, 0], 8088).into(), Service::Storage).unwrap_err();
+        assert_eq!(
+            errors,
+            ["tcp_ingest: only supported by the memory service", "syslog: only supported by the memory service"]
+        );
+    }
+}
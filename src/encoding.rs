@@ -0,0 +1,391 @@
+//! HTTP content encodings.
+//!
+//! Ingestion bodies may be sent with `Content-Encoding: gzip` or `zstd`, and responses are
+//! compressed with the encoding preferred by the request's `Accept-Encoding` header. Response
+//! bodies are compressed as they are streamed, each body chunk is flushed on its own so that
+//! streamed exports stay streamed. Each codec is built with the feature of the same name, the
+//! encodings left out are refused in requests and never chosen for responses.
+
+use std::io;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use warp::{
+    http::{
+        header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY},
+        StatusCode,
+    },
+    hyper::{body::HttpBody, Body},
+    reject::Reject,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::server::Routes;
+
+/// Bodies smaller than this are sent uncompressed.
+pub const MIN_COMPRESSED_BYTES: usize = 1024;
+
+/// Decoded request bodies larger than this are refused.
+pub const MAX_DECODED_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Identity,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    /// Encoding chosen when any is accepted: gzip, the most widely supported, if built in.
+    #[cfg(feature = "gzip")]
+    pub const ANY: Self = Self::Gzip;
+    #[cfg(all(not(feature = "gzip"), feature = "zstd"))]
+    pub const ANY: Self = Self::Zstd;
+    #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+    pub const ANY: Self = Self::Identity;
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Identity => "identity",
+            #[cfg(feature = "gzip")]
+            Self::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "identity" => Some(Self::Identity),
+            #[cfg(feature = "gzip")]
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Preferred supported encoding of an `Accept-Encoding` header, zstd over gzip on equal
+    /// weights, identity if it names neither.
+    pub fn from_accept(accept: &str) -> Self {
+        let mut best = (Self::Identity, 0.0);
+
+        for coding in accept.split(',') {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let quality = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let encoding = match name {
+                "*" => Some(Self::ANY),
+                name => Self::from_name(name),
+            };
+
+            if let Some(encoding) = encoding.filter(|encoding| *encoding != Self::Identity && quality > 0.0) {
+                if quality > best.1 || (quality == best.1 && encoding.as_str() == "zstd") {
+                    best = (encoding, quality)
+                }
+            }
+        }
+
+        best.0
+    }
+
+    /// `body` compressed in one go.
+    pub fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(*self)?;
+        let mut encoded = encoder.write(body)?;
+        encoded.extend(encoder.finish()?);
+
+        Ok(encoded)
+    }
+
+    /// `body` decompressed, at most `limit` bytes of it.
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused))]
+    fn decode(&self, body: Bytes, limit: u64) -> Result<Bytes, BodyError> {
+        let mut decoded = Vec::new();
+        let read: io::Result<usize> = match self {
+            Self::Identity => return Ok(body),
+            #[cfg(feature = "gzip")]
+            Self::Gzip => flate2::read::MultiGzDecoder::new(&body[..])
+                .take(limit + 1)
+                .read_to_end(&mut decoded),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::stream::read::Decoder::new(&body[..])
+                .and_then(|decoder| decoder.take(limit + 1).read_to_end(&mut decoded)),
+        };
+
+        match read {
+            Ok(read) if read as u64 > limit => Err(BodyError::TooLarge),
+            Ok(_) => Ok(decoded.into()),
+            Err(error) => Err(BodyError::Invalid(format!("invalid {} body: {}", self.as_str(), error))),
+        }
+    }
+}
+
+enum Encoder {
+    Identity(Vec<u8>),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Identity => Self::Identity(Vec::new()),
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => Self::Gzip(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default())),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Self::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+        })
+    }
+
+    /// Compresses and flushes `chunk`, returning what was encoded so far.
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let encoded = match self {
+            Self::Identity(encoded) => {
+                encoded.extend_from_slice(chunk);
+                encoded
+            }
+            #[cfg(feature = "gzip")]
+            Self::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+
+        Ok(std::mem::take(encoded))
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity(encoded) => Ok(encoded),
+            #[cfg(feature = "gzip")]
+            Self::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Why a request body couldn't be read.
+#[derive(Debug)]
+pub enum BodyError {
+    Unsupported(String),
+    Invalid(String),
+    TooLarge,
+}
+
+impl Reject for BodyError {}
+
+impl BodyError {
+    fn response(&self) -> Response {
+        let (message, status) = match self {
+            BodyError::Unsupported(encoding) => (
+                format!("unsupported content encoding {}", encoding),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            BodyError::Invalid(error) => (error.clone(), StatusCode::BAD_REQUEST),
+            BodyError::TooLarge => (
+                format!("body larger than {} bytes once decoded", MAX_DECODED_BYTES),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+        };
+
+        warp::reply::with_status(message, status).into_response()
+    }
+}
+
+/// Request body decoded according to its `Content-Encoding`, encodings applied in turn being
+/// undone in reverse.
+pub fn body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-encoding")
+        .and(warp::body::bytes())
+        .and_then(|encodings: Option<String>, body: Bytes| async move {
+            let encodings = encodings.unwrap_or_default();
+            let mut body = body;
+
+            for name in encodings.rsplit(',').filter(|name| !name.trim().is_empty()) {
+                let encoding = Encoding::from_name(name)
+                    .ok_or_else(|| warp::reject::custom(BodyError::Unsupported(name.trim().to_string())))?;
+                body = encoding.decode(body, MAX_DECODED_BYTES).map_err(warp::reject::custom)?;
+            }
+
+            Ok::<Bytes, Rejection>(body)
+        })
+}
+
+/// JSON request body, decoded according to its `Content-Encoding`.
+pub fn json<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    body().and_then(|body: Bytes| async move {
+        serde_json::from_slice(&body)
+            .map_err(|error| warp::reject::custom(BodyError::Invalid(format!("invalid JSON body: {}", error))))
+    })
+}
+
+/// Turns body rejections into 400/413/415 responses.
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    match rejection.find::<BodyError>() {
+        Some(error) => Ok(error.response()),
+        None => Err(rejection),
+    }
+}
+
+/// `response` compressed with `encoding`, unless it's already encoded or too small to bother.
+pub fn compress(encoding: Encoding, mut response: Response) -> Response {
+    response.headers_mut().append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let small = response
+        .body()
+        .size_hint()
+        .exact()
+        .is_some_and(|size| size < MIN_COMPRESSED_BYTES as u64);
+
+    if encoding == Encoding::Identity || small || response.headers().contains_key(CONTENT_ENCODING) {
+        return response;
+    }
+
+    let Ok(encoder) = Encoder::new(encoding) else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+
+    let body = stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                let encoded = encoder.write(&chunk);
+                Some((encoded, Some((body, encoder))))
+            }
+            Some(Err(error)) => Some((Err(io::Error::other(error)), None)),
+            None => Some((encoder.finish(), None)),
+        }
+    })
+    .filter(|encoded| std::future::ready(encoded.as_ref().map_or(true, |encoded| !encoded.is_empty())))
+    .map(|encoded| encoded.map(Bytes::from));
+
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// `routes` with their responses compressed as the requests accept.
+pub fn negotiate(routes: Routes) -> Routes {
+    warp::header::optional::<String>(ACCEPT_ENCODING.as_str())
+        .and(routes)
+        .map(|accept: Option<String>, response: Response| {
+            let encoding = accept.as_deref().map_or(Encoding::Identity, Encoding::from_accept);
+            compress(encoding, response)
+        })
+        .boxed()
+}
+
+/// Serialized JSON body encoded with `encoding` if it's large enough, with the encoding used.
+pub fn json_body<T: Serialize>(encoding: Encoding, value: &T) -> io::Result<(Vec<u8>, Encoding)> {
+    let body = serde_json::to_vec(value)?;
+
+    if body.len() < MIN_COMPRESSED_BYTES {
+        return Ok((body, Encoding::Identity));
+    }
+
+    Ok((encoding.encode(&body)?, encoding))
+}
+
+#[cfg(all(test, feature = "gzip", feature = "zstd"))]
+mod test {
+    use std::io::Read;
+
+    use warp::{http::StatusCode, Filter};
+
+    use super::{json, negotiate, Encoding, MIN_COMPRESSED_BYTES};
+    use crate::server;
+
+    #[test]
+    fn negotiates_encoding() {
+        assert_eq!(Encoding::from_accept("gzip, deflate, br, zstd"), Encoding::Zstd);
+        assert_eq!(Encoding::from_accept("zstd;q=0.5, gzip"), Encoding::Gzip);
+        assert_eq!(Encoding::from_accept("br, *;q=0.1"), Encoding::Gzip);
+        assert_eq!(Encoding::from_accept("gzip;q=0, identity"), Encoding::Identity);
+        assert_eq!(Encoding::from_accept(""), Encoding::Identity);
+    }
+
+    #[tokio::test]
+    async fn decodes_request_bodies() {
+        let route = warp::path!("logs").and(json::<Vec<String>>()).map(|logs: Vec<String>| logs.len().to_string());
+        let route = route.recover(super::handle_rejection);
+        let body = serde_json::to_vec(&vec!["disk full"; 100]).unwrap();
+
+        for encoding in [Encoding::Identity, Encoding::Gzip, Encoding::Zstd] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/logs")
+                .header("content-encoding", encoding.as_str())
+                .body(encoding.encode(&body).unwrap())
+                .reply(&route)
+                .await;
+
+            assert_eq!((response.status(), response.body().as_ref()), (StatusCode::OK, b"100".as_ref()));
+        }
+
+        let request = |encoding: &str, body: &[u8]| {
+            warp::test::request()
+                .method("POST")
+                .path("/logs")
+                .header("content-encoding", encoding)
+                .body(body)
+        };
+        assert_eq!(request("br", &body).reply(&route).await.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(request("gzip", &body).reply(&route).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(request("identity", b"[1, 2]").reply(&route).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn compresses_large_responses() {
+        let large = "disk full ".repeat(MIN_COMPRESSED_BYTES);
+        let routes = negotiate(server::routes(
+            warp::path!("large")
+                .map(move || large.clone())
+                .or(warp::path!("small").map(|| "ok".to_string()))
+                .unify(),
+        ));
+        let request = |path: &str, accept: &str| {
+            warp::test::request().path(path).header("accept-encoding", accept).reply(&routes)
+        };
+
+        let response = request("/large", "gzip").await;
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(response.body().as_ref()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "disk full ".repeat(MIN_COMPRESSED_BYTES));
+
+        let response = request("/large", "zstd").await;
+        assert_eq!(response.headers()["content-encoding"], "zstd");
+        let decoded = zstd::decode_all(response.body().as_ref()).unwrap();
+        assert_eq!(decoded.len(), 10 * MIN_COMPRESSED_BYTES);
+
+        assert!(!request("/small", "zstd").await.headers().contains_key("content-encoding"));
+        assert!(!request("/large", "br").await.headers().contains_key("content-encoding"));
+    }
+}
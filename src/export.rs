@@ -0,0 +1,397 @@
+//! Export formats of the read routes.
+//!
+//! Reads answer with a JSON array unless `?format=` or the `Accept` header asks for NDJSON (one
+//! JSON log per line), CSV or human-readable text lines. Bodies are encoded and sent
+//! [`CHUNK_LOGS`] logs at a time rather than built in one string, from iterators or streams that
+//! produce the logs lazily.
+
+use std::{convert::Infallible, error::Error};
+
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::header::CONTENT_TYPE,
+    hyper::Body,
+    reply::Response,
+    Filter, Rejection,
+};
+
+use crate::{
+    limits::{civil_from_days, SECONDS_PER_DAY},
+    logs::LogWrapper,
+    Fields, IsLog, LogLevel, ServiceLog, TraceContext, TracedLog,
+};
+
+/// Logs encoded per body chunk.
+pub const CHUNK_LOGS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Ndjson,
+    Csv,
+    Text,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/json" | "application/*" | "*/*" => Some(Self::Json),
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => Some(Self::Ndjson),
+            "text/csv" => Some(Self::Csv),
+            "text/plain" | "text/*" => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    /// Preferred supported format of an `Accept` header, JSON if it names none.
+    pub fn from_accept(accept: &str) -> Self {
+        let mut best = None;
+
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if let Some(format) = Self::from_media_type(&media_type).filter(|_| quality > 0.0) {
+                if best.is_none_or(|(_, best)| quality > best) {
+                    best = Some((format, quality))
+                }
+            }
+        }
+
+        best.map_or(Self::Json, |(format, _)| format)
+    }
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    format: Option<Format>,
+}
+
+/// Format requested by `?format=`, else by the `Accept` header. Unknown `format` values are
+/// rejected as an invalid query.
+pub fn format() -> impl Filter<Extract = (Format,), Error = Rejection> + Clone {
+    warp::query::<FormatQuery>()
+        .and(warp::header::optional::<String>("accept"))
+        .map(|query: FormatQuery, accept: Option<String>| {
+            query
+                .format
+                .unwrap_or_else(|| accept.as_deref().map_or(Format::Json, Format::from_accept))
+        })
+}
+
+/// Columns of a log in the CSV and text formats.
+pub struct Line {
+    pub user_id: Option<i64>,
+    pub time: i64,
+    pub level: LogLevel,
+    pub message: String,
+    pub fields: Fields,
+    pub trace: Option<TraceContext>,
+}
+
+/// A log that can be exported in every [`Format`].
+pub trait Export: Serialize {
+    const CSV_HEADER: &'static str = "time,level,message,fields,trace_id,span_id";
+
+    fn line(&self) -> Line;
+}
+
+impl Export for ServiceLog {
+    fn line(&self) -> Line {
+        Line {
+            user_id: None,
+            time: self.time,
+            level: self.level.clone(),
+            message: self.message.clone(),
+            fields: self.fields.clone(),
+            trace: self.trace.clone(),
+        }
+    }
+}
+
+impl<L: IsLog + Serialize> Export for LogWrapper<L> {
+    fn line(&self) -> Line {
+        Line {
+            user_id: None,
+            time: self.time,
+            level: self.inner.level(),
+            message: self.inner.message(),
+            fields: self.inner.fields(),
+            trace: self.inner.trace(),
+        }
+    }
+}
+
+impl<T: Export> Export for TracedLog<T> {
+    const CSV_HEADER: &'static str = "user_id,time,level,message,fields,trace_id,span_id";
+
+    fn line(&self) -> Line {
+        Line {
+            user_id: Some(self.user_id),
+            ..self.log.line()
+        }
+    }
+}
+
+/// `time` as an RFC 3339 UTC timestamp.
+fn rfc3339(time: i64) -> String {
+    let (year, month, day) = civil_from_days(time.div_euclid(SECONDS_PER_DAY));
+    let seconds = time.rem_euclid(SECONDS_PER_DAY);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `value` with the line breaks that would split a text line escaped.
+fn text_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+impl Line {
+    fn csv(&self) -> String {
+        let fields = if self.fields.is_empty() {
+            String::new()
+        } else {
+            serde_json::to_string(&crate::logs::fields_to_json(&self.fields)).unwrap()
+        };
+        let trace = self.trace.as_ref();
+
+        let columns = [
+            rfc3339(self.time),
+            self.level.as_str().to_string(),
+            self.message.clone(),
+            fields,
+            trace.and_then(|trace| trace.trace_id.clone()).unwrap_or_default(),
+            trace.and_then(|trace| trace.span_id.clone()).unwrap_or_default(),
+        ];
+
+        self.user_id
+            .map(|user_id| user_id.to_string())
+            .into_iter()
+            .chain(columns)
+            .map(|column| csv_field(&column))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// `2024-02-29T12:00:00Z ERROR user=5 message key=value trace_id=...`
+    fn text(&self) -> String {
+        let mut line = format!("{} {:<7}", rfc3339(self.time), self.level.as_str().to_uppercase());
+
+        if let Some(user_id) = self.user_id {
+            line += &format!(" user={}", user_id);
+        }
+
+        // One log per line.
+        line += " ";
+        line += &text_escape(&self.message);
+
+        for (key, value) in &self.fields {
+            line += &format!(" {}={}", text_escape(key), text_escape(&value.to_string()));
+        }
+
+        if let Some(trace) = &self.trace {
+            for (key, id) in [("trace_id", &trace.trace_id), ("span_id", &trace.span_id)] {
+                if let Some(id) = id {
+                    line += &format!(" {}={}", key, id);
+                }
+            }
+        }
+
+        line
+    }
+}
+
+impl Format {
+    fn prefix<T: Export>(&self) -> String {
+        match self {
+            Self::Json => "[".to_string(),
+            Self::Csv => format!("{}\n", T::CSV_HEADER),
+            Self::Ndjson | Self::Text => String::new(),
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Self::Json => "]",
+            _ => "",
+        }
+    }
+
+    fn encode<T: Export>(&self, first: bool, logs: &[T]) -> String {
+        let lines = logs.iter().map(|log| match self {
+            Self::Json | Self::Ndjson => serde_json::to_string(log).unwrap(),
+            Self::Csv => log.line().csv(),
+            Self::Text => log.line().text(),
+        });
+
+        match self {
+            Self::Json => {
+                let separator = if first { "" } else { "," };
+                format!("{}{}", separator, lines.collect::<Vec<_>>().join(","))
+            }
+            _ => lines.map(|line| line + "\n").collect(),
+        }
+    }
+}
+
+/// Streams `logs` in `format`, only pulling the logs of the chunk being encoded.
+pub fn reply<T, I>(format: Format, logs: I) -> Response
+where
+    T: Export + Send + 'static,
+    I: IntoIterator<Item = T>,
+    I::IntoIter: Send + 'static,
+{
+    reply_stream(format, stream::iter(logs).map(Ok::<_, Infallible>))
+}
+
+/// Streams the logs of a fallible stream in `format`. An error ends the body early, so the
+/// client sees a truncated response rather than a complete one.
+pub fn reply_stream<T, E, S>(format: Format, logs: S) -> Response
+where
+    T: Export + Send + 'static,
+    E: Error + Send + Sync + 'static,
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+{
+    let chunks = logs
+        .chunks(CHUNK_LOGS)
+        .enumerate()
+        .map(move |(index, chunk)| {
+            let chunk = chunk.into_iter().collect::<Result<Vec<_>, _>>()?;
+            Ok(format.encode(index == 0, &chunk))
+        });
+
+    let body = stream::once(async move { Ok(format.prefix::<T>()) })
+        .chain(chunks)
+        .chain(stream::once(async move { Ok(format.suffix().to_string()) }))
+        .filter(|chunk| std::future::ready(!matches!(chunk, Ok(chunk) if chunk.is_empty())))
+        .map(|chunk: Result<String, E>| chunk.map(Bytes::from));
+
+    let mut response = Response::new(Body::wrap_stream(body));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, format.content_type().parse().unwrap());
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use warp::Filter;
+
+    use futures_util::stream;
+
+    use super::{format, reply, reply_stream, Format, Line, CHUNK_LOGS};
+    use crate::{logs::LogWrapper, test::log, FieldValue, LogLevel, TracedLog};
+
+    async fn body(format: Format, logs: Vec<TracedLog<LogWrapper<crate::test::TestLog>>>) -> String {
+        let response = reply(format, logs);
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn negotiates_format() {
+        let route = format().map(|format: Format| format!("{:?}", format));
+        let negotiated = |query: &'static str, accept: &'static str| {
+            let route = route.clone();
+            async move {
+                let response = warp::test::request().path(query).header("accept", accept).reply(&route).await;
+                (response.status().as_u16(), String::from_utf8(response.body().to_vec()).unwrap())
+            }
+        };
+
+        assert_eq!(negotiated("/", "*/*").await, (200, "Json".to_string()));
+        assert_eq!(negotiated("/", "text/csv;q=0.5, application/x-ndjson").await, (200, "Ndjson".to_string()));
+        assert_eq!(negotiated("/", "image/png, text/plain;q=0.1").await, (200, "Text".to_string()));
+        assert_eq!(negotiated("/?format=csv&ledger=10", "application/json").await, (200, "Csv".to_string()));
+        assert_eq!(negotiated("/?format=xml", "").await.0, 400);
+    }
+
+    #[tokio::test]
+    async fn encodes_every_format() {
+        let logs = |count: usize| {
+            (0..count)
+                .map(|index| TracedLog {
+                    user_id: 5,
+                    log: LogWrapper {
+                        time: 1_709_208_000 + index as i64,
+                        inner: log(LogLevel::Error, "disk \"full\", again"),
+                    },
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let json = body(Format::Json, logs(CHUNK_LOGS + 1)).await;
+        assert_eq!(json, serde_json::to_string(&logs(CHUNK_LOGS + 1)).unwrap());
+        assert_eq!(body(Format::Json, vec![]).await, "[]");
+
+        let ndjson = body(Format::Ndjson, logs(2)).await;
+        assert_eq!(ndjson.lines().count(), 2);
+        assert!(ndjson.lines().all(|line| line.starts_with(r#"{"user_id":5,"time":"#)));
+
+        assert_eq!(
+            body(Format::Csv, logs(1)).await,
+            "user_id,time,level,message,fields,trace_id,span_id\n5,2024-02-29T12:00:00Z,error,\"disk \"\"full\"\", again\",,,\n"
+        );
+        assert_eq!(body(Format::Text, logs(1)).await, "2024-02-29T12:00:00Z ERROR   user=5 disk \"full\", again\n");
+    }
+
+    #[test]
+    fn text_lines_escape_line_breaks() {
+        let line = Line {
+            user_id: None,
+            time: 1_709_208_000,
+            level: LogLevel::Warning,
+            message: "first\r\nsecond \\n".to_string(),
+            fields: [("path".to_string(), FieldValue::String("a\nb".to_string()))].into(),
+            trace: None,
+        };
+
+        assert_eq!(line.text(), r"2024-02-29T12:00:00Z WARNING first\r\nsecond \\n path=a\nb");
+    }
+
+    #[tokio::test]
+    async fn stream_errors_truncate_the_body() {
+        let logs = (0..CHUNK_LOGS + 1).map(|index| {
+            match index {
+                CHUNK_LOGS => Err(std::io::Error::other("connection lost")),
+                _ => Ok(LogWrapper { time: index as i64, inner: log(LogLevel::Error, "") }),
+            }
+        });
+
+        let response = reply_stream(Format::Ndjson, stream::iter(logs));
+        assert!(warp::hyper::body::to_bytes(response.into_body()).await.is_err());
+    }
+}
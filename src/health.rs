@@ -0,0 +1,109 @@
+//! Liveness and readiness probes.
+//!
+//! `GET /healthz` only tells that the process serves requests. `GET /readyz` runs the checks of
+//! the service (Postgres reachable, schema migrated, writes not piling up, ...) and answers 503
+//! if any of them fails. Both answer on every listener and need no token.
+
+use std::future::Future;
+
+use serde::Serialize;
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+use crate::server::{self, Routes};
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    pub fn new(name: &'static str, result: Result<(), String>) -> Self {
+        Self {
+            name,
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct Health {
+    pub status: &'static str,
+    pub checks: Vec<Check>,
+}
+
+impl Health {
+    pub fn new(checks: Vec<Check>) -> Self {
+        let status = if checks.iter().all(|check| check.ok) {
+            "ok"
+        } else {
+            "unavailable"
+        };
+
+        Self { status, checks }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|check| check.ok)
+    }
+}
+
+impl Reply for Health {
+    fn into_response(self) -> Response {
+        let status = if self.is_ok() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        warp::reply::with_status(warp::reply::json(&self), status).into_response()
+    }
+}
+
+/// `GET /healthz` and `GET /readyz`, the latter answering with the result of `checks`.
+pub fn routes<C, F>(checks: C) -> Routes
+where
+    C: Fn() -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Vec<Check>> + Send,
+{
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .map(|| Health::new(vec![]));
+
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and_then(move || {
+            let checks = checks.clone();
+            async move { Ok::<_, Rejection>(Health::new(checks().await)) }
+        });
+
+    server::routes(healthz.or(readyz).unify())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{routes, Check};
+
+    #[tokio::test]
+    async fn readyz_fails_with_any_check() {
+        let probes = routes(|| async {
+            vec![
+                Check::new("db", Ok(())),
+                Check::new("writes", Err("saturated".to_string())),
+            ]
+        });
+
+        let healthz = warp::test::request().path("/healthz").reply(&probes).await;
+        assert_eq!(healthz.status(), 200);
+
+        let readyz = warp::test::request().path("/readyz").reply(&probes).await;
+        assert_eq!(readyz.status(), 503);
+        assert_eq!(
+            std::str::from_utf8(readyz.body()).unwrap(),
+            r#"{"status":"unavailable","checks":[{"name":"db","ok":true},{"name":"writes","ok":false,"error":"saturated"}]}"#
+        );
+    }
+}
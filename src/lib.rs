@@ -1,21 +1,61 @@
-pub use logs::{IsLog, LogLevel};
+pub use logs::{
+    FieldFilter, FieldValue, Fields, HistogramBucket, HistogramQuery, IsLog, LogLevel, LogSource, TraceContext,
+    UserStats,
+};
+pub use payload::{ContentType, PayloadCodecs, RenderedPayload};
 use logs::{LogWrapper, UserLogsGroup};
+pub use logs::{LogChunks, CHUNK_SIZE};
+use health::Check;
+use metrics::{Metrics, StoredGauges};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::Mutex;
 use tokio_postgres::Client;
 
+#[cfg(feature = "alerts")]
+pub mod alerts;
+
+#[cfg(feature = "compression")]
+pub mod compress;
+
+pub mod auth;
+pub mod config;
+pub mod encoding;
+pub mod export;
+pub mod health;
+pub mod limits;
 mod logs;
+pub mod metrics;
+
+#[cfg(feature = "otlp")]
+pub mod otlp;
+
+pub mod payload;
+pub mod server;
+pub mod syslog;
+pub mod tcp;
+
+#[cfg(feature = "memory")]
+mod snapshot;
+
+#[cfg(feature = "memory")]
+pub mod wal;
+
+#[cfg(feature = "memory")]
+pub use snapshot::Restored;
 
 #[cfg(feature = "sdk")]
 mod sdk;
 
 #[cfg(feature = "sdk")]
-pub use sdk::LoggingClient;
+pub use sdk::{in_trace, LoggingClient, TcpLoggingClient};
 
 #[cfg(feature = "storage")]
 mod storage;
 
+#[cfg(feature = "subscribers")]
+pub mod subscribers;
+
 #[cfg(feature = "storage")]
 pub use storage::MercuryLog;
 
@@ -23,6 +63,13 @@ pub use storage::MercuryLog;
 #[derive(Clone)]
 pub struct LoggerMemory<L> {
     state: Arc<Mutex<HashMap<i64, UserLogsGroup<L>>>>,
+    codecs: Arc<PayloadCodecs>,
+    wal: Option<Arc<wal::Journal<L>>>,
+    metrics: Option<Arc<Metrics>>,
+    #[cfg(feature = "subscribers")]
+    subscribers: Option<Arc<subscribers::Subscribers>>,
+    #[cfg(feature = "compression")]
+    compression: Option<Arc<compress::Codec<L>>>,
 }
 
 #[cfg(feature = "storage")]
@@ -30,178 +77,669 @@ pub struct LoggerMemory<L> {
 pub struct LoggerStorage {
     //db_path: String
     client: Client,
+    metrics: Option<Arc<Metrics>>,
+    pending_writes: std::sync::atomic::AtomicUsize,
+    stats: tokio::sync::Mutex<Option<(std::time::Instant, StoredGauges)>>,
+    codecs: Arc<PayloadCodecs>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ServiceLog {
     level: LogLevel,
     message: String,
-    data: Option<Vec<u8>>,
+    data: Option<RenderedPayload>,
+    #[serde(default)]
+    fields: Fields,
+    #[serde(default)]
+    source: Option<LogSource>,
+    #[serde(default)]
+    trace: Option<TraceContext>,
     time: i64,
 }
 
-impl<L: IsLog> From<&LogWrapper<L>> for ServiceLog {
-    fn from(value: &LogWrapper<L>) -> Self {
+/// Log returned by the cross-user trace reads, tagged with its owner.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TracedLog<T> {
+    pub user_id: i64,
+    #[serde(flatten)]
+    pub log: T,
+}
+
+impl ServiceLog {
+    /// Builds the unified view of `log`, rendering its payload through `codecs`.
+    pub fn render<L: IsLog>(log: &LogWrapper<L>, codecs: &PayloadCodecs) -> Self {
+        Self::render_at(log.inner(), log.time(), codecs)
+    }
+
+    pub fn level(&self) -> LogLevel {
+        self.level.clone()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Payload rendered as its declared content type.
+    pub fn data(&self) -> Option<&RenderedPayload> {
+        self.data.as_ref()
+    }
+
+    pub fn fields(&self) -> &Fields {
+        &self.fields
+    }
+
+    pub fn source(&self) -> Option<&LogSource> {
+        self.source.as_ref()
+    }
+
+    pub fn trace(&self) -> Option<&TraceContext> {
+        self.trace.as_ref()
+    }
+
+    /// Time the log was stored at, in seconds since the unix epoch.
+    pub fn time(&self) -> i64 {
+        self.time
+    }
+
+    /// Unified view of `log` stored at `time`.
+    pub(crate) fn render_at<L: IsLog>(log: &L, time: i64, codecs: &PayloadCodecs) -> Self {
+        let content_type = log.content_type();
+
         Self {
-            level: value.inner().level(),
-            message: value.inner().message(),
-            data: value.inner().data(),
-            time: value.time(),
+            level: log.level(),
+            message: log.message(),
+            data: log.data().map(|data| codecs.render(content_type.as_ref(), &data)),
+            fields: log.fields(),
+            source: log.source(),
+            trace: log.trace(),
+            time,
         }
     }
 }
 
+impl<L: IsLog> From<&LogWrapper<L>> for ServiceLog {
+    fn from(value: &LogWrapper<L>) -> Self {
+        Self::render(value, &PayloadCodecs::new())
+    }
+}
+
+#[cfg(feature = "memory")]
+impl<L: IsLog> Default for LoggerMemory<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(feature = "memory")]
 impl<L: IsLog> LoggerMemory<L> {
     pub fn new() -> Self {
+        Self::with_codecs(PayloadCodecs::new())
+    }
+
+    /// Logger rendering payloads of the unified view with the schemas registered in `codecs`.
+    pub fn with_codecs(codecs: PayloadCodecs) -> Self {
         Self {
             state: Arc::new(Mutex::new(HashMap::new())),
+            codecs: Arc::new(codecs),
+            wal: None,
+            metrics: None,
+            #[cfg(feature = "subscribers")]
+            subscribers: None,
+            #[cfg(feature = "compression")]
+            compression: None,
         }
     }
 
+    /// Schemas the payloads are rendered with, for reads that render logs themselves.
+    pub fn codecs(&self) -> Arc<PayloadCodecs> {
+        self.codecs.clone()
+    }
+
+    /// Records the accepted writes in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Publishes the stored logs to `subscribers`.
+    #[cfg(feature = "subscribers")]
+    pub fn with_subscribers(mut self, subscribers: Arc<subscribers::Subscribers>) -> Self {
+        self.subscribers = Some(subscribers);
+        self
+    }
+
     pub async fn read_users(&self) -> Vec<i64> {
         let state = self.state.lock().await;
-        state.keys().map(|x| *x).collect::<Vec<i64>>().clone()
+        state.keys().copied().collect::<Vec<i64>>().clone()
     }
 
     /// Unified view of all the logs
     pub async fn read_log(&self, user_id: i64) -> Vec<ServiceLog> {
-        let mut state = self.state.lock().await;
+        self.read_log_where(user_id, &FieldFilter::default()).await
+    }
 
-        let user_logs = state.get_mut(&user_id);
+    /// Unified view of the logs whose fields match `filter`.
+    pub async fn read_log_where(&self, user_id: i64, filter: &FieldFilter) -> Vec<ServiceLog> {
+        let levels = self.levels(user_id).await;
+        Self::render_where(levels, filter.clone(), self.codecs.clone()).collect()
+    }
 
-        if let Some(user_logs) = user_logs {
-            let mut all_logs_casted = Vec::new();
+    /// Snapshot of the logs of `user_id` at `level`. Taking it only clones shared chunk
+    /// pointers, and later writes don't change it.
+    pub async fn snapshot(&self, user_id: i64, level: LogLevel) -> LogChunks<L> {
+        let state = self.state.lock().await;
 
-            for log in user_logs.errors() {
-                all_logs_casted.push(log.into())
-            }
+        state
+            .get(&user_id)
+            .map(|user_logs| match level {
+                LogLevel::Error => user_logs.errors().clone(),
+                LogLevel::Warning => user_logs.warning().clone(),
+                LogLevel::Debug => user_logs.debug().clone(),
+            })
+            .unwrap_or_default()
+    }
 
-            for log in user_logs.debug() {
-                all_logs_casted.push(log.into())
-            }
+    /// Unified view of the logs whose fields match `filter`, rendered as the iterator is
+    /// consumed from a snapshot taken now.
+    pub async fn stream_log_where(
+        &self,
+        user_id: i64,
+        filter: FieldFilter,
+    ) -> impl Iterator<Item = ServiceLog> + Send + 'static
+    where
+        L: Send + Sync + 'static,
+    {
+        let levels = self.levels(user_id).await;
+        Self::render_where(levels, filter, self.codecs.clone())
+    }
 
-            for log in user_logs.warning() {
-                all_logs_casted.push(log.into())
-            }
+    /// Snapshots of the errors, debug and warning logs of `user_id`, in the order of the
+    /// unified view.
+    async fn levels(&self, user_id: i64) -> [LogChunks<L>; 3] {
+        let state = self.state.lock().await;
 
-            all_logs_casted
-        } else {
-            vec![]
+        state
+            .get(&user_id)
+            .map(|user_logs| [user_logs.errors().clone(), user_logs.debug().clone(), user_logs.warning().clone()])
+            .unwrap_or_default()
+    }
+
+    fn render_where(
+        levels: [LogChunks<L>; 3],
+        filter: FieldFilter,
+        codecs: Arc<PayloadCodecs>,
+    ) -> impl Iterator<Item = ServiceLog> {
+        levels
+            .into_iter()
+            .flat_map(LogChunks::into_logs)
+            .filter(move |log| log.matches(&filter))
+            .map(move |log| ServiceLog::render(&log, &codecs))
+    }
+
+    /// Logs of every user belonging to the trace `trace_id`, oldest first.
+    pub async fn read_trace(&self, trace_id: &str) -> Vec<TracedLog<ServiceLog>> {
+        let state = self.state.lock().await;
+        let mut traced = Vec::new();
+
+        for (user_id, user_logs) in state.iter() {
+            let logs = user_logs
+                .errors()
+                .iter()
+                .chain(user_logs.debug().iter())
+                .chain(user_logs.warning().iter());
+
+            for log in logs {
+                let in_trace = log
+                    .inner()
+                    .trace()
+                    .and_then(|trace| trace.trace_id)
+                    .is_some_and(|id| id == trace_id);
+
+                if in_trace {
+                    traced.push(TracedLog {
+                        user_id: *user_id,
+                        log: ServiceLog::render(&log, &self.codecs),
+                    })
+                }
+            }
         }
+
+        traced.sort_by_key(|traced| traced.log.time);
+        traced
     }
 
-    // NOTE: this clears past logs.
-    pub async fn is_logging(&self, user_id: i64) {
+    /// Deletes the logs of every user older than `time`.
+    pub async fn prune(&self, time: i64) {
         let mut state = self.state.lock().await;
 
-        let user_logs = state.get_mut(&user_id);
-
-        if let Some(user_logs) = user_logs {
-            user_logs.clear();
-            user_logs.is_logging()
-        } else {
-            return;
+        for user_logs in state.values_mut() {
+            user_logs.retain_since(time)
         }
     }
 
-    // NOTE: this clears past logs.
-    pub async fn is_not_logging(&self, user_id: i64) {
-        let mut state = self.state.lock().await;
+    /// Appends `record` to the WAL, if any. The operation must be applied before the state is
+    /// unlocked and acknowledged after [`Self::sync_journal`].
+    fn journal(&self, record: wal::WalRecord<&L>) -> std::io::Result<Option<wal::Position>> {
+        match &self.wal {
+            Some(journal) => journal.append(record),
+            None => Ok(None),
+        }
+    }
 
-        let user_logs = state.get_mut(&user_id);
+    /// Waits for the record journaled at `position` to be on disk, called without the state lock.
+    async fn sync_journal(&self, position: Option<wal::Position>) -> std::io::Result<()> {
+        match (&self.wal, position) {
+            (Some(journal), Some(position)) => journal.sync(position).await,
+            _ => Ok(()),
+        }
+    }
 
-        if let Some(user_logs) = user_logs {
+    fn apply_logging(state: &mut HashMap<i64, UserLogsGroup<L>>, user_id: i64, logging: bool) {
+        if let Some(user_logs) = state.get_mut(&user_id) {
             user_logs.clear();
-            user_logs.is_not_logging()
-        } else {
-            return;
+
+            if logging {
+                user_logs.is_logging()
+            } else {
+                user_logs.is_not_logging()
+            }
         }
     }
 
-    pub async fn write_log(&self, user_id: i64, log: L) {
-        match log.level() {
-            LogLevel::Error => self.write_error(user_id, log).await,
-            LogLevel::Debug => self.write_debug(user_id, log).await,
-            LogLevel::Warning => self.write_warning(user_id, log).await,
+    fn apply_write(
+        &self,
+        state: &mut HashMap<i64, UserLogsGroup<L>>,
+        user_id: i64,
+        time: i64,
+        level: LogLevel,
+        log: L,
+    ) -> bool {
+        // The group of a new user starts out not logging.
+        let user_logs = state.entry(user_id).or_insert_with(UserLogsGroup::new);
+        user_logs.add_at(time, level, log)
+    }
+
+    /// Seals the full chunks of `user_logs` in place, when restoring or replaying the state.
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn seal(&self, user_logs: &mut UserLogsGroup<L>) {
+        #[cfg(feature = "compression")]
+        if let Some(codec) = &self.compression {
+            user_logs.seal(codec)
         }
     }
 
-    pub async fn write_error(&self, user_id: i64, log: L) {
+    // NOTE: this clears past logs.
+    pub async fn is_logging(&self, user_id: i64) -> std::io::Result<()> {
+        let mut state = self.state.lock().await;
+        let position = self.journal(wal::WalRecord::Logging(user_id))?;
+        Self::apply_logging(&mut state, user_id, true);
+        drop(state);
+
+        self.sync_journal(position).await
+    }
+
+    // NOTE: this clears past logs.
+    pub async fn is_not_logging(&self, user_id: i64) -> std::io::Result<()> {
         let mut state = self.state.lock().await;
+        let position = self.journal(wal::WalRecord::NotLogging(user_id))?;
+        Self::apply_logging(&mut state, user_id, false);
+        drop(state);
 
-        let user_logs = state.get_mut(&user_id);
+        self.sync_journal(position).await
+    }
 
-        if let Some(user_logs) = user_logs {
-            user_logs.add_error(log);
-        } else {
-            let mut logs = UserLogsGroup::new();
-            logs.add_error(log);
-            state.insert(user_id, logs);
-        }
+    /// Stores `log` unless its user isn't logging, returns whether it was stored. Fails without
+    /// storing it if it cannot be journaled, or after storing it if the journal cannot be synced.
+    pub async fn write_log(&self, user_id: i64, log: L) -> std::io::Result<bool>
+    where
+        L: Send + Sync + 'static,
+    {
+        self.write_at(user_id, log.level(), log).await
     }
 
-    pub async fn write_warning(&self, user_id: i64, log: L) {
+    /// Same as [`Self::write_log`], `log` is stored, journaled and published at `level`.
+    async fn write_at(&self, user_id: i64, level: LogLevel, log: L) -> std::io::Result<bool>
+    where
+        L: Send + Sync + 'static,
+    {
+        let started = std::time::Instant::now();
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let bytes = limits::log_bytes(&log);
+
+        #[cfg(feature = "subscribers")]
+        let published = self
+            .subscribers
+            .as_ref()
+            .filter(|subscribers| subscribers.wants(user_id, &level))
+            .map(|subscribers| {
+                let rendered = ServiceLog::render_at(&log, time, &self.codecs);
+                (subscribers, ServiceLog { level: level.clone(), ..rendered })
+            });
+
         let mut state = self.state.lock().await;
+        let record = if level == log.level() {
+            wal::WalRecord::Write { user_id, time, log: &log }
+        } else {
+            wal::WalRecord::WriteAt { user_id, time, level: level.clone(), log: &log }
+        };
+        let position = match self.journal(record) {
+            Ok(position) => position,
+            Err(e) => return Err(self.wal_error(e)),
+        };
+        let stored = self.apply_write(&mut state, user_id, time, level.clone(), log);
+        #[cfg(feature = "compression")]
+        let filled = match (&self.compression, stored) {
+            (Some(_), true) => state.get(&user_id).and_then(|user_logs| user_logs.filled(&level)),
+            _ => None,
+        };
+        drop(state);
+
+        #[cfg(feature = "compression")]
+        if let Some(filled) = filled {
+            self.seal_filled(user_id, filled).await
+        }
 
-        let user_logs = state.get_mut(&user_id);
+        self.sync_journal(position).await.map_err(|e| self.wal_error(e))?;
 
-        if let Some(user_logs) = user_logs {
-            user_logs.add_warning(log)
-        } else {
-            let mut logs = UserLogsGroup::new();
-            logs.add_warning(log);
-            state.insert(user_id, logs);
+        #[cfg(feature = "subscribers")]
+        if let Some((subscribers, log)) = published.filter(|_| stored) {
+            subscribers.publish(user_id, log)
+        }
+
+        if let Some(metrics) = self.metrics.as_ref().filter(|_| stored) {
+            metrics.log_written("memory", level.as_str(), bytes, started)
         }
+
+        Ok(stored)
     }
 
-    pub async fn write_debug(&self, user_id: i64, log: L) {
-        let mut state = self.state.lock().await;
+    fn wal_error(&self, error: std::io::Error) -> std::io::Error {
+        if let Some(metrics) = &self.metrics {
+            metrics.log_rejected("wal_error")
+        }
+        error
+    }
 
-        let user_logs = state.get_mut(&user_id);
+    /// Readiness checks: the state can be locked and the WAL, if any, accepts writes.
+    pub async fn readiness(&self) -> Vec<Check> {
+        let state = tokio::time::timeout(std::time::Duration::from_secs(1), self.state.lock())
+            .await
+            .map(drop)
+            .map_err(|_| "state locked for more than 1s".to_string());
 
-        if let Some(user_logs) = user_logs {
-            user_logs.add_debug(log)
-        } else {
-            let mut logs = UserLogsGroup::new();
-            logs.add_debug(log);
-            state.insert(user_id, logs);
+        let mut checks = vec![Check::new("state", state)];
+        if let Some(journal) = &self.wal {
+            checks.push(Check::new("wal", journal.health()));
         }
+
+        checks
     }
 
-    pub async fn read_errros(&self, user_id: i64) -> Vec<LogWrapper<L>> {
+    /// Logging users and the logs they have stored, for the metrics.
+    pub async fn stats(&self) -> StoredGauges {
         let state = self.state.lock().await;
+        let mut stats = StoredGauges::default();
 
-        let user_logs = state.get(&user_id);
-        if user_logs.is_none() {
-            return vec![];
+        for (user_id, user_logs) in state.iter() {
+            let user = user_logs.stats(*user_id);
+
+            stats.active_users += u64::from(user_logs.logging());
+            stats.logs += user.debug + user.warning + user.error;
+            stats.bytes += user.bytes;
         }
 
-        let user_logs = user_logs.unwrap();
-        user_logs.errors().clone()
+        stats
     }
 
-    pub async fn read_debug(&self, user_id: i64) -> Vec<LogWrapper<L>> {
+    /// Summary of the logs of `user_id`, `None` for unknown users.
+    pub async fn user_stats(&self, user_id: i64) -> Option<UserStats> {
         let state = self.state.lock().await;
+        state.get(&user_id).map(|user_logs| user_logs.stats(user_id))
+    }
 
-        let user_logs = state.get(&user_id);
-        if user_logs.is_none() {
-            return vec![];
-        }
+    /// Logs of `user_id` per level in the time buckets of `query`.
+    pub async fn histogram(&self, user_id: i64, query: &HistogramQuery) -> Vec<HistogramBucket> {
+        let state = self.state.lock().await;
+        state
+            .get(&user_id)
+            .map(|user_logs| user_logs.histogram(query))
+            .unwrap_or_default()
+    }
 
-        let user_logs = user_logs.unwrap();
-        user_logs.debug().clone()
+    /// Summaries of every user, by user id.
+    pub async fn users_stats(&self) -> Vec<UserStats> {
+        let state = self.state.lock().await;
+        let mut users = state
+            .iter()
+            .map(|(user_id, user_logs)| user_logs.stats(*user_id))
+            .collect::<Vec<_>>();
+
+        users.sort_by_key(|user| user.user_id);
+        users
+    }
+
+    /// Same as [`Self::write_log`], `log` is stored at the error level whatever its own level.
+    pub async fn write_error(&self, user_id: i64, log: L) -> std::io::Result<bool>
+    where
+        L: Send + Sync + 'static,
+    {
+        self.write_at(user_id, LogLevel::Error, log).await
+    }
+
+    /// Same as [`Self::write_log`], `log` is stored at the warning level whatever its own level.
+    pub async fn write_warning(&self, user_id: i64, log: L) -> std::io::Result<bool>
+    where
+        L: Send + Sync + 'static,
+    {
+        self.write_at(user_id, LogLevel::Warning, log).await
+    }
+
+    /// Same as [`Self::write_log`], `log` is stored at the debug level whatever its own level.
+    pub async fn write_debug(&self, user_id: i64, log: L) -> std::io::Result<bool>
+    where
+        L: Send + Sync + 'static,
+    {
+        self.write_at(user_id, LogLevel::Debug, log).await
+    }
+
+    pub async fn read_errros(&self, user_id: i64) -> Vec<LogWrapper<L>> {
+        self.snapshot(user_id, LogLevel::Error).await.to_vec()
+    }
+
+    pub async fn read_debug(&self, user_id: i64) -> Vec<LogWrapper<L>> {
+        self.snapshot(user_id, LogLevel::Debug).await.to_vec()
     }
 
     pub async fn read_warning(&self, user_id: i64) -> Vec<LogWrapper<L>> {
-        let state = self.state.lock().await;
+        self.snapshot(user_id, LogLevel::Warning).await.to_vec()
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use crate::{IsLog, LogLevel, LoggerMemory, TraceContext};
+
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub(crate) struct TestLog {
+        pub level: LogLevel,
+        pub message: String,
+        pub trace: Option<TraceContext>,
+    }
+
+    impl IsLog for TestLog {
+        fn data(&self) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn message(&self) -> String {
+            self.message.clone()
+        }
+
+        fn level(&self) -> LogLevel {
+            self.level.clone()
+        }
+
+        fn trace(&self) -> Option<TraceContext> {
+            self.trace.clone()
+        }
+    }
+
+    pub(crate) fn log(level: LogLevel, message: &str) -> TestLog {
+        TestLog {
+            level,
+            message: message.into(),
+            trace: None,
+        }
+    }
+
+    /// Logger where `users` are already logging.
+    pub(crate) async fn logging_users(users: &[i64]) -> LoggerMemory<TestLog> {
+        let logger = LoggerMemory::new();
+
+        for user_id in users {
+            // The first write creates the (non-logging) user group.
+            logger.write_log(*user_id, log(LogLevel::Debug, "")).await.unwrap();
+            logger.is_logging(*user_id).await.unwrap();
+        }
 
-        let user_logs = state.get(&user_id);
-        if user_logs.is_none() {
-            return vec![];
+        logger
+    }
+
+    #[tokio::test]
+    async fn read_trace_across_users() {
+        let logger = logging_users(&[1, 2]).await;
+        let traced = |message: &str, trace_id: &str| TestLog {
+            trace: Some(TraceContext {
+                trace_id: Some(trace_id.into()),
+                ..Default::default()
+            }),
+            ..log(LogLevel::Error, message)
+        };
+
+        logger.write_log(1, traced("first", "abc")).await.unwrap();
+        logger.write_log(2, traced("second", "abc")).await.unwrap();
+        logger.write_log(2, traced("other", "def")).await.unwrap();
+        logger.write_log(1, log(LogLevel::Warning, "untraced")).await.unwrap();
+
+        let mut users = logger
+            .read_trace("abc")
+            .await
+            .iter()
+            .map(|traced| traced.user_id)
+            .collect::<Vec<_>>();
+        users.sort();
+
+        assert_eq!(users, vec![1, 2]);
+        assert!(logger.read_trace("missing").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stats_follow_writes_and_pruning() {
+        let logger = logging_users(&[1]).await;
+        assert!(logger.write_log(1, log(LogLevel::Error, "abc")).await.unwrap());
+        assert!(logger.write_log(1, log(LogLevel::Debug, "de")).await.unwrap());
+
+        let stats = logger.user_stats(1).await.unwrap();
+        assert_eq!((stats.error, stats.debug, stats.warning), (1, 1, 0));
+        assert_eq!(stats.bytes, 5);
+        assert_eq!(stats.logging, Some(true));
+        assert!(stats.first_log.is_some());
+
+        logger.prune(i64::MAX).await;
+        let stats = logger.user_stats(1).await.unwrap();
+        assert_eq!((stats.error, stats.debug, stats.bytes, stats.last_log), (0, 0, 0, None));
+
+        assert!(logger.user_stats(2).await.is_none());
+        assert_eq!(logger.users_stats().await.len(), 1);
+
+        // Logs of users not logging are not stored.
+        assert!(!logger.write_log(2, log(LogLevel::Debug, "dropped")).await.unwrap());
+        assert_eq!(logger.user_stats(2).await.unwrap().debug, 0);
+    }
+
+    #[cfg(feature = "storage")]
+    #[tokio::test]
+    async fn renders_registered_bincode_payloads() {
+        use crate::{ContentType, MercuryLog, PayloadCodecs, RenderedPayload, ServiceLog};
+
+        #[derive(Serialize, Deserialize)]
+        struct Transfer {
+            from: String,
+            amount: i64,
+        }
+
+        let mut codecs = PayloadCodecs::new();
+        codecs.register::<Transfer>("transfer");
+        let logger = LoggerMemory::<MercuryLog>::with_codecs(codecs);
+        // The first write creates the (non-logging) user group.
+        logger.write_log(5, MercuryLog::new(LogLevel::Debug, String::new())).await.unwrap();
+        logger.is_logging(5).await.unwrap();
+
+        let transfer = Transfer { from: "GABC".into(), amount: 10 };
+        let log = MercuryLog {
+            data: Some(bincode::serialize(&transfer).unwrap()),
+            content_type: Some(ContentType::Bincode("transfer".into())),
+            ..MercuryLog::new(LogLevel::Error, "transfer failed".into())
+        };
+        logger.write_log(5, log).await.unwrap();
+
+        // As read back by an SDK client.
+        let read = serde_json::to_string(&logger.read_log(5).await).unwrap();
+        let read: Vec<ServiceLog> = serde_json::from_str(&read).unwrap();
+        assert_eq!(read[0].message(), "transfer failed");
+        assert_eq!(
+            read[0].data(),
+            Some(&RenderedPayload::Json(serde_json::json!({"from": "GABC", "amount": 10})))
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_from_snapshot() {
+        let logger = logging_users(&[1]).await;
+        logger.write_log(1, log(LogLevel::Error, "before")).await.unwrap();
+
+        let errors = logger.snapshot(1, LogLevel::Error).await;
+        let unified = logger.stream_log_where(1, Default::default()).await;
+        logger.write_log(1, log(LogLevel::Error, "after")).await.unwrap();
+        logger.is_logging(1).await.unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(unified.map(|log| log.message).collect::<Vec<_>>(), ["before"]);
+        assert!(logger.snapshot(1, LogLevel::Error).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn histogram_buckets() {
+        let logger = logging_users(&[1]).await;
+
+        {
+            let mut state = logger.state.lock().await;
+            let user_logs = state.get_mut(&1).unwrap();
+            for (time, level) in [(59, LogLevel::Error), (60, LogLevel::Error), (61, LogLevel::Debug), (185, LogLevel::Warning)] {
+                user_logs.add_at(time, level.clone(), log(level, ""));
+            }
         }
 
-        let user_logs = user_logs.unwrap();
-        user_logs.warning().clone()
+        let query = |since| crate::HistogramQuery { width: 60, since, until: None };
+        let buckets = logger.histogram(1, &query(None)).await;
+
+        assert_eq!(
+            buckets.iter().map(|bucket| (bucket.start, bucket.debug, bucket.warning, bucket.error)).collect::<Vec<_>>(),
+            vec![(0, 0, 0, 1), (60, 1, 0, 1), (180, 0, 1, 0)]
+        );
+        assert_eq!(logger.histogram(1, &query(Some(61))).await.len(), 2);
+        assert!(logger.histogram(2, &query(None)).await.is_empty());
+
+        let reversed = crate::HistogramQuery { until: Some(60), ..query(Some(120)) };
+        assert_eq!(reversed.validate(), Err("since must not be after until"));
+        assert!(logger.histogram(1, &reversed).await.is_empty());
+        assert!(crate::HistogramQuery { width: u64::MAX, ..query(None) }.validate().is_err());
+        assert!(crate::HistogramQuery { width: 0, ..query(None) }.validate().is_err());
     }
 }
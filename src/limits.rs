@@ -0,0 +1,471 @@
+//! Per-user ingestion rate limits and quotas.
+//!
+//! Every user gets a token bucket (one token per log) and daily/monthly count and byte quotas.
+//! Limits are checked before a log is handed to the logger, rejected logs don't consume anything.
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use warp::{http::StatusCode, reply::Response, Reply};
+
+use crate::IsLog;
+
+pub(crate) const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Token bucket refilling `per_second` tokens up to `burst`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RateLimit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+/// Caps on the logs accepted per UTC day and month. `None` is unlimited.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Quota {
+    pub daily_count: Option<u64>,
+    pub daily_bytes: Option<u64>,
+    pub monthly_count: Option<u64>,
+    pub monthly_bytes: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct UserLimits {
+    pub rate: Option<RateLimit>,
+    pub quota: Quota,
+}
+
+impl Quota {
+    /// Quota with the caps of `self`, and those of `defaults` where `self` has none.
+    fn or(&self, defaults: &Quota) -> Quota {
+        Quota {
+            daily_count: self.daily_count.or(defaults.daily_count),
+            daily_bytes: self.daily_bytes.or(defaults.daily_bytes),
+            monthly_count: self.monthly_count.or(defaults.monthly_count),
+            monthly_bytes: self.monthly_bytes.or(defaults.monthly_bytes),
+        }
+    }
+}
+
+/// Default limits and per-user overrides. Overrides are merged with the defaults field by
+/// field: a rate or quota cap left unset in an override is the default one.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub defaults: UserLimits,
+    #[serde(deserialize_with = "user_ids")]
+    pub users: HashMap<i64, UserLimits>,
+}
+
+// Table keys are always strings in TOML, parse them as user ids.
+fn user_ids<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<HashMap<i64, UserLimits>, D::Error> {
+    HashMap::<String, UserLimits>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(user_id, limits)| {
+            user_id
+                .parse()
+                .map(|user_id| (user_id, limits))
+                .map_err(|_| serde::de::Error::custom(format!("invalid user id {}", user_id)))
+        })
+        .collect()
+}
+
+impl LimitsConfig {
+    /// Describes the invalid rate limits, if any.
+    pub fn validate(&self) -> Vec<String> {
+        let users = self.users.iter().map(|(user_id, limits)| (format!("user {}", user_id), limits));
+
+        std::iter::once(("defaults".to_string(), &self.defaults))
+            .chain(users)
+            .filter_map(|(name, limits)| {
+                let rate = limits.rate.as_ref()?;
+                (rate.burst < 1.0 || rate.per_second <= 0.0).then(|| {
+                    format!("limits: {} rate needs a burst of at least 1 and a positive per_second", name)
+                })
+            })
+            .collect()
+    }
+
+    pub fn for_user(&self, user_id: i64) -> UserLimits {
+        match self.users.get(&user_id) {
+            Some(limits) => UserLimits {
+                rate: limits.rate.clone().or_else(|| self.defaults.rate.clone()),
+                quota: limits.quota.or(&self.defaults.quota),
+            },
+            None => self.defaults.clone(),
+        }
+    }
+}
+
+/// Why a log was rejected and when the producer should retry.
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct LimitExceeded {
+    pub reason: &'static str,
+    pub retry_after: u64,
+}
+
+impl Reply for LimitExceeded {
+    fn into_response(self) -> Response {
+        let retry_after = self.retry_after.to_string();
+
+        warp::reply::with_header(
+            warp::reply::with_status(self.reason, StatusCode::TOO_MANY_REQUESTS),
+            "Retry-After",
+            retry_after,
+        )
+        .into_response()
+    }
+}
+
+/// Usage of a user in the current windows, as returned by the quota endpoints.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct QuotaUsage {
+    pub daily_count: u64,
+    pub daily_bytes: u64,
+    pub monthly_count: u64,
+    pub monthly_bytes: u64,
+    pub tokens: Option<f64>,
+    pub limits: UserLimits,
+}
+
+#[derive(Default)]
+struct UserUsage {
+    tokens: Option<f64>,
+    refilled_at: f64,
+    day: i64,
+    daily_count: u64,
+    daily_bytes: u64,
+    month: i64,
+    monthly_count: u64,
+    monthly_bytes: u64,
+}
+
+impl UserUsage {
+    fn roll_windows(&mut self, now: f64) {
+        let day = now as i64 / SECONDS_PER_DAY;
+        if day != self.day {
+            self.day = day;
+            self.daily_count = 0;
+            self.daily_bytes = 0;
+        }
+
+        let (month, _) = month_window(now as i64);
+        if month != self.month {
+            self.month = month;
+            self.monthly_count = 0;
+            self.monthly_bytes = 0;
+        }
+    }
+
+    fn refill(&mut self, rate: &RateLimit, now: f64) -> f64 {
+        let tokens = match self.tokens {
+            Some(tokens) => (tokens + (now - self.refilled_at) * rate.per_second).min(rate.burst),
+            None => rate.burst,
+        };
+
+        self.tokens = Some(tokens);
+        self.refilled_at = now;
+        tokens
+    }
+
+    /// Whether forgetting the usage changes nothing but the reported monthly usage: the bucket
+    /// has refilled, the day has passed, and so has the month if it has a quota.
+    fn is_idle(&self, limits: &UserLimits, now: f64) -> bool {
+        let refilled = match (&limits.rate, self.tokens) {
+            (Some(rate), Some(tokens)) => tokens + (now - self.refilled_at) * rate.per_second >= rate.burst,
+            _ => true,
+        };
+        let monthly_quota = limits.quota.monthly_count.is_some() || limits.quota.monthly_bytes.is_some();
+
+        refilled
+            && self.day != now as i64 / SECONDS_PER_DAY
+            && (!monthly_quota || self.month != month_window(now as i64).0)
+    }
+}
+
+pub struct Limiter {
+    config: LimitsConfig,
+    usage: Mutex<HashMap<i64, UserUsage>>,
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accounts for `log` if it is within the limits of `user_id`.
+    pub async fn check<L: IsLog>(&self, user_id: i64, log: &L) -> Result<(), LimitExceeded> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+
+        self.check_at(user_id, log_bytes(log), now).await
+    }
+
+    pub(crate) async fn check_at(&self, user_id: i64, bytes: u64, now: f64) -> Result<(), LimitExceeded> {
+        let limits = self.config.for_user(user_id);
+        let mut usage = self.usage.lock().await;
+        let usage = usage.entry(user_id).or_default();
+
+        usage.roll_windows(now);
+
+        let day_end = (usage.day + 1) * SECONDS_PER_DAY;
+        let (_, month_end) = month_window(now as i64);
+        let until = |end: i64| (end - now as i64).max(1) as u64;
+
+        let exceeds = |limit: Option<u64>, used: u64, added: u64| {
+            limit.is_some_and(|limit| used + added > limit)
+        };
+
+        if exceeds(limits.quota.daily_count, usage.daily_count, 1) {
+            return Err(LimitExceeded { reason: "daily log quota exceeded", retry_after: until(day_end) });
+        }
+
+        if exceeds(limits.quota.daily_bytes, usage.daily_bytes, bytes) {
+            return Err(LimitExceeded { reason: "daily byte quota exceeded", retry_after: until(day_end) });
+        }
+
+        if exceeds(limits.quota.monthly_count, usage.monthly_count, 1) {
+            return Err(LimitExceeded { reason: "monthly log quota exceeded", retry_after: until(month_end) });
+        }
+
+        if exceeds(limits.quota.monthly_bytes, usage.monthly_bytes, bytes) {
+            return Err(LimitExceeded { reason: "monthly byte quota exceeded", retry_after: until(month_end) });
+        }
+
+        if let Some(rate) = &limits.rate {
+            let tokens = usage.refill(rate, now);
+
+            if tokens < 1.0 {
+                let retry_after = ((1.0 - tokens) / rate.per_second).ceil().max(1.0) as u64;
+                return Err(LimitExceeded { reason: "rate limit exceeded", retry_after });
+            }
+
+            usage.tokens = Some(tokens - 1.0);
+        }
+
+        usage.daily_count += 1;
+        usage.daily_bytes += bytes;
+        usage.monthly_count += 1;
+        usage.monthly_bytes += bytes;
+
+        Ok(())
+    }
+
+    /// Forgets the usage of the users whose bucket has refilled and whose quota windows have
+    /// passed, so that it doesn't grow with every user ever seen. To be run periodically.
+    pub async fn evict_idle(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+
+        self.evict_idle_at(now).await
+    }
+
+    pub(crate) async fn evict_idle_at(&self, now: f64) {
+        let mut usage = self.usage.lock().await;
+        usage.retain(|user_id, usage| !usage.is_idle(&self.config.for_user(*user_id), now));
+    }
+
+    pub async fn usage(&self, user_id: i64) -> QuotaUsage {
+        let limits = self.config.for_user(user_id);
+        let usage = self.usage.lock().await;
+
+        match usage.get(&user_id) {
+            Some(usage) => QuotaUsage {
+                daily_count: usage.daily_count,
+                daily_bytes: usage.daily_bytes,
+                monthly_count: usage.monthly_count,
+                monthly_bytes: usage.monthly_bytes,
+                tokens: usage.tokens,
+                limits,
+            },
+            None => QuotaUsage {
+                limits,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Bytes accounted for a log: its message and payload.
+pub fn log_bytes<L: IsLog>(log: &L) -> u64 {
+    (log.message().len() + log.data().map(|data| data.len()).unwrap_or(0)) as u64
+}
+
+/// Index of the UTC month containing `timestamp` and the timestamp at which it ends.
+fn month_window(timestamp: i64) -> (i64, i64) {
+    let (year, month, _) = civil_from_days(timestamp.div_euclid(SECONDS_PER_DAY));
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+
+    (
+        year * 12 + month as i64 - 1,
+        days_from_civil(next_year, next_month, 1) * SECONDS_PER_DAY,
+    )
+}
+
+// Days since the unix epoch to (year, month, day), see http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod test {
+    use super::{month_window, LimitsConfig, Limiter, Quota, RateLimit, UserLimits};
+
+    // 2024-02-29T12:00:00Z
+    const NOW: f64 = 1_709_208_000.0;
+
+    #[test]
+    fn month_window_ends_on_next_month() {
+        // 2024-03-01T00:00:00Z
+        assert_eq!(month_window(NOW as i64).1, 1_709_251_200);
+    }
+
+    #[tokio::test]
+    async fn token_bucket_and_quotas() {
+        let limiter = Limiter::new(LimitsConfig {
+            defaults: UserLimits {
+                rate: Some(RateLimit { burst: 2.0, per_second: 0.5 }),
+                quota: Quota::default(),
+            },
+            users: [(
+                2,
+                UserLimits {
+                    rate: None,
+                    quota: Quota {
+                        daily_bytes: Some(10),
+                        ..Default::default()
+                    },
+                },
+            )]
+            .into(),
+        });
+
+        assert!(limiter.check_at(1, 5, NOW).await.is_ok());
+        assert!(limiter.check_at(1, 5, NOW).await.is_ok());
+        assert_eq!(limiter.check_at(1, 5, NOW).await.unwrap_err().retry_after, 2);
+        assert!(limiter.check_at(1, 5, NOW + 2.0).await.is_ok());
+
+        assert!(limiter.check_at(2, 8, NOW).await.is_ok());
+        let exceeded = limiter.check_at(2, 8, NOW).await.unwrap_err();
+        assert_eq!(exceeded.retry_after, 12 * 3600);
+        assert!(limiter.check_at(2, 8, NOW + 12.0 * 3600.0).await.is_ok());
+
+        assert_eq!(limiter.usage(1).await.daily_count, 3);
+        assert_eq!(limiter.usage(2).await.daily_bytes, 8);
+    }
+
+    #[test]
+    fn merges_overrides_with_defaults() {
+        let config = LimitsConfig {
+            defaults: UserLimits {
+                rate: Some(RateLimit { burst: 100.0, per_second: 10.0 }),
+                quota: Quota {
+                    daily_count: Some(100_000),
+                    ..Default::default()
+                },
+            },
+            users: [(
+                42,
+                UserLimits {
+                    rate: None,
+                    quota: Quota {
+                        monthly_bytes: Some(1_000_000_000),
+                        ..Default::default()
+                    },
+                },
+            )]
+            .into(),
+        };
+
+        assert_eq!(
+            config.for_user(42),
+            UserLimits {
+                rate: Some(RateLimit { burst: 100.0, per_second: 10.0 }),
+                quota: Quota {
+                    daily_count: Some(100_000),
+                    monthly_bytes: Some(1_000_000_000),
+                    ..Default::default()
+                },
+            }
+        );
+        assert_eq!(config.for_user(1), config.defaults);
+    }
+
+    #[tokio::test]
+    async fn evicts_idle_usage() {
+        let limiter = Limiter::new(LimitsConfig {
+            defaults: UserLimits {
+                rate: Some(RateLimit { burst: 2.0, per_second: 1.0 }),
+                quota: Quota::default(),
+            },
+            users: [(
+                2,
+                UserLimits {
+                    rate: None,
+                    quota: Quota {
+                        monthly_count: Some(10),
+                        ..Default::default()
+                    },
+                },
+            )]
+            .into(),
+        });
+        let users = |now| {
+            let limiter = &limiter;
+            async move {
+                limiter.evict_idle_at(now).await;
+                limiter.usage.lock().await.keys().copied().collect::<std::collections::BTreeSet<_>>()
+            }
+        };
+
+        // 2024-02-28T12:00:00Z
+        let start = NOW - 86_400.0;
+        assert!(limiter.check_at(1, 5, start).await.is_ok());
+        assert!(limiter.check_at(1, 5, start).await.is_ok());
+        assert!(limiter.check_at(2, 5, start).await.is_ok());
+
+        // Kept until the bucket has refilled and the day has passed, and the month for a
+        // monthly quota.
+        assert_eq!(users(start + 3600.0).await, [1, 2].into());
+        assert_eq!(limiter.usage(1).await.tokens, Some(0.0));
+        assert_eq!(users(NOW).await, [2].into());
+        assert_eq!(limiter.usage(1).await.tokens, None);
+
+        // 2024-03-01T12:00:00Z
+        assert_eq!(users(NOW + 86_400.0).await, [].into());
+        assert_eq!(limiter.usage(2).await.monthly_count, 0);
+    }
+}
@@ -1,11 +1,203 @@
-use std::time::SystemTime;
+use std::{borrow::Cow, collections::BTreeMap, collections::HashMap, fmt, sync::Arc};
 
-use serde::{Deserialize, Serialize};
+use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{limits::log_bytes, payload::ContentType};
+
+#[cfg(feature = "compression")]
+use crate::compress::{Codec, Dictionary, MemoryUsage, Sealed};
 
 pub trait IsLog: Clone {
     fn message(&self) -> String;
     fn data(&self) -> Option<Vec<u8>>;
     fn level(&self) -> LogLevel;
+
+    /// Structured key-value fields attached to the log (e.g. `contract_id`, `ledger`).
+    fn fields(&self) -> Fields {
+        Fields::new()
+    }
+
+    /// Declared encoding of `data()`, used to render it on reads.
+    fn content_type(&self) -> Option<ContentType> {
+        None
+    }
+
+    /// Component and location that produced the log.
+    fn source(&self) -> Option<LogSource> {
+        None
+    }
+
+    /// Distributed trace correlation ids.
+    fn trace(&self) -> Option<TraceContext> {
+        None
+    }
+}
+
+/// Correlation ids following a request across services.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: Option<String>,
+    pub span_id: Option<String>,
+    pub request_id: Option<String>,
+}
+
+/// Metadata about where a log was produced. All the fields are optional.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct LogSource {
+    /// Target or module path, e.g. `my_crate::ingest`.
+    pub target: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub hostname: Option<String>,
+    pub pid: Option<u32>,
+    /// Producer-defined component name.
+    pub component: Option<String>,
+}
+
+impl LogSource {
+    /// Textual value of the source attribute `key`, as matched by `source.{key}` filters.
+    pub fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "target" => self.target.clone(),
+            "file" => self.file.clone(),
+            "line" => self.line.map(|line| line.to_string()),
+            "hostname" => self.hostname.clone(),
+            "pid" => self.pid.map(|pid| pid.to_string()),
+            "component" => self.component.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Structured fields of a log, keyed by field name.
+pub type Fields = BTreeMap<String, FieldValue>;
+
+/// Value of a structured log field.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum FieldValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl FieldValue {
+    /// Plain JSON representation, as stored in the `fields` JSONB column.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::String(s) => serde_json::Value::from(s.clone()),
+            Self::Integer(n) => serde_json::Value::from(*n),
+            Self::Float(n) => serde_json::Value::from(*n),
+            Self::Bool(b) => serde_json::Value::from(*b),
+        }
+    }
+
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::String(s) => Some(Self::String(s.clone())),
+            serde_json::Value::Bool(b) => Some(Self::Bool(*b)),
+            serde_json::Value::Number(n) => n
+                .as_i64()
+                .map(Self::Integer)
+                .or_else(|| n.as_f64().map(Self::Float)),
+            _ => None,
+        }
+    }
+}
+
+// Matches the text that Postgres' `->>` operator yields for the JSON value.
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "{}", s),
+            Self::Integer(n) => write!(f, "{}", n),
+            Self::Float(n) => write!(f, "{}", serde_json::Value::from(*n)),
+            Self::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+pub fn fields_to_json(fields: &Fields) -> serde_json::Value {
+    serde_json::Value::Object(
+        fields
+            .iter()
+            .map(|(key, value)| (key.clone(), value.to_json()))
+            .collect(),
+    )
+}
+
+pub fn fields_from_json(value: &serde_json::Value) -> Fields {
+    let mut fields = Fields::new();
+
+    if let serde_json::Value::Object(map) = value {
+        for (key, value) in map {
+            if let Some(value) = FieldValue::from_json(value) {
+                fields.insert(key.clone(), value);
+            }
+        }
+    }
+
+    fields
+}
+
+/// Query parameter prefix selecting source attributes rather than fields, e.g. `?source.component=api`.
+pub const SOURCE_PREFIX: &str = "source.";
+
+/// Query parameters of the read endpoints that aren't field conditions.
+pub const RESERVED_PARAMS: [&str; 1] = ["format"];
+
+/// Field equality filter used by the read endpoints.
+/// Values are compared on their textual representation, so `?ledger=10` matches
+/// both an integer and a string `10`. Keys prefixed by [`SOURCE_PREFIX`] match
+/// the log's [`LogSource`] instead, and [`RESERVED_PARAMS`] are left out.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(from = "HashMap<String, String>")]
+pub struct FieldFilter(pub HashMap<String, String>);
+
+impl From<HashMap<String, String>> for FieldFilter {
+    fn from(mut params: HashMap<String, String>) -> Self {
+        for reserved in RESERVED_PARAMS {
+            params.remove(reserved);
+        }
+
+        Self(params)
+    }
+}
+
+impl FieldFilter {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, fields: &Fields) -> bool {
+        self.fields().all(|(key, expected)| {
+            fields
+                .get(key)
+                .map(|value| &value.to_string() == expected)
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn matches_source(&self, source: Option<&LogSource>) -> bool {
+        self.source().all(|(key, expected)| {
+            source
+                .and_then(|source| source.get(key))
+                .map(|value| &value == expected)
+                .unwrap_or(false)
+        })
+    }
+
+    /// Field conditions.
+    pub fn fields(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter().filter(|(key, _)| !key.starts_with(SOURCE_PREFIX))
+    }
+
+    /// Source conditions, with the prefix stripped from the keys.
+    pub fn source(&self) -> impl Iterator<Item = (&str, &String)> {
+        self.0
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(SOURCE_PREFIX)?, value)))
+    }
 }
 /* 
 /// Permitted log levels.
@@ -17,7 +209,7 @@ pub enum LogLevel {
     Debug,
 }*/
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 #[repr(u32)]
 pub enum LogLevel {
     Debug = 0,
@@ -26,6 +218,14 @@ pub enum LogLevel {
 }
 
 impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Debug => "debug",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+
     pub fn from_u32(n: u32) -> Self {
         match n {
             0 => Self::Debug,
@@ -49,24 +249,430 @@ impl<L: IsLog> LogWrapper<L> {
     pub(crate) fn time(&self) -> i64 {
         self.time
     }
+
+    pub fn matches(&self, filter: &FieldFilter) -> bool {
+        filter.is_empty()
+            || (filter.matches(&self.inner.fields())
+                && filter.matches_source(self.inner.source().as_ref()))
+    }
+}
+
+/// Logs kept per chunk of a [`LogChunks`].
+pub const CHUNK_SIZE: usize = 1024;
+
+/// Logs of a level, oldest first, in shared chunks of at most [`CHUNK_SIZE`] logs. Cloning only
+/// clones the chunk pointers, and only the last chunk is copied on the next write if a clone
+/// still holds it, so reads can take a snapshot under the lock and go through it afterwards.
+/// With compression on, full chunks are sealed and decoded on every read.
+///
+/// Serialized as a plain sequence of logs.
+#[derive(Debug, Clone)]
+pub struct LogChunks<L> {
+    chunks: Vec<Chunk<L>>,
+    len: usize,
+    /// Dictionary the chunks are sealed with, interned from the first one.
+    #[cfg(feature = "compression")]
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+#[derive(Debug, Clone)]
+enum Chunk<L> {
+    Open(Arc<Vec<LogWrapper<L>>>),
+    #[cfg(feature = "compression")]
+    Sealed(Arc<Sealed<L>>),
+}
+
+/// Full open chunk of a level, taken out of the state to be sealed.
+#[cfg(feature = "compression")]
+pub(crate) struct Filled<L> {
+    pub(crate) level: LogLevel,
+    pub(crate) logs: Arc<Vec<LogWrapper<L>>>,
+    /// Dictionary of the level, interned from this chunk if there is none yet.
+    pub(crate) dictionary: Option<Arc<Dictionary>>,
+}
+
+impl<L> Chunk<L> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Open(logs) => logs.len(),
+            #[cfg(feature = "compression")]
+            Self::Sealed(sealed) => sealed.len(),
+        }
+    }
+
+    /// Times of the oldest and newest logs, `(i64::MAX, i64::MIN)` if there are none.
+    fn bounds(&self) -> (i64, i64) {
+        match self {
+            Self::Open(logs) => logs
+                .iter()
+                .fold((i64::MAX, i64::MIN), |(first, last), log| (first.min(log.time), last.max(log.time))),
+            #[cfg(feature = "compression")]
+            Self::Sealed(sealed) => sealed.bounds(),
+        }
+    }
+
+    /// Logs of an open chunk, or the decoded logs of a sealed one.
+    fn logs(&self) -> (&[LogWrapper<L>], Vec<LogWrapper<L>>) {
+        match self {
+            Self::Open(logs) => (logs, vec![]),
+            #[cfg(feature = "compression")]
+            Self::Sealed(sealed) => (&[], sealed.logs()),
+        }
+    }
+}
+
+impl<L: IsLog> Chunk<L> {
+    /// Bytes of the logs, as counted in the stats.
+    fn bytes(&self) -> u64 {
+        match self {
+            Self::Open(logs) => logs.iter().map(|log| log_bytes(&log.inner)).sum(),
+            #[cfg(feature = "compression")]
+            Self::Sealed(sealed) => sealed.log_bytes(),
+        }
+    }
+}
+
+impl<L> Default for LogChunks<L> {
+    fn default() -> Self {
+        Self {
+            chunks: vec![],
+            len: 0,
+            #[cfg(feature = "compression")]
+            dictionary: None,
+        }
+    }
+}
+
+impl<L> LogChunks<L> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default()
+    }
+}
+
+impl<L: Clone> LogChunks<L> {
+    /// Logs borrowed from the open chunks and decoded from the sealed ones.
+    pub fn iter(&self) -> impl Iterator<Item = Cow<'_, LogWrapper<L>>> {
+        self.chunks.iter().flat_map(|chunk| {
+            let (open, decoded) = chunk.logs();
+            open.iter().map(Cow::Borrowed).chain(decoded.into_iter().map(Cow::Owned))
+        })
+    }
+
+    /// Owned logs, cloned one at a time from the open chunks.
+    pub fn into_logs(self) -> impl Iterator<Item = LogWrapper<L>> {
+        self.chunks.into_iter().flat_map(|chunk| {
+            let (open, decoded) = match chunk {
+                Chunk::Open(logs) => (logs, vec![]),
+                #[cfg(feature = "compression")]
+                Chunk::Sealed(sealed) => (Arc::default(), sealed.logs()),
+            };
+
+            (0..open.len()).map(move |index| open[index].clone()).chain(decoded)
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<LogWrapper<L>> {
+        self.iter().map(Cow::into_owned).collect()
+    }
+
+    pub(crate) fn push(&mut self, log: LogWrapper<L>) {
+        match self.chunks.last_mut() {
+            Some(Chunk::Open(last)) if last.len() < CHUNK_SIZE => Arc::make_mut(last).push(log),
+            _ => self.chunks.push(Chunk::Open(Arc::new(vec![log]))),
+        }
+
+        self.len += 1
+    }
+
+}
+
+impl<L: IsLog> LogChunks<L> {
+    /// Drops the logs older than `time` and returns their bytes. Chunks entirely on one side of
+    /// `time` are kept or dropped whole from their time bounds, only the ones straddling it are
+    /// decoded and copied. What is left of a sealed chunk is kept open.
+    pub(crate) fn retain_since(&mut self, time: i64) -> u64 {
+        let mut dropped = 0;
+        let mut kept = Vec::with_capacity(self.chunks.len());
+
+        for chunk in std::mem::take(&mut self.chunks) {
+            let (first, last) = chunk.bounds();
+
+            if first >= time {
+                kept.push(chunk)
+            } else if last < time {
+                dropped += chunk.bytes()
+            } else {
+                let (open, decoded) = chunk.logs();
+                let mut retained = Vec::with_capacity(chunk.len());
+
+                for log in open.iter().chain(&decoded) {
+                    match log.time >= time {
+                        true => retained.push(log.clone()),
+                        false => dropped += log_bytes(&log.inner),
+                    }
+                }
+                kept.push(Chunk::Open(Arc::new(retained)))
+            }
+        }
+
+        self.len = kept.iter().map(Chunk::len).sum();
+        self.chunks = kept;
+        dropped
+    }
+}
+
+#[cfg(feature = "compression")]
+impl<L: IsLog> LogChunks<L> {
+    /// Seals the full chunks written since the last sealed one.
+    pub(crate) fn seal(&mut self, codec: &Codec<L>) {
+        for chunk in self.chunks.iter_mut().rev() {
+            let logs = match chunk {
+                Chunk::Sealed(_) => break,
+                Chunk::Open(logs) if logs.len() == CHUNK_SIZE => logs,
+                Chunk::Open(_) => continue,
+            };
+
+            let dictionary = self
+                .dictionary
+                .get_or_insert_with(|| Arc::new(Dictionary::intern(logs, codec.compression.dictionary_bytes)));
+
+            match codec.seal(logs, dictionary) {
+                Ok(sealed) => *chunk = Chunk::Sealed(Arc::new(sealed)),
+                Err(e) => eprintln!("cannot compress logs: {}", e),
+            }
+        }
+    }
+
+    /// Last chunk, of logs of `level`, if the last write filled it up.
+    fn filled(&self, level: &LogLevel) -> Option<Filled<L>> {
+        match self.chunks.last() {
+            Some(Chunk::Open(last)) if last.len() == CHUNK_SIZE => Some(Filled {
+                level: level.clone(),
+                logs: last.clone(),
+                dictionary: self.dictionary.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Swaps the open chunk `logs` for its sealed version, unless it was cleared or pruned since.
+    fn replace_sealed(&mut self, logs: &Arc<Vec<LogWrapper<L>>>, sealed: Sealed<L>) {
+        self.dictionary.get_or_insert_with(|| sealed.dictionary().clone());
+
+        let open = self
+            .chunks
+            .iter_mut()
+            .find(|chunk| matches!(chunk, Chunk::Open(open) if Arc::ptr_eq(open, logs)));
+        if let Some(chunk) = open {
+            *chunk = Chunk::Sealed(Arc::new(sealed))
+        }
+    }
+
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            stored_bytes: self.dictionary.as_ref().map_or(0, |dictionary| dictionary.len() as u64),
+            ..Default::default()
+        };
+
+        for chunk in &self.chunks {
+            match chunk {
+                Chunk::Open(logs) => {
+                    let bytes = logs.iter().map(MemoryUsage::open_log).sum();
+                    usage.add(MemoryUsage {
+                        logs: logs.len() as u64,
+                        sealed_logs: 0,
+                        raw_bytes: bytes,
+                        stored_bytes: bytes,
+                    })
+                }
+                Chunk::Sealed(sealed) => usage.add(sealed.memory_usage()),
+            }
+        }
+
+        usage
+    }
+}
+
+impl<L> From<Vec<LogWrapper<L>>> for LogChunks<L> {
+    fn from(logs: Vec<LogWrapper<L>>) -> Self {
+        let len = logs.len();
+        let mut logs = logs.into_iter();
+        let chunks = std::iter::from_fn(|| {
+            let chunk = logs.by_ref().take(CHUNK_SIZE).collect::<Vec<_>>();
+            (!chunk.is_empty()).then(|| Chunk::Open(Arc::new(chunk)))
+        });
+
+        Self {
+            chunks: chunks.collect(),
+            len,
+            ..Default::default()
+        }
+    }
+}
+
+impl<L: Clone + Serialize> Serialize for LogChunks<L> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for log in self.iter() {
+            seq.serialize_element(&*log)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, L: Deserialize<'de>> Deserialize<'de> for LogChunks<L> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// Summary of the logs stored for a user.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct UserStats {
+    pub user_id: i64,
+    /// Whether the user is logging, unknown for the Postgres logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logging: Option<bool>,
+    pub debug: u64,
+    pub warning: u64,
+    pub error: u64,
+    /// Message and payload bytes.
+    pub bytes: u64,
+    pub first_log: Option<i64>,
+    pub last_log: Option<i64>,
+}
+
+/// Parameters of a histogram query: the bucket width in seconds and the time range.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct HistogramQuery {
+    #[serde(default = "HistogramQuery::default_width")]
+    pub width: u64,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl HistogramQuery {
+    fn default_width() -> u64 {
+        60
+    }
+
+    /// Rejects zero and out of range widths, and time ranges ending before they start.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.width == 0 {
+            return Err("width must be positive");
+        }
+        if self.width > i64::MAX as u64 {
+            return Err("width must be at most 9223372036854775807");
+        }
+        if self.since.zip(self.until).is_some_and(|(since, until)| since > until) {
+            return Err("since must not be after until");
+        }
+
+        Ok(())
+    }
+
+    /// Start of the bucket containing `time`.
+    pub fn bucket(&self, time: i64) -> i64 {
+        time - time.rem_euclid(self.width as i64)
+    }
+}
+
+/// Logs per level in the bucket starting at `start`.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct HistogramBucket {
+    pub start: i64,
+    pub debug: u64,
+    pub warning: u64,
+    pub error: u64,
+}
+
+impl HistogramBucket {
+    pub fn add(&mut self, level: &LogLevel, count: u64) {
+        match level {
+            LogLevel::Debug => self.debug += count,
+            LogLevel::Warning => self.warning += count,
+            LogLevel::Error => self.error += count,
+        }
+    }
+}
+
+/// Counters kept up to date as logs are added and dropped.
+#[derive(Clone, Debug, Default)]
+struct GroupStats {
+    debug: u64,
+    warning: u64,
+    error: u64,
+    bytes: u64,
+    first_log: Option<i64>,
+    last_log: Option<i64>,
+    /// Logs per second and level, the finest histogram bucket.
+    per_second: BTreeMap<i64, HistogramBucket>,
+}
+
+impl GroupStats {
+    fn add<L: IsLog>(&mut self, kind: &LogLevel, log: &LogWrapper<L>) {
+        match kind {
+            LogLevel::Debug => self.debug += 1,
+            LogLevel::Warning => self.warning += 1,
+            LogLevel::Error => self.error += 1,
+        }
+
+        self.bytes += log_bytes(&log.inner);
+        self.per_second
+            .entry(log.time)
+            .or_insert_with(|| HistogramBucket {
+                start: log.time,
+                ..Default::default()
+            })
+            .add(kind, 1);
+        self.first_log = Some(self.first_log.map_or(log.time, |first| first.min(log.time)));
+        self.last_log = Some(self.last_log.map_or(log.time, |last| last.max(log.time)));
+    }
+
+    /// Takes out the logs older than `time`, which were `bytes` long, without going over them.
+    fn drop_before(&mut self, time: i64, bytes: u64) {
+        let kept = self.per_second.split_off(&time);
+
+        for second in std::mem::replace(&mut self.per_second, kept).into_values() {
+            self.debug -= second.debug;
+            self.warning -= second.warning;
+            self.error -= second.error;
+        }
+        self.bytes -= bytes;
+        self.first_log = self.per_second.keys().next().copied();
+        self.last_log = self.per_second.keys().next_back().copied();
+    }
 }
 
 // Note: UserLogsGroup container has already been locked at this point.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "L: Clone + Serialize", deserialize = "L: Deserialize<'de>"))]
 pub struct UserLogsGroup<L> {
     is_logging: bool,
-    error: Vec<LogWrapper<L>>,
-    warn: Vec<LogWrapper<L>>,
-    debug: Vec<LogWrapper<L>>,
+    error: LogChunks<L>,
+    warn: LogChunks<L>,
+    debug: LogChunks<L>,
+    // Not part of snapshots, rebuilt with `recompute_stats` when restoring.
+    #[serde(skip)]
+    stats: GroupStats,
 }
 
 impl<L: IsLog> UserLogsGroup<L> {
     pub fn new() -> Self {
         Self {
             is_logging: false,
-            error: vec![],
-            warn: vec![],
-            debug: vec![],
+            error: LogChunks::default(),
+            warn: LogChunks::default(),
+            debug: LogChunks::default(),
+            stats: GroupStats::default(),
         }
     }
 
@@ -75,49 +681,90 @@ impl<L: IsLog> UserLogsGroup<L> {
 
         self.error.clear();
         self.warn.clear();
-        self.debug.clear()
+        self.debug.clear();
+        self.stats = GroupStats::default()
     }
 
-    pub(crate) fn add_error(&mut self, log: L) {
-        if !self.is_logging {
-            return;
-        }
+    /// Drops the logs older than `time`.
+    pub(crate) fn retain_since(&mut self, time: i64) {
+        let bytes = self.error.retain_since(time) + self.warn.retain_since(time) + self.debug.retain_since(time);
+        self.stats.drop_before(time, bytes)
+    }
 
-        let time = std::time::SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-        let log = LogWrapper { time, inner: log };
+    pub(crate) fn recompute_stats(&mut self) {
+        let mut stats = GroupStats::default();
+
+        for (kind, logs) in [
+            (LogLevel::Error, &self.error),
+            (LogLevel::Warning, &self.warn),
+            (LogLevel::Debug, &self.debug),
+        ] {
+            for log in logs.iter() {
+                stats.add(&kind, &log)
+            }
+        }
 
-        self.error.push(log)
+        self.stats = stats
     }
 
-    pub(crate) fn add_warning(&mut self, log: L) {
-        if !self.is_logging {
-            return;
+    /// Non-empty buckets of `query`, oldest first.
+    pub fn histogram(&self, query: &HistogramQuery) -> Vec<HistogramBucket> {
+        let mut buckets: Vec<HistogramBucket> = Vec::new();
+        if query.validate().is_err() {
+            return buckets;
         }
 
-        let time = std::time::SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-        let log = LogWrapper { time, inner: log };
+        let seconds = self.stats.per_second.range(query.since.unwrap_or(i64::MIN)..query.until.unwrap_or(i64::MAX));
+
+        for (time, second) in seconds {
+            let start = query.bucket(*time);
+
+            if buckets.last().is_none_or(|bucket| bucket.start != start) {
+                buckets.push(HistogramBucket {
+                    start,
+                    ..Default::default()
+                });
+            }
+
+            let bucket = buckets.last_mut().unwrap();
+            bucket.debug += second.debug;
+            bucket.warning += second.warning;
+            bucket.error += second.error;
+        }
+
+        buckets
+    }
 
-        self.warn.push(log)
+    pub fn stats(&self, user_id: i64) -> UserStats {
+        UserStats {
+            user_id,
+            logging: Some(self.is_logging),
+            debug: self.stats.debug,
+            warning: self.stats.warning,
+            error: self.stats.error,
+            bytes: self.stats.bytes,
+            first_log: self.stats.first_log,
+            last_log: self.stats.last_log,
+        }
     }
 
-    pub(crate) fn add_debug(&mut self, log: L) {
+    /// Adds `log` received at `time` to the logs of its level. Returns whether it was stored.
+    /// Adds `log` at `kind`, usually its own level, if logging. Returns whether it was added.
+    pub(crate) fn add_at(&mut self, time: i64, kind: LogLevel, log: L) -> bool {
         if !self.is_logging {
-            return;
+            return false;
         }
 
-        let time = std::time::SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
         let log = LogWrapper { time, inner: log };
+        self.stats.add(&kind, &log);
+
+        match kind {
+            LogLevel::Error => self.error.push(log),
+            LogLevel::Warning => self.warn.push(log),
+            LogLevel::Debug => self.debug.push(log),
+        }
 
-        self.debug.push(log)
+        true
     }
 
     pub fn is_logging(&mut self) {
@@ -128,15 +775,154 @@ impl<L: IsLog> UserLogsGroup<L> {
         self.is_logging = false
     }
 
-    pub fn errors(&self) -> &Vec<LogWrapper<L>> {
+    pub fn logging(&self) -> bool {
+        self.is_logging
+    }
+
+    pub fn errors(&self) -> &LogChunks<L> {
         &self.error
     }
 
-    pub fn debug(&self) -> &Vec<LogWrapper<L>> {
+    pub fn debug(&self) -> &LogChunks<L> {
         &self.debug
     }
 
-    pub fn warning(&self) -> &Vec<LogWrapper<L>> {
+    pub fn warning(&self) -> &LogChunks<L> {
         &self.warn
     }
 }
+
+#[cfg(feature = "compression")]
+impl<L: IsLog> UserLogsGroup<L> {
+    pub(crate) fn seal(&mut self, codec: &Codec<L>) {
+        self.error.seal(codec);
+        self.warn.seal(codec);
+        self.debug.seal(codec);
+    }
+
+    /// Chunk of `level` filled up by the last write, to be sealed without holding the state lock.
+    pub(crate) fn filled(&self, level: &LogLevel) -> Option<Filled<L>> {
+        let chunks = match level {
+            LogLevel::Error => &self.error,
+            LogLevel::Warning => &self.warn,
+            LogLevel::Debug => &self.debug,
+        };
+        chunks.filled(level)
+    }
+
+    pub(crate) fn replace_sealed(&mut self, filled: &Filled<L>, sealed: Sealed<L>) {
+        let chunks = match filled.level {
+            LogLevel::Error => &mut self.error,
+            LogLevel::Warning => &mut self.warn,
+            LogLevel::Debug => &mut self.debug,
+        };
+
+        chunks.replace_sealed(&filled.logs, sealed)
+    }
+
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = self.error.memory_usage();
+        usage.add(self.warn.memory_usage());
+        usage.add(self.debug.memory_usage());
+        usage
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{
+        Chunk, FieldFilter, FieldValue, Fields, IsLog, LogChunks, LogLevel, LogSource, LogWrapper, CHUNK_SIZE,
+    };
+
+    impl IsLog for i64 {
+        fn message(&self) -> String {
+            self.to_string()
+        }
+
+        fn data(&self) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn level(&self) -> LogLevel {
+            LogLevel::Debug
+        }
+    }
+
+    fn logs(times: std::ops::Range<i64>) -> LogChunks<i64> {
+        times.map(|time| LogWrapper { time, inner: time }).collect::<Vec<_>>().into()
+    }
+
+    fn shared(a: &Chunk<i64>, b: &Chunk<i64>) -> bool {
+        matches!((a, b), (Chunk::Open(a), Chunk::Open(b)) if Arc::ptr_eq(a, b))
+    }
+
+    #[test]
+    fn chunks_are_shared_until_written() {
+        let mut chunks = logs(0..CHUNK_SIZE as i64 + 10);
+        assert_eq!((chunks.len(), chunks.chunks.len()), (CHUNK_SIZE + 10, 2));
+
+        let snapshot = chunks.clone();
+        chunks.push(LogWrapper { time: -1, inner: -1 });
+        assert!(shared(&chunks.chunks[0], &snapshot.chunks[0]));
+        assert!(!shared(&chunks.chunks[1], &snapshot.chunks[1]));
+        assert_eq!(snapshot.len(), CHUNK_SIZE + 10);
+        assert_eq!(snapshot.iter().map(|log| log.time).last(), Some(CHUNK_SIZE as i64 + 9));
+
+        // Pruning keeps or drops whole chunks from their bounds, only copying the ones it cuts.
+        let before = chunks.clone();
+        assert_eq!(chunks.retain_since(0), "-1".len() as u64);
+        assert_eq!(chunks.len(), CHUNK_SIZE + 10);
+        assert!(shared(&chunks.chunks[0], &before.chunks[0]));
+        assert!(!shared(&chunks.chunks[1], &before.chunks[1]));
+
+        let digits = (0..CHUNK_SIZE).map(|time| time.to_string().len() as u64).sum::<u64>();
+        assert_eq!(chunks.retain_since(CHUNK_SIZE as i64), digits);
+        assert_eq!((chunks.len(), chunks.chunks.len()), (10, 1));
+
+        // Serialized like a plain vector, as in snapshots.
+        assert_eq!(
+            bincode::serialize(&snapshot).unwrap(),
+            bincode::serialize(&snapshot.to_vec()).unwrap()
+        );
+        let decoded: LogChunks<i64> = bincode::deserialize(&bincode::serialize(&snapshot).unwrap()).unwrap();
+        assert!(decoded.into_logs().map(|log| log.time).eq(0..CHUNK_SIZE as i64 + 10));
+    }
+
+    #[test]
+    fn field_filter_matches_textual_values() {
+        let mut fields = Fields::new();
+        fields.insert("contract_id".into(), FieldValue::String("CA123".into()));
+        fields.insert("ledger".into(), FieldValue::Integer(10));
+        fields.insert("failed".into(), FieldValue::Bool(false));
+
+        let filter: FieldFilter =
+            serde_json::from_str(r#"{"ledger": "10", "failed": "false"}"#).unwrap();
+        assert!(filter.matches(&fields));
+
+        let filter: FieldFilter = serde_json::from_str(r#"{"contract_id": "CB456"}"#).unwrap();
+        assert!(!filter.matches(&fields));
+
+        let filter: FieldFilter = serde_json::from_str(r#"{"tx_hash": "ab"}"#).unwrap();
+        assert!(!filter.matches(&fields));
+
+        let filter: FieldFilter = serde_json::from_str(r#"{"ledger": "10", "format": "csv"}"#).unwrap();
+        assert!(filter.matches(&fields));
+    }
+
+    #[test]
+    fn field_filter_matches_source() {
+        let source = LogSource {
+            component: Some("ingest".into()),
+            line: Some(42),
+            ..Default::default()
+        };
+
+        let filter: FieldFilter =
+            serde_json::from_str(r#"{"source.component": "ingest", "source.line": "42"}"#).unwrap();
+        assert!(filter.matches(&Fields::new()));
+        assert!(filter.matches_source(Some(&source)));
+        assert!(!filter.matches_source(None));
+    }
+}
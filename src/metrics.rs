@@ -0,0 +1,253 @@
+//! Prometheus metrics of the services.
+//!
+//! A [`Metrics`] registry is shared by the loggers and the HTTP layer and rendered in the
+//! Prometheus text exposition format by the `GET /metrics` admin route. Gauges that are cheaper to
+//! compute on scrape (active users, logs stored) are passed to [`Metrics::render`].
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use warp::{http::StatusCode, log::Info};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Series of a metric by label values.
+#[derive(Debug)]
+struct Family<M> {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    series: Mutex<BTreeMap<Vec<String>, M>>,
+}
+
+impl<M: Default> Family<M> {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            series: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn with(&self, values: &[&str], f: impl FnOnce(&mut M)) {
+        let values = values.iter().map(|value| value.to_string()).collect();
+        f(self.series.lock().unwrap().entry(values).or_default())
+    }
+
+    fn label_set(&self, values: &[String], extra: Option<(&str, String)>) -> String {
+        let labels = self
+            .labels
+            .iter()
+            .zip(values)
+            .map(|(label, value)| (*label, escape(value)))
+            .chain(extra)
+            .map(|(label, value)| format!("{}=\"{}\"", label, value))
+            .collect::<Vec<_>>();
+
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+}
+
+impl Family<u64> {
+    fn inc(&self, values: &[&str], by: u64) {
+        self.with(values, |counter| *counter += by)
+    }
+
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}\n# TYPE {} counter", self.name, self.help, self.name).unwrap();
+
+        for (values, counter) in self.series.lock().unwrap().iter() {
+            writeln!(out, "{}{} {}", self.name, self.label_set(values, None), counter).unwrap();
+        }
+    }
+}
+
+impl Family<Histogram> {
+    fn observe(&self, values: &[&str], elapsed: Duration) {
+        self.with(values, |histogram| histogram.observe(elapsed.as_secs_f64()))
+    }
+
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}\n# TYPE {} histogram", self.name, self.help, self.name).unwrap();
+
+        for (values, histogram) in self.series.lock().unwrap().iter() {
+            let buckets = histogram
+                .buckets
+                .iter()
+                .zip(LATENCY_BUCKETS)
+                .map(|(count, bound)| (bound.to_string(), *count))
+                .chain([("+Inf".to_string(), histogram.count)]);
+
+            for (bound, count) in buckets {
+                let labels = self.label_set(values, Some(("le", bound)));
+                writeln!(out, "{}_bucket{} {}", self.name, labels, count).unwrap();
+            }
+
+            let labels = self.label_set(values, None);
+            writeln!(out, "{}_sum{} {}", self.name, labels, histogram.sum).unwrap();
+            writeln!(out, "{}_count{} {}", self.name, labels, histogram.count).unwrap();
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Gauges computed when scraping.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoredGauges {
+    pub active_users: u64,
+    pub logs: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    http_requests: Family<u64>,
+    http_duration: Family<Histogram>,
+    logs_written: Family<u64>,
+    bytes_written: Family<u64>,
+    write_duration: Family<Histogram>,
+    rejected: Family<u64>,
+    postgres_duration: Family<Histogram>,
+    postgres_errors: Family<u64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            http_requests: Family::new("zephyr_http_requests_total", "HTTP requests by route, method and status.", &["route", "method", "status"]),
+            http_duration: Family::new("zephyr_http_request_duration_seconds", "HTTP request latency by route.", &["route"]),
+            logs_written: Family::new("zephyr_logs_written_total", "Logs accepted by level.", &["level"]),
+            bytes_written: Family::new("zephyr_log_bytes_written_total", "Message and payload bytes accepted.", &[]),
+            write_duration: Family::new("zephyr_log_write_duration_seconds", "Latency of writing a log to the logger.", &["backend"]),
+            rejected: Family::new("zephyr_logs_rejected_total", "Logs rejected before being written, by reason.", &["reason"]),
+            postgres_duration: Family::new("zephyr_postgres_query_duration_seconds", "Latency of Postgres queries.", &["query"]),
+            postgres_errors: Family::new("zephyr_postgres_errors_total", "Failed Postgres queries.", &["query"]),
+        }
+    }
+
+    /// Records a log of `level` and `bytes` written to `backend` since `started`.
+    pub fn log_written(&self, backend: &str, level: &str, bytes: u64, started: Instant) {
+        self.logs_written.inc(&[level], 1);
+        self.bytes_written.inc(&[], bytes);
+        self.write_duration.observe(&[backend], started.elapsed());
+    }
+
+    pub fn log_rejected(&self, reason: &str) {
+        self.rejected.inc(&[reason], 1)
+    }
+
+    pub fn postgres_query(&self, query: &str, started: Instant) {
+        self.postgres_duration.observe(&[query], started.elapsed())
+    }
+
+    pub fn postgres_failed(&self, query: &str) {
+        self.postgres_errors.inc(&[query], 1)
+    }
+
+    /// Records a served request. Requests that matched no route are grouped together so that
+    /// arbitrary paths don't create series.
+    pub fn http_request(&self, info: Info<'_>) {
+        let route = match info.status() {
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => "unmatched",
+            _ => info.path().trim_start_matches('/').split('/').next().unwrap_or_default(),
+        };
+
+        self.http_requests
+            .inc(&[route, info.method().as_str(), info.status().as_str()], 1);
+        self.http_duration.observe(&[route], info.elapsed());
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn render(&self, stored: &StoredGauges) -> String {
+        let mut out = String::new();
+
+        self.http_requests.render(&mut out);
+        self.http_duration.render(&mut out);
+        self.logs_written.render(&mut out);
+        self.bytes_written.render(&mut out);
+        self.write_duration.render(&mut out);
+        self.rejected.render(&mut out);
+        self.postgres_duration.render(&mut out);
+        self.postgres_errors.render(&mut out);
+
+        for (name, help, value) in [
+            ("zephyr_active_users", "Users logging to memory, or with logs in Postgres.", stored.active_users),
+            ("zephyr_logs_stored", "Logs currently stored.", stored.logs),
+            ("zephyr_log_bytes_stored", "Message and payload bytes currently stored.", stored.bytes),
+        ] {
+            writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value).unwrap();
+        }
+
+        out
+    }
+}
+
+/// Content type of [`Metrics::render`].
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use super::{Metrics, StoredGauges};
+
+    #[test]
+    fn renders_text_format() {
+        let metrics = Metrics::new();
+        metrics.log_written("memory", "error", 10, Instant::now());
+        metrics.log_written("memory", "error", 5, Instant::now());
+        metrics.log_rejected("rate_limited");
+        metrics.postgres_failed("insert");
+
+        let rendered = metrics.render(&StoredGauges {
+            active_users: 2,
+            ..Default::default()
+        });
+
+        assert!(rendered.contains("zephyr_logs_written_total{level=\"error\"} 2\n"));
+        assert!(rendered.contains("zephyr_log_bytes_written_total 15\n"));
+        assert!(rendered.contains("zephyr_log_write_duration_seconds_bucket{backend=\"memory\",le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("zephyr_logs_rejected_total{reason=\"rate_limited\"} 1\n"));
+        assert!(rendered.contains("zephyr_postgres_errors_total{query=\"insert\"} 1\n"));
+        assert!(rendered.contains("# TYPE zephyr_active_users gauge\nzephyr_active_users 2\n"));
+    }
+}
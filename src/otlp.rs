@@ -0,0 +1,561 @@
+//! OpenTelemetry logs ingestion (OTLP/HTTP).
+//!
+//! `POST /v1/logs` takes an `ExportLogsServiceRequest` encoded as protobuf
+//! (`application/x-protobuf`) or OTLP/JSON (`application/json`), and answers in the same encoding.
+//! Only the messages and fields of the logs signal that are mapped into the service's logs are
+//! declared below; prost skips the others while decoding.
+//!
+//! Every record belongs to the user in the user id attribute of its resource, records of resources
+//! without one are rejected and reported in the response's partial success.
+
+use std::{fmt::Display, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use prost::Message;
+use serde::{Deserialize, Deserializer, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{Reply, Response},
+};
+
+use crate::{FieldValue, Fields, LogLevel, LogSource, TraceContext};
+
+/// Resource attribute holding the user id unless configured otherwise.
+pub const DEFAULT_USER_ID_ATTRIBUTE: &str = "zephyr.user_id";
+
+/// Request and response encodings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Protobuf,
+    Json,
+}
+
+impl Format {
+    /// Encoding of a request with this `Content-Type`, `None` if unsupported.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "application/x-protobuf" => Some(Self::Protobuf),
+            "application/json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Protobuf => "application/x-protobuf",
+            Self::Json => "application/json",
+        }
+    }
+
+    pub fn decode(&self, body: &[u8]) -> Result<ExportLogsServiceRequest, String> {
+        match self {
+            Self::Protobuf => ExportLogsServiceRequest::decode(body).map_err(|error| error.to_string()),
+            Self::Json => serde_json::from_slice(body).map_err(|error| error.to_string()),
+        }
+    }
+
+    fn reply<T: Message + Serialize>(&self, status: StatusCode, message: &T) -> Response {
+        let body = match self {
+            Self::Protobuf => message.encode_to_vec(),
+            Self::Json => serde_json::to_vec(message).unwrap(),
+        };
+
+        let mut response = warp::reply::with_status(body, status).into_response();
+        response
+            .headers_mut()
+            .insert("content-type", self.content_type().parse().unwrap());
+        response
+    }
+
+    /// Success response, reporting the `rejected` records if any.
+    pub fn accepted(&self, rejected: i64, error_message: String) -> Response {
+        let partial_success = (rejected > 0).then_some(ExportLogsPartialSuccess {
+            rejected_log_records: rejected,
+            error_message,
+        });
+
+        self.reply(StatusCode::OK, &ExportLogsServiceResponse { partial_success })
+    }
+
+    /// Failure response with a `google.rpc.Status` body.
+    pub fn error(&self, status: StatusCode, message: String) -> Response {
+        // INVALID_ARGUMENT, PERMISSION_DENIED, UNAVAILABLE and UNKNOWN.
+        let code = match status {
+            StatusCode::BAD_REQUEST => 3,
+            StatusCode::FORBIDDEN => 7,
+            StatusCode::SERVICE_UNAVAILABLE => 14,
+            _ => 2,
+        };
+
+        self.reply(status, &Status { code, message })
+    }
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ExportLogsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_logs: Vec<ResourceLogs>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ResourceLogs {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_logs: Vec<ScopeLogs>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ScopeLogs {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub log_records: Vec<LogRecord>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LogRecord {
+    #[prost(fixed64, tag = "1")]
+    #[serde(deserialize_with = "number")]
+    pub time_unix_nano: u64,
+    /// `SeverityNumber`, 0 if unspecified.
+    #[prost(int32, tag = "2")]
+    pub severity_number: i32,
+    #[prost(string, tag = "3")]
+    pub severity_text: String,
+    #[prost(message, optional, tag = "5")]
+    pub body: Option<AnyValue>,
+    #[prost(message, repeated, tag = "6")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(bytes = "vec", tag = "9")]
+    #[serde(deserialize_with = "hex_bytes")]
+    pub trace_id: Vec<u8>,
+    #[prost(bytes = "vec", tag = "10")]
+    #[serde(deserialize_with = "hex_bytes")]
+    pub span_id: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+pub struct AnyValue {
+    #[prost(oneof = "Value", tags = "1, 2, 3, 4, 5, 6, 7")]
+    #[serde(flatten)]
+    pub value: Option<Value>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Value {
+    #[prost(string, tag = "1")]
+    StringValue(String),
+    #[prost(bool, tag = "2")]
+    BoolValue(bool),
+    #[prost(int64, tag = "3")]
+    #[serde(deserialize_with = "number")]
+    IntValue(i64),
+    #[prost(double, tag = "4")]
+    DoubleValue(f64),
+    #[prost(message, tag = "5")]
+    ArrayValue(ArrayValue),
+    #[prost(message, tag = "6")]
+    KvlistValue(KeyValueList),
+    #[prost(bytes = "vec", tag = "7")]
+    #[serde(deserialize_with = "base64_bytes")]
+    BytesValue(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default)]
+pub struct ArrayValue {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default)]
+pub struct KeyValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportLogsServiceResponse {
+    #[prost(message, optional, tag = "1")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial_success: Option<ExportLogsPartialSuccess>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportLogsPartialSuccess {
+    #[prost(int64, tag = "1")]
+    pub rejected_log_records: i64,
+    #[prost(string, tag = "2")]
+    pub error_message: String,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct Status {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+/// OTLP/JSON 64-bit integers, which may be strings.
+fn number<'de, D: Deserializer<'de>, T: FromStr + Deserialize<'de>>(deserializer: D) -> Result<T, D::Error>
+where
+    T::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number<T> {
+        Number(T),
+        String(String),
+    }
+
+    match Number::deserialize(deserializer)? {
+        Number::Number(number) => Ok(number),
+        Number::String(number) => number.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// OTLP/JSON trace and span ids, which are hex instead of base64.
+fn hex_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    hex::decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn base64_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    STANDARD
+        .decode(String::deserialize(deserializer)?)
+        .map_err(serde::de::Error::custom)
+}
+
+impl AnyValue {
+    fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            Some(Value::StringValue(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match &self.value {
+            Some(Value::IntValue(value)) => Some(*value),
+            Some(Value::StringValue(value)) => value.parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match &self.value {
+            Some(Value::StringValue(value)) => value.clone().into(),
+            Some(Value::BoolValue(value)) => (*value).into(),
+            Some(Value::IntValue(value)) => (*value).into(),
+            Some(Value::DoubleValue(value)) => (*value).into(),
+            Some(Value::ArrayValue(array)) => array.values.iter().map(AnyValue::to_json).collect(),
+            Some(Value::KvlistValue(list)) => list
+                .values
+                .iter()
+                .map(|pair| (pair.key.clone(), pair.value.as_ref().map(AnyValue::to_json).unwrap_or_default()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            Some(Value::BytesValue(bytes)) => STANDARD.encode(bytes).into(),
+            None => serde_json::Value::Null,
+        }
+    }
+
+    /// Text of a log body: strings as they are, other values as JSON.
+    fn to_text(&self) -> String {
+        match &self.value {
+            Some(Value::StringValue(value)) => value.clone(),
+            _ => self.to_json().to_string(),
+        }
+    }
+
+    /// Field value, arrays and maps as their JSON text and bytes as base64.
+    fn to_field(&self) -> Option<FieldValue> {
+        Some(match self.value.as_ref()? {
+            Value::StringValue(value) => FieldValue::String(value.clone()),
+            Value::BoolValue(value) => FieldValue::Bool(*value),
+            Value::IntValue(value) => FieldValue::Integer(*value),
+            Value::DoubleValue(value) => FieldValue::Float(*value),
+            Value::BytesValue(bytes) => FieldValue::String(STANDARD.encode(bytes)),
+            Value::ArrayValue(_) | Value::KvlistValue(_) => FieldValue::String(self.to_json().to_string()),
+        })
+    }
+}
+
+fn attribute<'a>(attributes: &'a [KeyValue], keys: &[&str]) -> Option<&'a AnyValue> {
+    keys.iter()
+        .find_map(|key| attributes.iter().find(|pair| pair.key == *key))
+        .and_then(|pair| pair.value.as_ref())
+}
+
+/// Fatal and error severities are errors, warnings are warnings, the rest debug logs. Records
+/// without a severity number fall back on their severity text.
+pub fn level(severity_number: i32, severity_text: &str) -> LogLevel {
+    match severity_number {
+        17.. => LogLevel::Error,
+        13..=16 => LogLevel::Warning,
+        1..=12 => LogLevel::Debug,
+        _ => {
+            let text = severity_text.to_ascii_uppercase();
+            if ["FATAL", "ERROR", "CRIT", "ALERT", "EMERG"].iter().any(|prefix| text.starts_with(prefix)) {
+                LogLevel::Error
+            } else if text.starts_with("WARN") {
+                LogLevel::Warning
+            } else {
+                LogLevel::Debug
+            }
+        }
+    }
+}
+
+/// Lowercase hex of a trace or span id, `None` if missing or invalid (all zeros).
+fn id(bytes: &[u8]) -> Option<String> {
+    bytes.iter().any(|byte| *byte != 0).then(|| hex::encode(bytes))
+}
+
+/// Log record mapped into the service's log model.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub user_id: i64,
+    pub level: LogLevel,
+    pub message: String,
+    /// Record attributes, except the `code.*` ones of the source.
+    pub fields: Fields,
+    /// `service.name`, `host.name` and `process.pid` of the resource, the instrumentation scope
+    /// as target and the `code.*` attributes of the record.
+    pub source: LogSource,
+    pub trace: Option<TraceContext>,
+}
+
+/// Records of `request` with the user of their resource, and the number of records without one.
+pub fn records(request: ExportLogsServiceRequest, user_id_attribute: &str) -> (Vec<Record>, i64) {
+    const FILE: [&str; 2] = ["code.file.path", "code.filepath"];
+    const LINE: [&str; 2] = ["code.line.number", "code.lineno"];
+
+    let mut records = Vec::new();
+    let mut unassigned = 0;
+
+    for resource_logs in request.resource_logs {
+        let resource = resource_logs.resource.unwrap_or_default().attributes;
+        let count = || resource_logs.scope_logs.iter().map(|scope| scope.log_records.len() as i64).sum::<i64>();
+
+        let Some(user_id) = attribute(&resource, &[user_id_attribute]).and_then(AnyValue::as_int) else {
+            unassigned += count();
+            continue;
+        };
+
+        let text = |keys: &[&str]| attribute(&resource, keys).and_then(AnyValue::as_str).map(str::to_string);
+        let component = text(&["service.name"]);
+        let hostname = text(&["host.name"]);
+        let pid = attribute(&resource, &["process.pid"])
+            .and_then(AnyValue::as_int)
+            .and_then(|pid| pid.try_into().ok());
+
+        for scope_logs in resource_logs.scope_logs {
+            let target = scope_logs.scope.map(|scope| scope.name).filter(|name| !name.is_empty());
+
+            for record in scope_logs.log_records {
+                let trace_id = id(&record.trace_id);
+                let span_id = id(&record.span_id);
+                let trace = (trace_id.is_some() || span_id.is_some()).then_some(TraceContext {
+                    trace_id,
+                    span_id,
+                    request_id: None,
+                });
+
+                let source = LogSource {
+                    target: target.clone(),
+                    file: attribute(&record.attributes, &FILE)
+                        .and_then(AnyValue::as_str)
+                        .map(str::to_string),
+                    line: attribute(&record.attributes, &LINE)
+                        .and_then(AnyValue::as_int)
+                        .and_then(|line| line.try_into().ok()),
+                    hostname: hostname.clone(),
+                    pid,
+                    component: component.clone(),
+                };
+
+                let fields = record
+                    .attributes
+                    .iter()
+                    .filter(|pair| !FILE.contains(&pair.key.as_str()) && !LINE.contains(&pair.key.as_str()))
+                    .filter_map(|pair| Some((pair.key.clone(), pair.value.as_ref()?.to_field()?)))
+                    .collect();
+
+                records.push(Record {
+                    user_id,
+                    level: level(record.severity_number, &record.severity_text),
+                    message: record.body.as_ref().map(AnyValue::to_text).unwrap_or_default(),
+                    fields,
+                    source,
+                    trace,
+                });
+            }
+        }
+    }
+
+    (records, unassigned)
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+
+    use super::{
+        level, records, AnyValue, ExportLogsServiceRequest, Format, KeyValue, LogRecord, Resource, ResourceLogs,
+        ScopeLogs, Value, DEFAULT_USER_ID_ATTRIBUTE,
+    };
+    use crate::{FieldValue, LogLevel};
+
+    fn string(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.into(),
+            value: Some(AnyValue {
+                value: Some(Value::StringValue(value.into())),
+            }),
+        }
+    }
+
+    #[test]
+    fn maps_severities() {
+        assert_eq!(level(21, ""), LogLevel::Error);
+        assert_eq!(level(17, "INFO"), LogLevel::Error);
+        assert_eq!(level(13, ""), LogLevel::Warning);
+        assert_eq!(level(9, "ERROR"), LogLevel::Debug);
+        assert_eq!(level(0, "error"), LogLevel::Error);
+        assert_eq!(level(0, "Warning"), LogLevel::Warning);
+        assert_eq!(level(0, ""), LogLevel::Debug);
+    }
+
+    #[test]
+    fn decodes_protobuf() {
+        let request = ExportLogsServiceRequest {
+            resource_logs: vec![
+                ResourceLogs {
+                    resource: Some(Resource {
+                        attributes: vec![string(DEFAULT_USER_ID_ATTRIBUTE, "5"), string("service.name", "billing")],
+                    }),
+                    scope_logs: vec![ScopeLogs {
+                        scope: None,
+                        log_records: vec![LogRecord {
+                            severity_number: 17,
+                            body: Some(AnyValue {
+                                value: Some(Value::StringValue("payment failed".into())),
+                            }),
+                            attributes: vec![string("order", "A-1"), string("code.filepath", "pay.rs")],
+                            trace_id: vec![0xab; 16],
+                            ..Default::default()
+                        }],
+                    }],
+                },
+                ResourceLogs {
+                    resource: None,
+                    scope_logs: vec![ScopeLogs {
+                        scope: None,
+                        log_records: vec![LogRecord::default(); 2],
+                    }],
+                },
+            ],
+        };
+
+        let decoded = Format::Protobuf.decode(&request.encode_to_vec()).unwrap();
+        let (records, unassigned) = records(decoded, DEFAULT_USER_ID_ATTRIBUTE);
+
+        assert_eq!(unassigned, 2);
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].user_id, &records[0].level), (5, &LogLevel::Error));
+        assert_eq!(records[0].message, "payment failed");
+        assert_eq!(records[0].fields.keys().collect::<Vec<_>>(), ["order"]);
+        assert_eq!(records[0].source.component.as_deref(), Some("billing"));
+        assert_eq!(records[0].source.file.as_deref(), Some("pay.rs"));
+        assert_eq!(records[0].trace.as_ref().unwrap().trace_id, Some("ab".repeat(16)));
+        assert_eq!(records[0].trace.as_ref().unwrap().span_id, None);
+
+        assert!(Format::Protobuf.decode(b"\x0a\xff").is_err());
+    }
+
+    #[test]
+    fn decodes_json() {
+        let body = r#"{
+            "resourceLogs": [{
+                "resource": {"attributes": [
+                    {"key": "zephyr.user_id", "value": {"intValue": "7"}},
+                    {"key": "host.name", "value": {"stringValue": "web-1"}},
+                    {"key": "process.pid", "value": {"intValue": 42}}
+                ]},
+                "scopeLogs": [{
+                    "scope": {"name": "checkout", "version": "1.0"},
+                    "logRecords": [{
+                        "timeUnixNano": "1544712660300000000",
+                        "severityText": "WARN",
+                        "traceId": "5b8efff798038103d269b633813fc60c",
+                        "spanId": "eee19b7ec3c1b174",
+                        "body": {"kvlistValue": {"values": [{"key": "cart", "value": {"arrayValue": {"values": [{"intValue": "1"}]}}}]}},
+                        "attributes": [
+                            {"key": "retries", "value": {"intValue": "3"}},
+                            {"key": "ratio", "value": {"doubleValue": 0.5}},
+                            {"key": "cached", "value": {"boolValue": true}},
+                            {"key": "raw", "value": {"bytesValue": "AAE="}}
+                        ]
+                    }]
+                }]
+            }]
+        }"#;
+
+        let request = Format::Json.decode(body.as_bytes()).unwrap();
+        assert_eq!(request.resource_logs[0].scope_logs[0].log_records[0].time_unix_nano, 1544712660300000000);
+
+        let (records, unassigned) = records(request, DEFAULT_USER_ID_ATTRIBUTE);
+        let record = &records[0];
+
+        assert_eq!((record.user_id, unassigned), (7, 0));
+        assert_eq!(record.level, LogLevel::Warning);
+        assert_eq!(record.message, r#"{"cart":[1]}"#);
+        assert_eq!(record.fields["retries"], FieldValue::Integer(3));
+        assert_eq!(record.fields["ratio"], FieldValue::Float(0.5));
+        assert_eq!(record.fields["cached"], FieldValue::Bool(true));
+        assert_eq!(record.fields["raw"], FieldValue::String("AAE=".into()));
+        assert_eq!(record.source.target.as_deref(), Some("checkout"));
+        assert_eq!((record.source.hostname.as_deref(), record.source.pid), (Some("web-1"), Some(42)));
+        assert_eq!(
+            record.trace.as_ref().unwrap().span_id.as_deref(),
+            Some("eee19b7ec3c1b174")
+        );
+
+        assert!(Format::Json.decode(br#"{"resourceLogs": [{"scopeLogs": [{"logRecords": [{"traceId": "zz"}]}]}]}"#).is_err());
+        assert_eq!(Format::from_content_type("application/json; charset=utf-8"), Some(Format::Json));
+        assert_eq!(Format::from_content_type("text/plain"), None);
+    }
+}
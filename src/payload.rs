@@ -0,0 +1,182 @@
+//! Decoding of the opaque `data` payload carried by logs.
+//!
+//! A log can declare the [`ContentType`] of its payload, and read endpoints render it through
+//! a [`PayloadCodecs`] registry. Payloads without a (known) content type are rendered as base64.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Declared encoding of a log's `data` payload.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum ContentType {
+    /// UTF-8 encoded JSON document.
+    Json,
+    /// Bincode encoding of the type registered under the given name.
+    Bincode(String),
+    /// UTF-8 text.
+    Text,
+    /// Arbitrary bytes, rendered as hex.
+    Hex,
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Bincode(name) => write!(f, "bincode:{}", name),
+            Self::Text => write!(f, "text"),
+            Self::Hex => write!(f, "hex"),
+        }
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "text" => Ok(Self::Text),
+            "hex" => Ok(Self::Hex),
+            _ => match s.strip_prefix("bincode:") {
+                Some(name) => Ok(Self::Bincode(name.to_string())),
+                None => Err(format!("unknown content type {}", s)),
+            },
+        }
+    }
+}
+
+/// Payload as returned by the read endpoints.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "encoding", content = "value", rename_all = "snake_case")]
+pub enum RenderedPayload {
+    Json(serde_json::Value),
+    Text(String),
+    Hex(String),
+    Base64(String),
+}
+
+type Decoder = Box<dyn Fn(&[u8]) -> Option<serde_json::Value> + Send + Sync>;
+
+/// Registry of the bincode schemas known to the service.
+#[derive(Default)]
+pub struct PayloadCodecs {
+    decoders: HashMap<String, Decoder>,
+}
+
+impl fmt::Debug for PayloadCodecs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.decoders.keys()).finish()
+    }
+}
+
+impl PayloadCodecs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `T` as the schema of payloads declaring `ContentType::Bincode(name)`.
+    pub fn register<T: DeserializeOwned + Serialize + 'static>(&mut self, name: impl ToString) {
+        let decoder = |bytes: &[u8]| {
+            let decoded: T = bincode::deserialize(bytes).ok()?;
+            serde_json::to_value(decoded).ok()
+        };
+
+        self.decoders.insert(name.to_string(), Box::new(decoder));
+    }
+
+    pub fn render(&self, content_type: Option<&ContentType>, data: &[u8]) -> RenderedPayload {
+        let rendered = match content_type {
+            Some(ContentType::Json) => serde_json::from_slice(data).ok().map(RenderedPayload::Json),
+            Some(ContentType::Text) => std::str::from_utf8(data)
+                .ok()
+                .map(|text| RenderedPayload::Text(text.to_string())),
+            Some(ContentType::Hex) => Some(RenderedPayload::Hex(hex::encode(data))),
+            Some(ContentType::Bincode(name)) => self
+                .decoders
+                .get(name)
+                .and_then(|decode| decode(data))
+                .map(RenderedPayload::Json),
+            None => None,
+        };
+
+        rendered.unwrap_or_else(|| RenderedPayload::Base64(STANDARD.encode(data)))
+    }
+}
+
+/// Serde helper for `Option<Vec<u8>>` payloads: base64 strings in human-readable formats
+/// (JSON), plain bytes otherwise (bincode). Byte arrays are still accepted when deserializing
+/// JSON for older clients.
+pub mod base64_data {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Base64(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(data: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            data.as_ref()
+                .map(|bytes| STANDARD.encode(bytes))
+                .serialize(serializer)
+        } else {
+            data.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Option::<Vec<u8>>::deserialize(deserializer);
+        }
+
+        match Option::<Repr>::deserialize(deserializer)? {
+            Some(Repr::Base64(encoded)) => STANDARD.decode(encoded).map(Some).map_err(D::Error::custom),
+            Some(Repr::Bytes(bytes)) => Ok(Some(bytes)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use super::{ContentType, PayloadCodecs, RenderedPayload};
+
+    #[derive(Serialize, Deserialize)]
+    struct Transfer {
+        from: String,
+        amount: i64,
+    }
+
+    #[test]
+    fn render_registered_bincode_and_fallback() {
+        let mut codecs = PayloadCodecs::new();
+        codecs.register::<Transfer>("transfer");
+
+        let data = bincode::serialize(&Transfer {
+            from: "GABC".into(),
+            amount: 10,
+        })
+        .unwrap();
+
+        assert_eq!(
+            codecs.render(Some(&ContentType::Bincode("transfer".into())), &data),
+            RenderedPayload::Json(serde_json::json!({"from": "GABC", "amount": 10}))
+        );
+        assert_eq!(
+            codecs.render(Some(&ContentType::Json), b"not json"),
+            RenderedPayload::Base64("bm90IGpzb24=".into())
+        );
+        assert_eq!(
+            codecs.render(Some(&ContentType::Hex), &[0xde, 0xad]),
+            RenderedPayload::Hex("dead".into())
+        );
+    }
+}
@@ -1,34 +1,430 @@
 //! Simple structs to send logs to the service.
 
-use reqwest::Client;
+use std::{collections::VecDeque, future::Future, io, net::SocketAddr, panic::Location, path::Path};
 
-use crate::{logs::LogWrapper, LogLevel, MercuryLog};
+use reqwest::{
+    header::{CONTENT_ENCODING, CONTENT_TYPE},
+    Client,
+};
+use tokio::{
+    io::{BufReader, BufWriter},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+};
+
+use crate::{
+    encoding::{self, Encoding},
+    tcp::{self, ClientFrame, ServerFrame},
+    Fields, LogLevel, LogSource, MercuryLog, ServiceLog, TraceContext,
+};
+
+tokio::task_local! {
+    static TRACE: TraceContext;
+}
+
+/// Runs `future` with `trace` as the trace context of every log it sends.
+pub async fn in_trace<F: Future>(trace: TraceContext, future: F) -> F::Output {
+    TRACE.scope(trace, future).await
+}
+
+/// Trace context of the running task: the ids set with [`in_trace`], with the current `tracing`
+/// span as span id when none was set explicitly.
+fn current_trace() -> Option<TraceContext> {
+    let trace = TRACE.try_with(Clone::clone).unwrap_or_default();
+    let trace = TraceContext {
+        span_id: trace.span_id.or_else(current_span_id),
+        ..trace
+    };
+
+    (trace != TraceContext::default()).then_some(trace)
+}
+
+#[cfg(feature = "tracing")]
+fn current_span_id() -> Option<String> {
+    tracing::Span::current()
+        .id()
+        .map(|id| format!("{:016x}", id.into_u64()))
+}
+
+#[cfg(not(feature = "tracing"))]
+fn current_span_id() -> Option<String> {
+    None
+}
+
+/// Source metadata for the current module, file and line, to pass to
+/// [`LoggingClient::send_log_with_source`].
+#[macro_export]
+macro_rules! log_source {
+    () => {
+        $crate::LogSource {
+            target: Some(module_path!().to_string()),
+            file: Some(file!().to_string()),
+            line: Some(line!()),
+            ..Default::default()
+        }
+    };
+}
+
+/// Sends a log through a [`LoggingClient`] or [`TcpLoggingClient`] with
+/// [`log_source!`](crate::log_source) as its source, so that unlike `send_log` it records the
+/// calling module as well as the file and line. Fields may be passed as a fifth argument.
+#[macro_export]
+macro_rules! send_log {
+    ($client:expr, $user_id:expr, $level:expr, $message:expr $(,)?) => {
+        $crate::send_log!($client, $user_id, $level, $message, $crate::Fields::new())
+    };
+    ($client:expr, $user_id:expr, $level:expr, $message:expr, $fields:expr $(,)?) => {
+        $client.send_log_with_source($user_id, $level, $message, $fields, $crate::log_source!())
+    };
+}
+
+/// Default address of the storage service.
+const DEFAULT_BASE_URL: &str = "http://127.0.0.1:8088";
 
 pub struct LoggingClient {
-    client: Client
+    client: Client,
+    base_url: String,
+    component: Option<String>,
+    hostname: Option<String>,
+    token: Option<String>,
+    compression: Encoding,
+}
+
+impl Default for LoggingClient {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LoggingClient {
     pub fn new() -> Self {
         Self {
-            client: reqwest::Client::new()
+            client: reqwest::Client::new(),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            component: None,
+            hostname: hostname(),
+            token: None,
+            compression: Encoding::ANY,
+        }
+    }
+
+    /// Authenticates requests with the user (or admin) `token` issued by the service.
+    pub fn with_token(mut self, token: impl ToString) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    fn authorized(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Encoding of the logs sent, [`Encoding::ANY`] (gzip) unless set. Small logs are always
+    /// sent as is, and responses are decompressed in any of the built-in encodings.
+    pub fn with_compression(mut self, compression: Encoding) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Client of the service at `base_url`, e.g. `http://10.0.0.2:8088`.
+    pub fn with_base_url(mut self, base_url: impl ToString) -> Self {
+        self.base_url = base_url.to_string().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Client of the service listening on the Unix socket at `path`.
+    pub fn unix(path: impl AsRef<Path>) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .unix_socket(path.as_ref())
+                .build()
+                .unwrap(),
+            // The host is ignored when connecting through the socket.
+            base_url: "http://localhost".to_string(),
+            ..Self::new()
         }
     }
 
-    pub async fn send_log(&self, user_id: i64, log_level: LogLevel, message: String) -> Result<reqwest::Response, reqwest::Error> {
-        let log = MercuryLog {
-            level: log_level,
-            message,
-            data: None
+    /// Tags every log sent by this client with the `component` name.
+    pub fn with_component(mut self, component: impl ToString) -> Self {
+        self.component = Some(component.to_string());
+        self
+    }
+
+    /// Sends a log, recording the caller's file and line as its source. The module is only
+    /// known to macros, see [`send_log!`](crate::send_log).
+    #[track_caller]
+    pub fn send_log(&self, user_id: i64, log_level: LogLevel, message: String) -> impl Future<Output = Result<reqwest::Response, reqwest::Error>> + '_ {
+        self.send_log_with_fields(user_id, log_level, message, Fields::new())
+    }
+
+    /// Sends a log with structured fields, recording the caller's file and line as its source
+    /// (see [`send_log!`](crate::send_log) to record the module too).
+    #[track_caller]
+    pub fn send_log_with_fields(&self, user_id: i64, log_level: LogLevel, message: String, fields: Fields) -> impl Future<Output = Result<reqwest::Response, reqwest::Error>> + '_ {
+        let caller = Location::caller();
+        let source = LogSource {
+            file: Some(caller.file().to_string()),
+            line: Some(caller.line()),
+            ..Default::default()
         };
 
-        self.client.post(format!("http://127.0.0.1:8088/logs/{}", user_id)).json(&log).send().await
+        self.send_log_with_source(user_id, log_level, message, fields, source)
     }
 
-    pub async fn read_log(&self, user_id: i64) -> Result<Vec<LogWrapper<MercuryLog>>, reqwest::Error> {
-        let resp = reqwest::get(format!("http://127.0.0.1:8088/logs/{}", user_id)).await?;
-        let resp: Vec<LogWrapper<MercuryLog>> = resp.json().await?;
+    /// Sends a log with an explicit source (see [`log_source!`](crate::log_source)). The hostname,
+    /// process id and component are filled in when missing, and the trace ids are propagated
+    /// from the current trace (see [`in_trace`]).
+    pub async fn send_log_with_source(&self, user_id: i64, log_level: LogLevel, message: String, fields: Fields, source: LogSource) -> Result<reqwest::Response, reqwest::Error> {
+        let log = new_log(log_level, message, fields, source, &self.hostname, &self.component);
+
+        let (body, encoding) = encoding::json_body(self.compression, &log).expect("logs encode to JSON");
+        let mut request = self
+            .client
+            .post(format!("{}/logs/{}", self.base_url, user_id))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+
+        if encoding != Encoding::Identity {
+            request = request.header(CONTENT_ENCODING, encoding.as_str());
+        }
+
+        self.authorized(request).send().await
+    }
+
+    pub async fn read_log(&self, user_id: i64) -> Result<Vec<ServiceLog>, reqwest::Error> {
+        let resp = self.authorized(self.client.get(format!("{}/logs/{}", self.base_url, user_id))).send().await?;
+        let resp: Vec<ServiceLog> = resp.json().await?;
 
         Ok(resp)
     }
 }
+
+/// Log with the missing hostname, process id and component filled in, and the current trace.
+fn new_log(
+    level: LogLevel,
+    message: String,
+    fields: Fields,
+    mut source: LogSource,
+    hostname: &Option<String>,
+    component: &Option<String>,
+) -> MercuryLog {
+    source.hostname = source.hostname.or_else(|| hostname.clone());
+    source.pid = source.pid.or_else(|| Some(std::process::id()));
+    source.component = source.component.or_else(|| component.clone());
+
+    MercuryLog {
+        level,
+        message,
+        data: None,
+        fields,
+        content_type: None,
+        source: Some(source),
+        trace: current_trace(),
+    }
+}
+
+/// Logs sent in a batch unless set.
+const DEFAULT_BATCH_SIZE: usize = 512;
+
+/// Batches sent before waiting for the oldest one to be acknowledged.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// Client of the raw TCP ingestion protocol of the memory service (see [`tcp`](crate::tcp)), for
+/// producers sending too many logs for a request each. Logs are queued and sent in batches,
+/// several of which may be awaiting their acknowledgement; [`flush`](Self::flush) sends the
+/// queued logs and waits until every batch is acknowledged.
+///
+/// The batches are kept until acknowledged: after an I/O error, [`reconnect`](Self::reconnect)
+/// sends them again on a new connection. A batch the service wrote before the connection broke
+/// is then written twice.
+pub struct TcpLoggingClient {
+    addr: SocketAddr,
+    token: Option<String>,
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    batch: Vec<(i64, Vec<u8>)>,
+    batch_size: usize,
+    sequence: u64,
+    /// Number of the next log sent.
+    sent: u64,
+    in_flight: VecDeque<InFlight>,
+    refused: Vec<(u64, String)>,
+    component: Option<String>,
+    hostname: Option<String>,
+}
+
+/// Batch sent and not acknowledged yet.
+struct InFlight {
+    sequence: u64,
+    /// Number of its first log.
+    first: u64,
+    logs: Vec<(i64, Vec<u8>)>,
+}
+
+/// Connection to `addr` on which the service accepted `token`.
+async fn handshake(
+    addr: impl ToSocketAddrs,
+    token: Option<String>,
+) -> io::Result<(SocketAddr, BufReader<OwnedReadHalf>, BufWriter<OwnedWriteHalf>)> {
+    let stream = TcpStream::connect(addr).await?;
+    let addr = stream.peer_addr()?;
+    let (reader, writer) = stream.into_split();
+    let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+
+    tcp::write_frame(&mut writer, &ClientFrame::Hello { token }).await?;
+    match tcp::read_frame(&mut reader).await? {
+        Some(ServerFrame::Ready) => Ok((addr, reader, writer)),
+        Some(ServerFrame::Error(error)) => Err(io::Error::new(io::ErrorKind::PermissionDenied, error)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected ready")),
+    }
+}
+
+impl TcpLoggingClient {
+    /// Connects to the TCP ingestion address of the service, authenticating with the user (or
+    /// admin) `token` issued by the service.
+    pub async fn connect(addr: impl ToSocketAddrs, token: Option<String>) -> io::Result<Self> {
+        let (addr, reader, writer) = handshake(addr, token.clone()).await?;
+
+        Ok(Self {
+            addr,
+            token,
+            reader,
+            writer,
+            batch: Vec::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            sequence: 0,
+            sent: 0,
+            in_flight: VecDeque::new(),
+            refused: Vec::new(),
+            component: None,
+            hostname: hostname(),
+        })
+    }
+
+    /// Logs sent in a batch, 512 unless set.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.clamp(1, tcp::MAX_BATCH_LOGS);
+        self
+    }
+
+    /// Tags every log sent by this client with the `component` name.
+    pub fn with_component(mut self, component: impl ToString) -> Self {
+        self.component = Some(component.to_string());
+        self
+    }
+
+    /// Queues a log, recording the caller's file and line as its source (see
+    /// [`send_log!`](crate::send_log) to record the module too).
+    #[track_caller]
+    pub fn send_log(&mut self, user_id: i64, log_level: LogLevel, message: String) -> impl Future<Output = io::Result<u64>> + '_ {
+        let caller = Location::caller();
+        let source = LogSource {
+            file: Some(caller.file().to_string()),
+            line: Some(caller.line()),
+            ..Default::default()
+        };
+
+        self.send_log_with_source(user_id, log_level, message, Fields::new(), source)
+    }
+
+    /// Queues a log with structured fields and an explicit source, filled in as by
+    /// [`LoggingClient::send_log_with_source`].
+    pub async fn send_log_with_source(&mut self, user_id: i64, log_level: LogLevel, message: String, fields: Fields, source: LogSource) -> io::Result<u64> {
+        let log = new_log(log_level, message, fields, source, &self.hostname, &self.component);
+        self.send(user_id, &log).await
+    }
+
+    /// Queues `log` as is, sending the batch if it is full. Returns the number of the log on this
+    /// client, counted from 0, by which [`flush`](Self::flush) reports the refused ones.
+    pub async fn send(&mut self, user_id: i64, log: &MercuryLog) -> io::Result<u64> {
+        let serialized = bincode::serialize(log).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let number = self.sent;
+        self.sent += 1;
+        self.batch.push((user_id, serialized));
+
+        if self.batch.len() >= self.batch_size {
+            self.send_batch().await?;
+        }
+
+        Ok(number)
+    }
+
+    /// Sends the queued logs and waits for every acknowledgement. Returns the number and reason of
+    /// the logs refused by the service since the last flush, e.g. `limit_exceeded`.
+    pub async fn flush(&mut self) -> io::Result<Vec<(u64, String)>> {
+        self.send_batch().await?;
+        while !self.in_flight.is_empty() {
+            self.read_ack().await?;
+        }
+
+        Ok(std::mem::take(&mut self.refused))
+    }
+
+    async fn send_batch(&mut self) -> io::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        if self.in_flight.len() >= MAX_IN_FLIGHT {
+            self.read_ack().await?;
+        }
+
+        let logs = std::mem::take(&mut self.batch);
+        let batch = InFlight {
+            sequence: self.sequence,
+            first: self.sent - logs.len() as u64,
+            logs,
+        };
+        self.sequence += 1;
+
+        let frame = ClientFrame::Batch {
+            sequence: batch.sequence,
+            logs: batch.logs.clone(),
+        };
+        self.in_flight.push_back(batch);
+        tcp::write_frame(&mut self.writer, &frame).await
+    }
+
+    /// Connects again, e.g. after an I/O error, and sends the batches that weren't acknowledged.
+    /// The queued logs are sent with the next batch.
+    pub async fn reconnect(&mut self) -> io::Result<()> {
+        let (_, reader, writer) = handshake(self.addr, self.token.clone()).await?;
+        (self.reader, self.writer) = (reader, writer);
+
+        for batch in &self.in_flight {
+            let frame = ClientFrame::Batch {
+                sequence: batch.sequence,
+                logs: batch.logs.clone(),
+            };
+            tcp::write_frame(&mut self.writer, &frame).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn read_ack(&mut self) -> io::Result<()> {
+        match tcp::read_frame(&mut self.reader).await? {
+            Some(ServerFrame::Ack { sequence, refused }) if Some(sequence) == self.in_flight.front().map(|batch| batch.sequence) => {
+                let first = self.in_flight.pop_front().unwrap().first;
+                self.refused
+                    .extend(refused.into_iter().map(|refused| (first + refused.index as u64, refused.reason)));
+                Ok(())
+            }
+            Some(ServerFrame::Error(error)) => Err(io::Error::other(error)),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected frame")),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+}
@@ -0,0 +1,331 @@
+//! Serving the HTTP route groups on their own addresses.
+//!
+//! Routes are split in ingestion (writes), reads and admin groups so that, for example, admin
+//! routes can only be reachable from localhost. Groups bound to the same address share a listener.
+//! Addresses are either TCP socket addresses or `unix:{path}` Unix domain sockets.
+
+use std::{
+    fmt,
+    fs::{self, DirBuilder},
+    future::Future,
+    io,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use futures_util::{stream, FutureExt};
+use tokio::{
+    net::UnixListener,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use warp::{filters::BoxedFilter, reply::Response, Filter, Rejection, Reply};
+
+use crate::{auth, encoding, metrics::Metrics};
+
+/// Permissions of the Unix sockets unless configured otherwise.
+const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for BindAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+impl FromStr for BindAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => s
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|_| format!("invalid bind address {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub type Routes = BoxedFilter<(Response,)>;
+
+/// Type-erases a route group so that groups can be merged on a shared listener.
+pub fn routes<F, R>(filter: F) -> Routes
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    filter.map(|reply: R| reply.into_response()).boxed()
+}
+
+/// Group without routes, for groups disabled by configuration.
+pub fn none() -> Routes {
+    routes(warp::any().and_then(|| async { Err::<Response, _>(warp::reject::not_found()) }))
+}
+
+/// Addresses of the route groups.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listeners {
+    pub ingest: BindAddr,
+    pub read: BindAddr,
+    pub admin: BindAddr,
+    /// Permissions of the Unix socket files.
+    pub socket_mode: u32,
+}
+
+impl Listeners {
+    /// All the groups on `addr`.
+    pub fn single(addr: impl Into<BindAddr>) -> Self {
+        let addr = addr.into();
+
+        Self {
+            ingest: addr.clone(),
+            read: addr.clone(),
+            admin: addr,
+            socket_mode: DEFAULT_SOCKET_MODE,
+        }
+    }
+
+    /// Route groups merged by address, with the `probes` on every listener.
+    fn bind(&self, ingest: Routes, read: Routes, admin: Routes, probes: Routes) -> Vec<(BindAddr, Routes)> {
+        let mut bound: Vec<(BindAddr, Routes)> = Vec::new();
+
+        for (addr, routes) in [
+            (self.ingest.clone(), ingest),
+            (self.read.clone(), read),
+            (self.admin.clone(), admin),
+        ] {
+            match bound.iter_mut().find(|(bound_addr, _)| *bound_addr == addr) {
+                Some((_, shared)) => *shared = shared.clone().or(routes).unify().boxed(),
+                None => bound.push((addr, routes)),
+            }
+        }
+
+        bound
+            .into_iter()
+            .map(|(addr, routes)| (addr, probes.clone().or(routes).unify().boxed()))
+            .collect()
+    }
+
+    /// Serves every group until `shutdown` resolves, then stops accepting connections and
+    /// returns once the in-flight requests are done. Fails without serving anything if an address
+    /// cannot be bound.
+    pub async fn serve(
+        self,
+        ingest: Routes,
+        read: Routes,
+        admin: Routes,
+        probes: Routes,
+        metrics: Arc<Metrics>,
+        shutdown: impl Future<Output = ()>,
+    ) -> io::Result<()> {
+        // Every listener stops when the sender is dropped.
+        let (stop, stopped) = watch::channel(());
+        let stopped = move || {
+            let mut stopped = stopped.clone();
+            async move {
+                let _ = stopped.changed().await;
+            }
+        };
+
+        let (mut servers, mut sockets) = (Vec::new(), Vec::new());
+        for (addr, routes) in self.bind(ingest, read, admin, probes) {
+            let metrics = metrics.clone();
+            let routes = encoding::negotiate(routes)
+                .recover(auth::handle_rejection)
+                .recover(encoding::handle_rejection)
+                .with(warp::log::custom(move |info| metrics.http_request(info)))
+                .boxed();
+
+            let server = match &addr {
+                BindAddr::Tcp(tcp) => warp::serve(routes)
+                    .try_bind_with_graceful_shutdown(*tcp, stopped())
+                    .map(|(_, server)| server.boxed())
+                    .map_err(io::Error::other),
+                BindAddr::Unix(path) => bind_unix(path, self.socket_mode).map(|listener| {
+                    sockets.push(path.clone());
+                    serve_unix(routes, listener, path.clone(), stopped()).boxed()
+                }),
+            };
+
+            match server {
+                Ok(server) => servers.push(server),
+                Err(e) => {
+                    // The servers already bound never start to remove their sockets.
+                    for path in sockets {
+                        let _ = fs::remove_file(path);
+                    }
+                    return Err(io::Error::new(e.kind(), format!("cannot listen on {}: {}", addr, e)));
+                }
+            }
+        }
+
+        let servers = servers.into_iter().map(tokio::spawn).collect::<Vec<_>>();
+
+        shutdown.await;
+        drop(stop);
+
+        for server in servers {
+            server.await.unwrap();
+        }
+
+        Ok(())
+    }
+
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+pub async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    eprintln!("shutting down, draining in-flight requests");
+}
+
+/// Binds a Unix socket at `path` with permissions `mode`, replacing a stale socket but no other
+/// kind of file. The socket is created in a private directory and moved into place once its
+/// permissions are set, so it is never reachable with looser ones.
+fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file path", path.display())))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    DirBuilder::new().mode(0o700).create(&private)?;
+
+    let staged = private.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+
+    let _ = fs::remove_file(&staged);
+    fs::remove_dir(&private)?;
+    bound
+}
+
+/// Serves `routes` on the Unix socket `listener` bound at `path`, removing it once stopped.
+async fn serve_unix(
+    routes: BoxedFilter<(impl Reply + 'static,)>,
+    listener: UnixListener,
+    path: PathBuf,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let incoming = stream::unfold(listener, |listener| async move {
+        let stream = listener.accept().await.map(|(stream, _)| stream);
+        Some((stream, listener))
+    });
+
+    warp::serve(routes)
+        .serve_incoming_with_graceful_shutdown(incoming, shutdown)
+        .await;
+
+    let _ = fs::remove_file(&path);
+}
+
+#[cfg(test)]
+mod test {
+    use warp::Filter;
+
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    use super::{bind_unix, routes, BindAddr, Listeners};
+
+    #[test]
+    fn parse_bind_addr() {
+        assert_eq!(
+            "unix:/run/zephyr.sock".parse::<BindAddr>(),
+            Ok(BindAddr::Unix("/run/zephyr.sock".into()))
+        );
+        assert_eq!(
+            "127.0.0.1:8082".parse::<BindAddr>(),
+            Ok(BindAddr::Tcp(([127, 0, 0, 1], 8082).into()))
+        );
+        assert!("localhost".parse::<BindAddr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn binds_unix_sockets_safely() {
+        let dir = std::env::temp_dir().join(format!("zephyr-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        // A stale socket is replaced, leaving nothing else behind.
+        drop(listener);
+        bind_unix(&path, 0o660).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Any other file is kept.
+        let file = dir.join("data");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(bind_unix(&file, 0o600).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn groups_sharing_an_address_share_a_listener() {
+        let listeners = Listeners {
+            admin: "unix:/tmp/admin.sock".parse().unwrap(),
+            ..Listeners::single("0.0.0.0:8082".parse::<BindAddr>().unwrap())
+        };
+
+        let bound = listeners.bind(
+            routes(warp::path!("ingest").map(|| "ingest")),
+            routes(warp::path!("read").map(|| "read")),
+            routes(warp::path!("admin").map(|| "admin")),
+            routes(warp::path!("healthz").map(|| "ok")),
+        );
+
+        assert_eq!(bound.len(), 2);
+
+        let (_, public) = &bound[0];
+        let request = || warp::test::request().method("GET");
+        assert!(request().path("/ingest").filter(public).await.is_ok());
+        assert!(request().path("/read").filter(public).await.is_ok());
+        assert!(request().path("/admin").filter(public).await.is_err());
+
+        let (_, admin) = &bound[1];
+        assert!(request().path("/healthz").filter(public).await.is_ok());
+        assert!(request().path("/healthz").filter(admin).await.is_ok());
+    }
+}
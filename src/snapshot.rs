@@ -0,0 +1,279 @@
+//! Snapshots of the in-memory logger.
+//!
+//! A snapshot file is the `ZLOGSNAP` magic, the little-endian `u32` format version and the
+//! bincode encoded log groups of every user, including whether they are logging. Since version 2
+//! the groups are preceded by the first WAL segment not covered by the snapshot.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "storage")]
+use crate::{logs::LogWrapper, LogLevel};
+use crate::{logs::UserLogsGroup, IsLog, LoggerMemory};
+
+const MAGIC: &[u8; 8] = b"ZLOGSNAP";
+
+/// Version of the snapshot format, bumped on every incompatible change.
+const VERSION: u32 = 2;
+
+type State<L> = HashMap<i64, UserLogsGroup<L>>;
+
+/// What a snapshot restored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Restored {
+    pub users: usize,
+    /// First WAL segment to replay on top of the snapshot.
+    pub wal_segment: u64,
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn encode<L: Clone + Serialize>(wal_segment: u64, state: &State<L>) -> io::Result<Vec<u8>> {
+    let mut snapshot = MAGIC.to_vec();
+    snapshot.extend_from_slice(&VERSION.to_le_bytes());
+    bincode::serialize_into(&mut snapshot, &(wal_segment, state)).map_err(invalid_data)?;
+
+    Ok(snapshot)
+}
+
+fn decode<L: DeserializeOwned>(snapshot: &[u8]) -> io::Result<(u64, State<L>)> {
+    let body = snapshot
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| invalid_data("not a snapshot file"))?;
+
+    let (version, body) = body
+        .split_first_chunk::<4>()
+        .ok_or_else(|| invalid_data("truncated snapshot"))?;
+
+    match u32::from_le_bytes(*version) {
+        1 => bincode::deserialize(body).map(|state| (0, state)).map_err(invalid_data),
+        VERSION => bincode::deserialize(body).map_err(invalid_data),
+        version => Err(invalid_data(format!("unsupported snapshot version {}", version))),
+    }
+}
+
+/// Writes `snapshot` to `partial`, renames it to `path` and syncs both the file and its
+/// directory, so that nothing it covers is needed anymore.
+fn persist(partial: &Path, path: &Path, snapshot: &[u8]) -> io::Result<()> {
+    let mut file = File::create(partial)?;
+    file.write_all(snapshot)?;
+    file.sync_all()?;
+    fs::rename(partial, path)?;
+
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
+impl<L: IsLog + Serialize + DeserializeOwned> LoggerMemory<L> {
+    /// Writes the logs of every user to `path`. The snapshot is written next to it first and
+    /// then renamed, so a crash never leaves a truncated snapshot behind. The WAL segments it
+    /// covers are deleted once it is on disk.
+    pub async fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+
+        let (wal_segment, snapshot) = {
+            let state = self.state.lock().await;
+            let wal_segment = match &self.wal {
+                Some(journal) => journal.wal.rotate()?,
+                None => 0,
+            };
+
+            (wal_segment, encode(wal_segment, &*state)?)
+        };
+
+        let (partial, path) = (path.with_extension("partial"), path.to_path_buf());
+        tokio::task::spawn_blocking(move || persist(&partial, &path, &snapshot))
+            .await
+            .map_err(io::Error::other)??;
+
+        match &self.wal {
+            Some(journal) => journal.wal.compact(wal_segment),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the state with the snapshot at `path`, `None` if there is no snapshot yet.
+    pub async fn restore_snapshot(&self, path: impl AsRef<Path>) -> io::Result<Option<Restored>> {
+        let snapshot = match tokio::fs::read(path).await {
+            Ok(snapshot) => snapshot,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let (wal_segment, mut state) = decode::<L>(&snapshot)?;
+        for user_logs in state.values_mut() {
+            user_logs.recompute_stats();
+            self.seal(user_logs);
+        }
+
+        let users = state.len();
+        *self.state.lock().await = state;
+
+        Ok(Some(Restored { users, wal_segment }))
+    }
+}
+
+#[cfg(feature = "storage")]
+impl<L: IsLog> LoggerMemory<L> {
+    /// Copies the logs of every user to `storage`, keeping their original timestamps. They are
+    /// read from a copy of the state so that writes go on meanwhile, and the logs copied by an
+    /// earlier flush are skipped. Returns the number of logs inserted.
+    pub async fn flush_to_storage(&self, storage: &crate::LoggerStorage) -> Result<u64, tokio_postgres::Error> {
+        // The chunks are shared with the state, copying them is cheap.
+        let users = self
+            .state
+            .lock()
+            .await
+            .iter()
+            .map(|(user_id, user_logs)| {
+                let levels = [user_logs.errors(), user_logs.debug(), user_logs.warning()];
+                (*user_id, levels.map(Clone::clone))
+            })
+            .collect::<Vec<_>>();
+        let mut flushed = 0;
+
+        for (user_id, levels) in users {
+            let mut keys = FlushKeys::new(user_id);
+
+            for log in levels.iter().flat_map(|logs| logs.iter()) {
+                let key = keys.next(&log);
+                if storage
+                    .write_log_once(user_id, log.time, &key, crate::MercuryLog::from_log(&log.inner))
+                    .await?
+                {
+                    flushed += 1;
+                }
+            }
+        }
+
+        Ok(flushed)
+    }
+}
+
+/// Keys of the logs of a user flushed to Postgres, the same for a log on every flush: its user,
+/// level and time, its rank among the logs of that level and time and a digest of its contents.
+#[cfg(feature = "storage")]
+struct FlushKeys {
+    user_id: i64,
+    ranks: HashMap<(LogLevel, i64), u32>,
+}
+
+#[cfg(feature = "storage")]
+impl FlushKeys {
+    fn new(user_id: i64) -> Self {
+        Self {
+            user_id,
+            ranks: HashMap::new(),
+        }
+    }
+
+    /// Key of `log`, following the previous logs of the user in order.
+    fn next<L: IsLog>(&mut self, log: &LogWrapper<L>) -> String {
+        use sha2::{Digest, Sha256};
+
+        let level = log.inner.level();
+        let digest = Sha256::new()
+            .chain_update(log.inner.message())
+            .chain_update(log.inner.data().unwrap_or_default())
+            .finalize();
+
+        let rank = self.ranks.entry((level.clone(), log.time)).or_default();
+        let key = format!(
+            "{}.{}.{}.{}.{}",
+            self.user_id,
+            level.as_str(),
+            log.time,
+            rank,
+            hex::encode(&digest[..8])
+        );
+        *rank += 1;
+
+        key
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, Restored, VERSION};
+    use crate::{
+        test::{log, logging_users, TestLog},
+        LogLevel, LoggerMemory,
+    };
+
+    #[tokio::test]
+    async fn snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("zephyr-snapshot-{}", std::process::id()));
+
+        let logger = logging_users(&[1]).await;
+        logger.write_log(1, log(LogLevel::Error, "kept")).await.unwrap();
+        logger.write_log(2, log(LogLevel::Error, "not logging")).await.unwrap();
+        logger.save_snapshot(&path).await.unwrap();
+
+        let restored: LoggerMemory<TestLog> = LoggerMemory::new();
+        assert_eq!(
+            restored.restore_snapshot(&path).await.unwrap(),
+            Some(Restored { users: 2, wal_segment: 0 })
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let errors = restored.read_errros(1).await;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].inner.message, "kept");
+        assert_eq!(errors[0].time, logger.read_errros(1).await[0].time);
+
+        // Logging flags are restored too.
+        restored.write_log(1, log(LogLevel::Warning, "after")).await.unwrap();
+        restored.write_log(2, log(LogLevel::Warning, "after")).await.unwrap();
+        assert_eq!(restored.read_warning(1).await.len(), 1);
+        assert!(restored.read_warning(2).await.is_empty());
+
+        assert_eq!(restored.restore_snapshot(&path).await.unwrap(), None);
+    }
+
+    #[cfg(feature = "storage")]
+    #[test]
+    fn flush_keys_are_stable() {
+        use super::FlushKeys;
+        use crate::logs::LogWrapper;
+
+        let logs = [(1, "a"), (1, "a"), (2, "b")].map(|(time, message)| LogWrapper {
+            time,
+            inner: log(LogLevel::Debug, message),
+        });
+        let keys = |logs: &[LogWrapper<TestLog>]| {
+            let mut keys = FlushKeys::new(7);
+            logs.iter().map(|log| keys.next(log)).collect::<Vec<_>>()
+        };
+
+        // Identical logs of the same second get their own keys.
+        let flushed = keys(&logs);
+        assert!(flushed[0].starts_with("7.debug.1.0."), "{}", flushed[0]);
+        assert!(flushed[1].starts_with("7.debug.1.1."), "{}", flushed[1]);
+
+        // Pruned seconds don't change the keys of the next ones.
+        assert_eq!(keys(&logs[2..]), flushed[2..]);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut snapshot = b"ZLOGSNAP".to_vec();
+        snapshot.extend_from_slice(&(VERSION + 1).to_le_bytes());
+
+        assert!(decode::<TestLog>(&snapshot).is_err());
+        assert!(decode::<TestLog>(b"garbage").is_err());
+
+        // Version 1 snapshots have no WAL segment.
+        let mut snapshot = b"ZLOGSNAP".to_vec();
+        snapshot.extend_from_slice(&1u32.to_le_bytes());
+        snapshot.extend_from_slice(&bincode::serialize(&std::collections::HashMap::<i64, ()>::new()).unwrap());
+        assert_eq!(decode::<TestLog>(&snapshot).unwrap().0, 0);
+    }
+}
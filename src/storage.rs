@@ -1,14 +1,66 @@
-use std::time::SystemTime;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
+use futures_util::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use tokio_postgres::{types::Type, Error, NoTls, Statement};
-use crate::{logs::LogWrapper, IsLog, LogLevel, LoggerStorage};
+use tokio_postgres::{types::Type, Error, NoTls, Row, Statement};
+use crate::{
+    health::Check,
+    limits::log_bytes,
+    logs::{fields_from_json, fields_to_json, LogWrapper},
+    metrics::{Metrics, StoredGauges},
+    payload::base64_data,
+    ContentType, FieldFilter, Fields, PayloadCodecs, IsLog, LogLevel, LogSource, LoggerStorage, TraceContext,
+    HistogramBucket, HistogramQuery, TracedLog, UserStats,
+};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct MercuryLog {
     pub level: LogLevel,
     pub message: String,
+    #[serde(default, with = "base64_data")]
     pub data: Option<Vec<u8>>,
+    #[serde(default)]
+    pub fields: Fields,
+    #[serde(default)]
+    pub content_type: Option<ContentType>,
+    #[serde(default)]
+    pub source: Option<LogSource>,
+    #[serde(default)]
+    pub trace: Option<TraceContext>,
+}
+
+impl MercuryLog {
+    /// Plain log with no payload or metadata.
+    pub fn new(level: LogLevel, message: String) -> Self {
+        Self {
+            level,
+            message,
+            data: None,
+            fields: Fields::new(),
+            content_type: None,
+            source: None,
+            trace: None,
+        }
+    }
+
+    /// Copy of any other log type.
+    pub fn from_log<L: IsLog>(log: &L) -> Self {
+        Self {
+            level: log.level(),
+            message: log.message(),
+            data: log.data(),
+            fields: log.fields(),
+            content_type: log.content_type(),
+            source: log.source(),
+            trace: log.trace(),
+        }
+    }
 }
 
 impl IsLog for MercuryLog {
@@ -23,13 +75,52 @@ impl IsLog for MercuryLog {
     fn level(&self) -> LogLevel {
         self.level.clone()
     }
+
+    fn fields(&self) -> Fields {
+        self.fields.clone()
+    }
+
+    fn content_type(&self) -> Option<ContentType> {
+        self.content_type.clone()
+    }
+
+    fn source(&self) -> Option<LogSource> {
+        self.source.clone()
+    }
+
+    fn trace(&self) -> Option<TraceContext> {
+        self.trace.clone()
+    }
+}
+
+/// Inserts in flight above which the service reports itself as not ready.
+const MAX_PENDING_WRITES: usize = 256;
+
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest time the stored gauges of the metrics are reused before being recomputed.
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counts an insert as in flight until dropped, also when the request writing it is cancelled.
+struct PendingWrite<'a>(&'a AtomicUsize);
+
+impl<'a> PendingWrite<'a> {
+    fn start(pending: &'a AtomicUsize) -> Self {
+        pending.fetch_add(1, Ordering::Relaxed);
+        Self(pending)
+    }
+}
+
+impl Drop for PendingWrite<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl LoggerStorage {
-    pub async fn new(db_path: impl ToString) -> Self {
-        let (client, connection) = tokio_postgres::connect(&db_path.to_string(), NoTls)
-            .await
-            .unwrap();
+    /// Connects to the Postgres database at `db_path`.
+    pub async fn new(db_path: impl ToString) -> Result<Self, Error> {
+        let (client, connection) = tokio_postgres::connect(&db_path.to_string(), NoTls).await?;
 
         tokio::spawn(async move {
             if let Err(e) = connection.await {
@@ -37,92 +128,421 @@ impl LoggerStorage {
             }
         });
 
-        Self { client }
+        Ok(Self {
+            client,
+            metrics: None,
+            pending_writes: AtomicUsize::new(0),
+            stats: Default::default(),
+            codecs: Arc::new(PayloadCodecs::new()),
+        })
+    }
+
+    /// Renders the payloads of the reads with the schemas registered in `codecs`.
+    pub fn with_codecs(mut self, codecs: PayloadCodecs) -> Self {
+        self.codecs = Arc::new(codecs);
+        self
+    }
+
+    /// Schemas the payloads of the reads are rendered with.
+    pub fn codecs(&self) -> Arc<PayloadCodecs> {
+        self.codecs.clone()
+    }
+
+    /// Records the queries and accepted writes in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    fn query_done(&self, query: &str, started: Instant) {
+        if let Some(metrics) = &self.metrics {
+            metrics.postgres_query(query, started)
+        }
+    }
+
+    fn query_failed(&self, query: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.postgres_failed(query)
+        }
     }
 
-    pub async fn db_setup_project(&self) {
-        let create_table = String::from(&format!(
-            "CREATE TABLE IF NOT EXISTS mercury_user_logs (
+    /// Creates the logs table, deleting the existing logs if `clear` is set.
+    pub async fn db_setup_project(&self, clear: bool) -> Result<(), Error> {
+        let create_table = "CREATE TABLE IF NOT EXISTS mercury_user_logs (
                 user_id INT8,
                 timestamp INT8,
                 loglevel INT8,
-                message TEXT
-            )",
-        ));
+                message TEXT,
+                fields JSONB NOT NULL DEFAULT '{}',
+                data BYTEA,
+                content_type TEXT,
+                source JSONB,
+                trace_id TEXT,
+                span_id TEXT,
+                request_id TEXT,
+                flush_key TEXT
+            )";
+
+        // Tables created before these columns existed.
+        let add_columns = "ALTER TABLE mercury_user_logs
+                ADD COLUMN IF NOT EXISTS fields JSONB NOT NULL DEFAULT '{}',
+                ADD COLUMN IF NOT EXISTS data BYTEA,
+                ADD COLUMN IF NOT EXISTS content_type TEXT,
+                ADD COLUMN IF NOT EXISTS source JSONB,
+                ADD COLUMN IF NOT EXISTS trace_id TEXT,
+                ADD COLUMN IF NOT EXISTS span_id TEXT,
+                ADD COLUMN IF NOT EXISTS request_id TEXT,
+                ADD COLUMN IF NOT EXISTS flush_key TEXT";
 
-        let delete_rows = String::from(&format!("DELETE FROM mercury_user_logs",));
+        let trace_index = "CREATE INDEX IF NOT EXISTS mercury_user_logs_trace_id ON mercury_user_logs (trace_id)";
 
-        self.client.execute(&create_table, &[]).await.unwrap();
-        self.client.execute(&delete_rows, &[]).await.unwrap();
+        // Logs copied from memory are only inserted once, the others have no key.
+        let flush_index =
+            "CREATE UNIQUE INDEX IF NOT EXISTS mercury_user_logs_flush_key ON mercury_user_logs (flush_key)";
+
+        let delete_rows = "DELETE FROM mercury_user_logs";
+
+        self.client.execute(create_table, &[]).await?;
+        self.client.execute(add_columns, &[]).await?;
+        self.client.execute(trace_index, &[]).await?;
+        self.client.execute(flush_index, &[]).await?;
+
+        if clear {
+            self.client.execute(delete_rows, &[]).await?;
+        }
+
+        Ok(())
     }
 
     async fn prepared_statement(&self) -> Result<Statement, Error> {
         self.client.prepare_typed(
-            "INSERT INTO mercury_user_logs (user_id, timestamp, loglevel, message) VALUES ($1, $2, $3, $4)",
-            &[Type::INT8, Type::INT8, Type::INT8, Type::TEXT],
+            "INSERT INTO mercury_user_logs (user_id, timestamp, loglevel, message, fields, data, content_type, source, trace_id, span_id, request_id, flush_key) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) ON CONFLICT DO NOTHING",
+            &[Type::INT8, Type::INT8, Type::INT8, Type::TEXT, Type::JSONB, Type::BYTEA, Type::TEXT, Type::JSONB, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT],
         ).await
     }
 
+    /// Inserts `log` unless `flush_key` is already taken. Returns whether it was inserted.
+    async fn insert(&self, user_id: i64, timestamp: i64, flush_key: Option<&str>, log: MercuryLog) -> Result<bool, Error> {
+        let started = Instant::now();
+        let level = log.level.as_str();
+        let bytes = log_bytes(&log);
+        let statement = self.prepared_statement().await.inspect_err(|_| self.query_failed("insert"))?;
+
+        let fields = fields_to_json(&log.fields);
+        let content_type = log.content_type.as_ref().map(|content_type| content_type.to_string());
+        let source = log.source.as_ref().map(|source| serde_json::to_value(source).unwrap());
+        let trace = log.trace.unwrap_or_default();
+
+        let pending = PendingWrite::start(&self.pending_writes);
+        let inserted = self.client.execute(&statement, &[&user_id, &timestamp, &(log.level as i64), &log.message, &fields, &log.data, &content_type, &source, &trace.trace_id, &trace.span_id, &trace.request_id, &flush_key]).await.inspect_err(|_| self.query_failed("insert"))? > 0;
+        drop(pending);
+
+        self.query_done("insert", started);
+        if let Some(metrics) = self.metrics.as_ref().filter(|_| inserted) {
+            metrics.log_written("postgres", level, bytes, started)
+        }
+
+        Ok(inserted)
+    }
+
     pub async fn write_log(&self, user_id: i64, log: MercuryLog) -> Result<(), Error> {
-        let statement = self.prepared_statement().await?;
         let time = std::time::SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
 
-        self.client.execute(&statement, &[&user_id, &time, &(log.level as i64), &log.message]).await?;
+        self.insert(user_id, time, None, log).await.map(drop)
+    }
 
-        Ok(())
+    /// Writes a log received at `timestamp`, e.g. when moving logs from memory.
+    pub async fn write_log_at(&self, user_id: i64, timestamp: i64, log: MercuryLog) -> Result<(), Error> {
+        self.insert(user_id, timestamp, None, log).await.map(drop)
     }
 
-    pub async fn write_debug(&self, user_id: i64, timestamp: i64, message: String) -> Result<(), Error> {
-        let statement = self.prepared_statement().await?;
-        self.client.execute(&statement, &[&user_id, &timestamp, &(LogLevel::Debug as u32), &message]).await?;
+    /// Same as [`Self::write_log_at`], unless a log was already written under `flush_key`.
+    /// Returns whether it was written.
+    pub async fn write_log_once(&self, user_id: i64, timestamp: i64, flush_key: &str, log: MercuryLog) -> Result<bool, Error> {
+        self.insert(user_id, timestamp, Some(flush_key), log).await
+    }
 
-        Ok(())
+    pub async fn write_debug(&self, user_id: i64, timestamp: i64, message: String) -> Result<(), Error> {
+        self.insert(user_id, timestamp, None, MercuryLog::new(LogLevel::Debug, message)).await.map(drop)
     }
 
     pub async fn write_warning(&self, user_id: i64, timestamp: i64, message: String) -> Result<(), Error> {
-        let statement = self.prepared_statement().await?;
-        self.client.execute(&statement, &[&user_id, &timestamp, &(LogLevel::Warning as u32), &message]).await?;
-
-        Ok(())
+        self.insert(user_id, timestamp, None, MercuryLog::new(LogLevel::Warning, message)).await.map(drop)
     }
 
     pub async fn write_error(&self, user_id: i64, timestamp: i64, message: String) -> Result<(), Error> {
-        let statement = self.prepared_statement().await?;
-        self.client.execute(&statement, &[&user_id, &timestamp, &(LogLevel::Error as u32), &message]).await?;
+        self.insert(user_id, timestamp, None, MercuryLog::new(LogLevel::Error, message)).await.map(drop)
+    }
 
-        Ok(())
+    /// Deletes the logs of every user older than `timestamp`.
+    pub async fn delete_older_than(&self, timestamp: i64) -> Result<u64, Error> {
+        let started = Instant::now();
+        let deleted = self.client
+            .execute("DELETE FROM mercury_user_logs WHERE timestamp < $1", &[&timestamp])
+            .await
+            .inspect_err(|_| self.query_failed("delete"))?;
+
+        self.query_done("delete", started);
+        Ok(deleted)
+    }
+
+    /// Readiness checks: Postgres answers, the table has every column and inserts aren't piling up.
+    pub async fn readiness(&self) -> Vec<Check> {
+        let connection = match tokio::time::timeout(READINESS_TIMEOUT, self.client.simple_query("SELECT 1")).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no answer within {:?}", READINESS_TIMEOUT)),
+        };
+
+        let schema = match &connection {
+            Ok(()) => self.missing_columns().await.and_then(|missing| match missing.as_slice() {
+                [] => Ok(()),
+                missing => Err(format!("missing columns {}, restart to migrate", missing.join(", "))),
+            }),
+            Err(_) => Err("database unreachable".to_string()),
+        };
+
+        let pending = self.pending_writes.load(Ordering::Relaxed);
+        let writes = if pending < MAX_PENDING_WRITES {
+            Ok(())
+        } else {
+            Err(format!("{} inserts in flight", pending))
+        };
+
+        vec![
+            Check::new("postgres", connection),
+            Check::new("schema", schema),
+            Check::new("writes", writes),
+        ]
+    }
+
+    async fn missing_columns(&self) -> Result<Vec<String>, String> {
+        let rows = self.client
+            .query(
+                "SELECT column_name::TEXT FROM information_schema.columns WHERE table_name = 'mercury_user_logs'",
+                &[],
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let columns = rows.iter().map(|row| row.get::<_, String>(0)).collect::<Vec<_>>();
+
+        Ok(["user_id", "flush_key"]
+            .into_iter()
+            .chain(LOG_COLUMNS.split(", "))
+            .filter(|column| !columns.iter().any(|existing| existing == column))
+            .map(String::from)
+            .collect())
+    }
+
+    /// Summary of the logs of `user_id`, `None` if it has none.
+    pub async fn user_stats(&self, user_id: i64) -> Result<Option<UserStats>, Error> {
+        Ok(self.query_stats(Some(user_id)).await?.pop())
+    }
+
+    /// Logs of `user_id` per level in the time buckets of `query`, oldest first.
+    pub async fn histogram(&self, user_id: i64, query: &HistogramQuery) -> Result<Vec<HistogramBucket>, Error> {
+        let started = Instant::now();
+        // Timestamps are unix seconds, flooring them to the width is `date_trunc` for arbitrary widths.
+        let rows = self.client
+            .query(
+                "SELECT (floor(timestamp::NUMERIC / $2) * $2)::INT8 AS bucket, loglevel, count(*)
+                FROM mercury_user_logs
+                WHERE user_id = $1 AND ($3::INT8 IS NULL OR timestamp >= $3) AND ($4::INT8 IS NULL OR timestamp < $4)
+                GROUP BY bucket, loglevel
+                ORDER BY bucket",
+                &[&user_id, &(query.width as i64), &query.since, &query.until],
+            )
+            .await
+            .inspect_err(|_| self.query_failed("histogram"))?;
+
+        self.query_done("histogram", started);
+
+        let mut buckets: Vec<HistogramBucket> = Vec::new();
+        for row in rows {
+            let start: i64 = row.get(0);
+
+            if buckets.last().is_none_or(|bucket| bucket.start != start) {
+                buckets.push(HistogramBucket {
+                    start,
+                    ..Default::default()
+                });
+            }
+
+            let level = LogLevel::from_u32(row.get::<_, i64>(1) as u32);
+            buckets.last_mut().unwrap().add(&level, row.get::<_, i64>(2) as u64);
+        }
+
+        Ok(buckets)
+    }
+
+    /// Summaries of every user with logs, by user id.
+    pub async fn users_stats(&self) -> Result<Vec<UserStats>, Error> {
+        self.query_stats(None).await
+    }
+
+    async fn query_stats(&self, user_id: Option<i64>) -> Result<Vec<UserStats>, Error> {
+        let started = Instant::now();
+        let rows = self.client
+            .query(
+                "SELECT user_id,
+                    count(*) FILTER (WHERE loglevel = 0),
+                    count(*) FILTER (WHERE loglevel = 1),
+                    count(*) FILTER (WHERE loglevel = 2),
+                    coalesce(sum(octet_length(message) + coalesce(octet_length(data), 0)), 0)::INT8,
+                    min(timestamp),
+                    max(timestamp)
+                FROM mercury_user_logs
+                WHERE $1::INT8 IS NULL OR user_id = $1
+                GROUP BY user_id
+                ORDER BY user_id",
+                &[&user_id],
+            )
+            .await
+            .inspect_err(|_| self.query_failed("user_stats"))?;
+
+        self.query_done("user_stats", started);
+
+        Ok(rows
+            .iter()
+            .map(|row| UserStats {
+                user_id: row.get(0),
+                logging: None,
+                debug: row.get::<_, i64>(1) as u64,
+                warning: row.get::<_, i64>(2) as u64,
+                error: row.get::<_, i64>(3) as u64,
+                bytes: row.get::<_, i64>(4) as u64,
+                first_log: row.get(5),
+                last_log: row.get(6),
+            })
+            .collect())
+    }
+
+    /// Users and the logs they have stored, for the metrics. The totals scan the whole table, so
+    /// they are recomputed at most every [`STATS_INTERVAL`] and concurrent scrapes share a query.
+    pub async fn stats(&self) -> Result<StoredGauges, Error> {
+        let mut cached = self.stats.lock().await;
+        if let Some((_, stats)) = cached.as_ref().filter(|(computed, _)| computed.elapsed() < STATS_INTERVAL) {
+            return Ok(stats.clone());
+        }
+
+        let started = Instant::now();
+        let row = self.client
+            .query_one(
+                "SELECT count(DISTINCT user_id), count(*), coalesce(sum(octet_length(message) + coalesce(octet_length(data), 0)), 0)::INT8 FROM mercury_user_logs",
+                &[],
+            )
+            .await
+            .inspect_err(|_| self.query_failed("stats"))?;
+
+        self.query_done("stats", started);
+
+        let stats = StoredGauges {
+            active_users: row.get::<_, i64>(0) as u64,
+            logs: row.get::<_, i64>(1) as u64,
+            bytes: row.get::<_, i64>(2) as u64,
+        };
+        *cached = Some((started, stats.clone()));
+
+        Ok(stats)
     }
 
     pub async fn read_user_logs(&self, user_id: i64) -> Result<Vec<LogWrapper<MercuryLog>>, Error> {
+        self.read_user_logs_where(user_id, &FieldFilter::default()).await?.try_collect().await
+    }
+
+    /// Streams the logs of `user_id` whose fields match `filter`, decoding the rows as they arrive.
+    pub async fn read_user_logs_where(
+        &self,
+        user_id: i64,
+        filter: &FieldFilter,
+    ) -> Result<impl Stream<Item = Result<LogWrapper<MercuryLog>, Error>> + Send + 'static, Error> {
+        let started = Instant::now();
+        let client = &self.client;
+
+        let mut sql = format!("select {} from mercury_user_logs where user_id = $1", LOG_COLUMNS);
+        let mut types = vec![Type::INT8];
+        let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = vec![&user_id];
+
+        let conditions = filter
+            .fields()
+            .map(|(key, value)| ("fields", key.as_str(), value))
+            .chain(filter.source().map(|(key, value)| ("source", key, value)))
+            .collect::<Vec<_>>();
+
+        for (column, key, value) in &conditions {
+            sql.push_str(&format!(" and {}->>${} = ${}", column, params.len() + 1, params.len() + 2));
+            types.extend([Type::TEXT, Type::TEXT]);
+            params.push(key);
+            params.push(*value);
+        }
+
+        let query = client.prepare_typed(&sql, &types).await.inspect_err(|_| self.query_failed("read_user"))?;
+        let rows = client.query_raw(&query, params).await.inspect_err(|_| self.query_failed("read_user"))?;
+
+        self.query_done("read_user", started);
+        Ok(rows.map_ok(|row| log_from_row(&row)))
+    }
+
+    /// Streams the logs of every user belonging to the trace `trace_id`, oldest first.
+    pub async fn read_trace_logs(
+        &self,
+        trace_id: &str,
+    ) -> Result<impl Stream<Item = Result<TracedLog<LogWrapper<MercuryLog>>, Error>> + Send + 'static, Error> {
+        let started = Instant::now();
         let client = &self.client;
         let query = client
         .prepare_typed(
-            "select timestamp, loglevel, message from mercury_user_logs where user_id = $1;",
-            &[Type::INT8],
+            &format!("select user_id, {} from mercury_user_logs where trace_id = $1 order by timestamp;", LOG_COLUMNS),
+            &[Type::TEXT],
         )
-        .await?;
-
-        let query = client.query(&query, &[&user_id]).await?;
-        let mut logs = Vec::new();
-
-        for row in query {
-            let timestamp: i64 = row.get(0);
-            let log_level: i64 = row.get(1);
-            let message: String = row.get(2);
-
-            logs.push(LogWrapper {
-                time: timestamp,
-                inner: MercuryLog {
-                    level: LogLevel::from_u32(log_level as u32),
-                    message,
-                    data: None
-                }
-            })
-        }
+        .await
+        .inspect_err(|_| self.query_failed("read_trace"))?;
 
-        Ok(logs)
+        let rows = client.query_raw(&query, [trace_id]).await.inspect_err(|_| self.query_failed("read_trace"))?;
+
+        self.query_done("read_trace", started);
+        Ok(rows.map_ok(|row| TracedLog {
+            user_id: row.get(0),
+            log: log_from_offset(&row, 1),
+        }))
+    }
+}
+
+/// Columns decoded by [`log_from_row`], in order.
+const LOG_COLUMNS: &str = "timestamp, loglevel, message, fields, data, content_type, source, trace_id, span_id, request_id";
+
+fn log_from_row(row: &Row) -> LogWrapper<MercuryLog> {
+    log_from_offset(row, 0)
+}
+
+fn log_from_offset(row: &Row, offset: usize) -> LogWrapper<MercuryLog> {
+    let timestamp: i64 = row.get(offset);
+    let log_level: i64 = row.get(offset + 1);
+    let message: String = row.get(offset + 2);
+    let fields: serde_json::Value = row.get(offset + 3);
+    let data: Option<Vec<u8>> = row.get(offset + 4);
+    let content_type: Option<String> = row.get(offset + 5);
+    let source: Option<serde_json::Value> = row.get(offset + 6);
+    let trace = TraceContext {
+        trace_id: row.get(offset + 7),
+        span_id: row.get(offset + 8),
+        request_id: row.get(offset + 9),
+    };
+
+    LogWrapper {
+        time: timestamp,
+        inner: MercuryLog {
+            level: LogLevel::from_u32(log_level as u32),
+            message,
+            data,
+            fields: fields_from_json(&fields),
+            content_type: content_type.and_then(|content_type| content_type.parse().ok()),
+            source: source.and_then(|source| serde_json::from_value(source).ok()),
+            trace: (trace != TraceContext::default()).then_some(trace),
+        }
     }
 }
@@ -0,0 +1,515 @@
+//! Fan-out of ingested logs to subscribers.
+//!
+//! A [`Subscription`] receives a copy of every log stored for a user, of some levels only if
+//! given, as JSON arrays of [`TracedLog`]s POSTed to its URL. [`LoggerMemory::write_log`]
+//! publishes to a bounded queue per subscription, drained by a task sending batches of up to
+//! [`DeliveryOptions::batch_size`] logs. A failed batch is retried with exponential backoff and,
+//! after the last attempt, kept in the dead letters of the subscription.
+//!
+//! [`LoggerMemory::write_log`]: crate::LoggerMemory::write_log
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use warp::{http::StatusCode, reply::Response, Filter, Reply};
+
+use crate::{
+    auth::{self, Auth},
+    server::{self, Routes},
+    LogLevel, ServiceLog, TracedLog,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeliveryOptions {
+    /// Logs waiting per subscription, further ones are dropped.
+    pub queue: usize,
+    pub batch_size: usize,
+    /// How long a batch waits for more logs before being sent.
+    pub linger: Duration,
+    /// Attempts per batch, including the first one.
+    pub attempts: u32,
+    /// Wait before the first retry, doubled after each one.
+    pub backoff: Duration,
+    /// Failed batches kept per subscription, the oldest are dropped first.
+    pub dead_letters: usize,
+}
+
+impl Default for DeliveryOptions {
+    fn default() -> Self {
+        Self {
+            queue: 10_000,
+            batch_size: 100,
+            linger: Duration::from_millis(200),
+            attempts: 5,
+            backoff: Duration::from_millis(500),
+            dead_letters: 100,
+        }
+    }
+}
+
+/// Timeout of each delivery attempt.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SubscriptionSpec {
+    pub user_id: i64,
+    /// Levels delivered, every level if empty.
+    #[serde(default)]
+    pub levels: Vec<LogLevel>,
+    pub url: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Subscription {
+    pub id: u64,
+    #[serde(flatten)]
+    pub spec: SubscriptionSpec,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct DeliveryStats {
+    /// Logs delivered.
+    pub delivered: u64,
+    /// Batches delivered.
+    pub batches: u64,
+    pub retries: u64,
+    /// Logs dropped because the queue was full.
+    pub dropped: u64,
+    /// Logs moved to the dead letters.
+    pub dead_lettered: u64,
+    /// Logs waiting to be sent.
+    pub queued: usize,
+    pub last_delivery: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+/// A batch given up on.
+#[derive(Clone, Serialize)]
+pub struct DeadLetter {
+    pub failed_at: i64,
+    pub error: String,
+    pub logs: Vec<TracedLog<ServiceLog>>,
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct SubscriptionStatus {
+    #[serde(flatten)]
+    pub subscription: Subscription,
+    pub stats: DeliveryStats,
+}
+
+#[derive(Default)]
+struct Delivery {
+    stats: DeliveryStats,
+    dead_letters: VecDeque<DeadLetter>,
+}
+
+struct Subscriber {
+    subscription: Subscription,
+    queue: mpsc::Sender<TracedLog<ServiceLog>>,
+    delivery: Arc<Mutex<Delivery>>,
+}
+
+impl Subscriber {
+    fn wants(&self, user_id: i64, level: &LogLevel) -> bool {
+        let spec = &self.subscription.spec;
+        spec.user_id == user_id && (spec.levels.is_empty() || spec.levels.contains(level))
+    }
+
+    fn status(&self) -> SubscriptionStatus {
+        let mut stats = self.delivery.lock().unwrap().stats.clone();
+        stats.queued = self.queue.max_capacity() - self.queue.capacity();
+
+        SubscriptionStatus {
+            subscription: self.subscription.clone(),
+            stats,
+        }
+    }
+}
+
+pub struct Subscribers {
+    options: DeliveryOptions,
+    client: reqwest::Client,
+    next_id: Mutex<u64>,
+    subscribers: RwLock<BTreeMap<u64, Subscriber>>,
+}
+
+impl Default for Subscribers {
+    fn default() -> Self {
+        Self::new(DeliveryOptions::default())
+    }
+}
+
+impl Subscribers {
+    pub fn new(options: DeliveryOptions) -> Self {
+        Self {
+            options,
+            client: reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap(),
+            next_id: Mutex::new(1),
+            subscribers: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Registers `spec` and starts delivering to it, on the current runtime.
+    pub fn subscribe(&self, spec: SubscriptionSpec) -> Result<Subscription, String> {
+        match reqwest::Url::parse(&spec.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => return Err(format!("{} is not an http(s) URL", spec.url)),
+        }
+
+        let subscription = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            Subscription { id: *next_id - 1, spec }
+        };
+
+        let (queue, pending) = mpsc::channel(self.options.queue);
+        let delivery = Arc::new(Mutex::new(Delivery::default()));
+
+        tokio::spawn(deliver(
+            self.client.clone(),
+            subscription.spec.url.clone(),
+            self.options.clone(),
+            pending,
+            delivery.clone(),
+        ));
+
+        self.subscribers.write().unwrap().insert(
+            subscription.id,
+            Subscriber {
+                subscription: subscription.clone(),
+                queue,
+                delivery,
+            },
+        );
+
+        Ok(subscription)
+    }
+
+    /// Removes the subscription `id`, the logs already queued are still delivered.
+    pub fn unsubscribe(&self, id: u64) -> bool {
+        self.subscribers.write().unwrap().remove(&id).is_some()
+    }
+
+    pub fn list(&self) -> Vec<SubscriptionStatus> {
+        self.subscribers.read().unwrap().values().map(Subscriber::status).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<SubscriptionStatus> {
+        self.subscribers.read().unwrap().get(&id).map(Subscriber::status)
+    }
+
+    pub fn dead_letters(&self, id: u64) -> Option<Vec<DeadLetter>> {
+        let subscribers = self.subscribers.read().unwrap();
+        let delivery = subscribers.get(&id)?.delivery.lock().unwrap();
+
+        Some(delivery.dead_letters.iter().cloned().collect())
+    }
+
+    /// Drops the dead letters of `id`, returning them.
+    pub fn take_dead_letters(&self, id: u64) -> Option<Vec<DeadLetter>> {
+        let subscribers = self.subscribers.read().unwrap();
+        let mut delivery = subscribers.get(&id)?.delivery.lock().unwrap();
+
+        Some(delivery.dead_letters.drain(..).collect())
+    }
+
+    /// Whether a log of `level` for `user_id` has any subscriber, to skip rendering it otherwise.
+    pub(crate) fn wants(&self, user_id: i64, level: &LogLevel) -> bool {
+        let subscribers = self.subscribers.read().unwrap();
+        subscribers.values().any(|subscriber| subscriber.wants(user_id, level))
+    }
+
+    pub(crate) fn publish(&self, user_id: i64, log: ServiceLog) {
+        let subscribers = self.subscribers.read().unwrap();
+
+        for subscriber in subscribers.values().filter(|subscriber| subscriber.wants(user_id, &log.level)) {
+            let traced = TracedLog {
+                user_id,
+                log: log.clone(),
+            };
+
+            if subscriber.queue.try_send(traced).is_err() {
+                subscriber.delivery.lock().unwrap().stats.dropped += 1;
+            }
+        }
+    }
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    url: String,
+    options: DeliveryOptions,
+    mut pending: mpsc::Receiver<TracedLog<ServiceLog>>,
+    delivery: Arc<Mutex<Delivery>>,
+) {
+    while let Some(first) = pending.recv().await {
+        let mut batch = vec![first];
+        let linger = tokio::time::sleep(options.linger);
+        tokio::pin!(linger);
+
+        while batch.len() < options.batch_size {
+            tokio::select! {
+                log = pending.recv() => match log {
+                    Some(log) => batch.push(log),
+                    None => break,
+                },
+                _ = &mut linger => break,
+            }
+        }
+
+        send_batch(&client, &url, &options, batch, &delivery).await;
+    }
+}
+
+async fn send_batch(
+    client: &reqwest::Client,
+    url: &str,
+    options: &DeliveryOptions,
+    batch: Vec<TracedLog<ServiceLog>>,
+    delivery: &Mutex<Delivery>,
+) {
+    let mut backoff = options.backoff;
+
+    for attempt in 1..=options.attempts.max(1) {
+        let sent = client
+            .post(url)
+            .json(&batch)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        {
+            let mut delivery = delivery.lock().unwrap();
+
+            match sent {
+                Ok(_) => {
+                    delivery.stats.delivered += batch.len() as u64;
+                    delivery.stats.batches += 1;
+                    delivery.stats.last_delivery = Some(now());
+                    return;
+                }
+                Err(e) if attempt < options.attempts => {
+                    delivery.stats.retries += 1;
+                    delivery.stats.last_error = Some(e.to_string());
+                }
+                Err(e) => {
+                    eprintln!("subscribers: giving up on {} logs for {}: {}", batch.len(), url, e);
+
+                    delivery.stats.dead_lettered += batch.len() as u64;
+                    delivery.stats.last_error = Some(e.to_string());
+                    delivery.dead_letters.push_back(DeadLetter {
+                        failed_at: now(),
+                        error: e.to_string(),
+                        logs: batch,
+                    });
+
+                    while delivery.dead_letters.len() > options.dead_letters {
+                        delivery.dead_letters.pop_front();
+                    }
+                    return;
+                }
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+fn with_subscribers(
+    subscribers: Arc<Subscribers>,
+) -> impl Filter<Extract = (Arc<Subscribers>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || subscribers.clone())
+}
+
+fn json_reply<T: Serialize>(found: Option<T>, status: StatusCode) -> Response {
+    match found {
+        Some(found) => warp::reply::with_status(serde_json::to_string(&found).unwrap(), status).into_response(),
+        None => warp::reply::with_status("unknown subscription", StatusCode::NOT_FOUND).into_response(),
+    }
+}
+
+/// Admin routes: `GET`/`POST /subscriptions`, `GET`/`DELETE /subscriptions/{id}` and
+/// `GET`/`DELETE /subscriptions/{id}/dead_letters`.
+pub fn routes(subscribers: Arc<Subscribers>, auth: Arc<Auth>) -> Routes {
+    let list = warp::path!("subscriptions")
+        .and(warp::get())
+        .and(with_subscribers(subscribers.clone()))
+        .map(|subscribers: Arc<Subscribers>| json_reply(Some(subscribers.list()), StatusCode::OK));
+
+    let subscribe = warp::path!("subscriptions")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_subscribers(subscribers.clone()))
+        .map(
+            |spec: SubscriptionSpec, subscribers: Arc<Subscribers>| match subscribers.subscribe(spec) {
+                Ok(subscription) => json_reply(Some(subscription), StatusCode::CREATED),
+                Err(error) => warp::reply::with_status(error, StatusCode::BAD_REQUEST).into_response(),
+            },
+        );
+
+    let get = warp::path!("subscriptions" / u64)
+        .and(warp::get())
+        .and(with_subscribers(subscribers.clone()))
+        .map(|id, subscribers: Arc<Subscribers>| json_reply(subscribers.get(id), StatusCode::OK));
+
+    let unsubscribe = warp::path!("subscriptions" / u64)
+        .and(warp::delete())
+        .and(with_subscribers(subscribers.clone()))
+        .map(|id, subscribers: Arc<Subscribers>| {
+            json_reply(subscribers.unsubscribe(id).then_some("success"), StatusCode::OK)
+        });
+
+    let dead_letters = warp::path!("subscriptions" / u64 / "dead_letters")
+        .and(warp::get())
+        .and(with_subscribers(subscribers.clone()))
+        .map(|id, subscribers: Arc<Subscribers>| json_reply(subscribers.dead_letters(id), StatusCode::OK));
+
+    let take_dead_letters = warp::path!("subscriptions" / u64 / "dead_letters")
+        .and(warp::delete())
+        .and(with_subscribers(subscribers))
+        .map(|id, subscribers: Arc<Subscribers>| json_reply(subscribers.take_dead_letters(id), StatusCode::OK));
+
+    let crud = list
+        .or(subscribe)
+        .unify()
+        .or(get)
+        .unify()
+        .or(unsubscribe)
+        .unify()
+        .or(dead_letters)
+        .unify()
+        .or(take_dead_letters)
+        .unify();
+
+    server::routes(auth::admin(auth).and(crud))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc;
+    use warp::{http::StatusCode, Filter};
+
+    use super::{DeliveryOptions, Subscribers, SubscriptionSpec};
+    use crate::{
+        test::{log, logging_users},
+        LogLevel,
+    };
+
+    fn options() -> DeliveryOptions {
+        DeliveryOptions {
+            linger: Duration::from_millis(50),
+            attempts: 2,
+            backoff: Duration::from_millis(10),
+            ..Default::default()
+        }
+    }
+
+    /// Endpoint failing the first `failures` requests, with the batches it accepted.
+    fn endpoint(failures: usize) -> (String, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let (received, batches) = mpsc::unbounded_channel();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let endpoint = warp::post().and(warp::body::json()).map(move |batch: serde_json::Value| {
+            if requests.fetch_add(1, Ordering::SeqCst) < failures {
+                return warp::reply::with_status("down", StatusCode::SERVICE_UNAVAILABLE);
+            }
+
+            received.send(batch).unwrap();
+            warp::reply::with_status("ok", StatusCode::OK)
+        });
+
+        let (addr, server) = warp::serve(endpoint).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        (format!("http://{}/logs", addr), batches)
+    }
+
+    #[tokio::test]
+    async fn delivers_batches_with_retries() {
+        let (url, mut batches) = endpoint(1);
+        let subscribers = Arc::new(Subscribers::new(options()));
+        let subscription = subscribers
+            .subscribe(SubscriptionSpec {
+                user_id: 1,
+                levels: vec![LogLevel::Error, LogLevel::Warning],
+                url,
+            })
+            .unwrap();
+
+        let logger = logging_users(&[1, 2]).await.with_subscribers(subscribers.clone());
+        logger.write_log(1, log(LogLevel::Error, "first")).await.unwrap();
+        logger.write_log(1, log(LogLevel::Debug, "filtered out")).await.unwrap();
+        logger.write_log(2, log(LogLevel::Error, "other user")).await.unwrap();
+        logger.write_log(1, log(LogLevel::Warning, "second")).await.unwrap();
+        // Not stored, the user isn't logging.
+        logger.write_log(3, log(LogLevel::Error, "dropped")).await.unwrap();
+
+        let batch = batches.recv().await.unwrap();
+        let messages = batch
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|log| (log["user_id"].as_i64().unwrap(), log["message"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(messages, [(1, "first"), (1, "second")]);
+
+        // The endpoint answers before the delivery is recorded.
+        while subscribers.get(subscription.id).unwrap().stats.batches == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let stats = subscribers.get(subscription.id).unwrap().stats;
+        assert_eq!((stats.delivered, stats.batches, stats.retries, stats.queued), (2, 1, 1, 0));
+        assert!(stats.last_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn keeps_dead_letters() {
+        let (url, _batches) = endpoint(usize::MAX);
+        let subscribers = Arc::new(Subscribers::new(options()));
+        let subscription = subscribers
+            .subscribe(SubscriptionSpec {
+                user_id: 1,
+                levels: vec![],
+                url,
+            })
+            .unwrap();
+
+        let logger = logging_users(&[1]).await.with_subscribers(subscribers.clone());
+        logger.write_log(1, log(LogLevel::Debug, "lost")).await.unwrap();
+
+        while subscribers.get(subscription.id).unwrap().stats.dead_lettered == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let stats = subscribers.get(subscription.id).unwrap().stats;
+        assert_eq!((stats.delivered, stats.retries), (0, 1));
+
+        let dead_letters = subscribers.take_dead_letters(subscription.id).unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].logs[0].log.message, "lost");
+        assert!(subscribers.dead_letters(subscription.id).unwrap().is_empty());
+
+        assert!(subscribers.unsubscribe(subscription.id));
+        assert!(subscribers.get(subscription.id).is_none());
+    }
+}
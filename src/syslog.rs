@@ -0,0 +1,587 @@
+//! Syslog ingestion.
+//!
+//! Messages are received on UDP (one per datagram) and TCP (octet-counted or newline-delimited
+//! frames, RFC 6587) and parsed as RFC 5424, or as RFC 3164 when they lack the version. The user
+//! a message belongs to is the `user_id` parameter of its structured data, else the first app-name
+//! rule matching its app-name (or RFC 3164 tag). Messages without a user are dropped.
+//!
+//! Syslog carries no credentials: whoever can reach the listeners can write the logs of any user.
+//! `allowed_sources` restricts them to the given networks, and the service refuses to start them
+//! with authentication enabled unless sources are restricted or `allow_unauthenticated` is set.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+};
+
+use crate::{metrics::Metrics, FieldValue, Fields, LogLevel, LogSource};
+
+/// Structured data parameter holding the user id unless configured otherwise.
+pub const DEFAULT_USER_ID_PARAM: &str = "user_id";
+
+/// Longest message accepted, larger TCP frames close the connection.
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Messages received but not yet written.
+const QUEUE: usize = 10_000;
+
+/// Messages of the app-names matching `app` belong to `user_id`. `app` may end with `*` to
+/// match a prefix.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppRule {
+    pub app: String,
+    pub user_id: i64,
+}
+
+impl AppRule {
+    fn matches(&self, app: &str) -> bool {
+        match self.app.strip_suffix('*') {
+            Some(prefix) => app.starts_with(prefix),
+            None => app == self.app,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyslogConfig {
+    pub udp: Option<SocketAddr>,
+    pub tcp: Option<SocketAddr>,
+    pub user_id_param: String,
+    pub apps: Vec<AppRule>,
+    /// Accept messages from unrestricted sources while authentication is enabled.
+    pub allow_unauthenticated: bool,
+    /// Networks messages are accepted from, any if empty.
+    pub allowed_sources: Vec<IpNet>,
+}
+
+impl SyslogConfig {
+    /// Whether messages from `peer` are accepted.
+    pub fn allows(&self, peer: IpAddr) -> bool {
+        let peer = peer.to_canonical();
+        self.allowed_sources.is_empty() || self.allowed_sources.iter().any(|net| net.contains(&peer))
+    }
+
+    /// User `message` belongs to.
+    pub fn user_id(&self, message: &Message) -> Option<i64> {
+        message
+            .param(&self.user_id_param)
+            .and_then(|user_id| user_id.parse().ok())
+            .or_else(|| {
+                let app = message.app_name.as_deref()?;
+                self.apps.iter().find(|rule| rule.matches(app)).map(|rule| rule.user_id)
+            })
+    }
+}
+
+/// Element of the structured data of an RFC 5424 message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SdElement {
+    pub id: String,
+    pub params: Vec<(String, String)>,
+}
+
+/// Parsed syslog message. Missing (`-`) header fields are `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub facility: u8,
+    pub severity: u8,
+    pub timestamp: Option<String>,
+    pub hostname: Option<String>,
+    pub app_name: Option<String>,
+    pub proc_id: Option<String>,
+    pub msg_id: Option<String>,
+    pub structured_data: Vec<SdElement>,
+    pub message: String,
+}
+
+impl Message {
+    /// Emergency to error are errors, warnings are warnings, notice to debug are debug logs.
+    pub fn level(&self) -> LogLevel {
+        match self.severity {
+            0..=3 => LogLevel::Error,
+            4 => LogLevel::Warning,
+            _ => LogLevel::Debug,
+        }
+    }
+
+    /// First structured data parameter called `name`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.structured_data
+            .iter()
+            .flat_map(|element| &element.params)
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Facility, severity, message id and the structured data parameters.
+    pub fn fields(&self) -> Fields {
+        let mut fields = Fields::new();
+        fields.insert("facility".into(), FieldValue::Integer(self.facility.into()));
+        fields.insert("severity".into(), FieldValue::Integer(self.severity.into()));
+
+        if let Some(msg_id) = &self.msg_id {
+            fields.insert("msg_id".into(), FieldValue::String(msg_id.clone()));
+        }
+
+        for (name, value) in self.structured_data.iter().flat_map(|element| &element.params) {
+            fields
+                .entry(name.clone())
+                .or_insert_with(|| FieldValue::String(value.clone()));
+        }
+
+        fields
+    }
+
+    pub fn source(&self) -> LogSource {
+        LogSource {
+            hostname: self.hostname.clone(),
+            pid: self.proc_id.as_deref().and_then(|pid| pid.parse().ok()),
+            component: self.app_name.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn remove_param(&mut self, name: &str) {
+        for element in &mut self.structured_data {
+            element.params.retain(|(param, _)| param != name)
+        }
+    }
+}
+
+/// Parses an RFC 5424 message, or an RFC 3164 one if there is no version after the priority.
+pub fn parse(line: &str) -> Result<Message, String> {
+    let line = line.trim_end_matches(['\r', '\n', '\0']);
+
+    let (priority, rest) = line
+        .strip_prefix('<')
+        .and_then(|rest| rest.split_once('>'))
+        .ok_or("missing priority")?;
+    let priority = priority
+        .parse::<u8>()
+        .ok()
+        .filter(|priority| *priority <= 191)
+        .ok_or_else(|| format!("invalid priority {}", priority))?;
+
+    match rest.strip_prefix("1 ") {
+        Some(rest) => parse_5424(priority, rest),
+        None => Ok(parse_3164(priority, rest)),
+    }
+}
+
+fn nil(value: &str) -> Option<String> {
+    (value != "-").then(|| value.to_string())
+}
+
+fn parse_5424(priority: u8, rest: &str) -> Result<Message, String> {
+    let mut header = rest.splitn(6, ' ');
+    let mut next = |name: &str| header.next().ok_or_else(|| format!("missing {}", name));
+
+    let timestamp = nil(next("timestamp")?);
+    let hostname = nil(next("hostname")?);
+    let app_name = nil(next("app-name")?);
+    let proc_id = nil(next("procid")?);
+    let msg_id = nil(next("msgid")?);
+    let (structured_data, message) = parse_structured_data(next("structured data")?)?;
+
+    let message = message.strip_prefix(' ').unwrap_or(message);
+
+    Ok(Message {
+        facility: priority / 8,
+        severity: priority % 8,
+        timestamp,
+        hostname,
+        app_name,
+        proc_id,
+        msg_id,
+        structured_data,
+        message: message.trim_start_matches('\u{feff}').to_string(),
+    })
+}
+
+/// Structured data at the start of `data`, and what follows it.
+fn parse_structured_data(data: &str) -> Result<(Vec<SdElement>, &str), String> {
+    if let Some(rest) = data.strip_prefix('-') {
+        return Ok((vec![], rest));
+    }
+
+    let mut elements = Vec::new();
+    let mut rest = data;
+
+    while let Some(element) = rest.strip_prefix('[') {
+        let id_end = element.find([' ', ']']).ok_or("unterminated structured data")?;
+        let mut params = Vec::new();
+        let mut cursor = &element[id_end..];
+
+        rest = loop {
+            if let Some(after) = cursor.strip_prefix(']') {
+                break after;
+            }
+
+            let (name, quoted) = cursor
+                .strip_prefix(' ')
+                .and_then(|param| param.split_once("=\""))
+                .ok_or("invalid structured data parameter")?;
+
+            // Values escape `"`, `\` and `]` with a backslash.
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next().ok_or("unterminated structured data value")? {
+                    (_, '\\') => match chars.next() {
+                        Some((_, escaped @ ('"' | '\\' | ']'))) => value.push(escaped),
+                        Some((_, other)) => value.extend(['\\', other]),
+                        None => return Err("unterminated structured data value".to_string()),
+                    },
+                    (index, '"') => break index + 1,
+                    (_, other) => value.push(other),
+                }
+            };
+
+            params.push((name.to_string(), value));
+            cursor = &quoted[end..];
+        };
+
+        elements.push(SdElement {
+            id: element[..id_end].to_string(),
+            params,
+        });
+    }
+
+    if elements.is_empty() {
+        return Err("invalid structured data".to_string());
+    }
+
+    Ok((elements, rest))
+}
+
+/// `Mmm dd hh:mm:ss`, the day padded with a space.
+fn is_3164_timestamp(timestamp: &str) -> bool {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let bytes = timestamp.as_bytes();
+    let digit = |index: usize| bytes[index].is_ascii_digit();
+
+    bytes.len() == 15
+        && MONTHS.iter().any(|month| timestamp.starts_with(month))
+        && bytes[3] == b' '
+        && (bytes[4] == b' ' || digit(4))
+        && digit(5)
+        && bytes[6] == b' '
+        && [7, 8, 10, 11, 13, 14].into_iter().all(digit)
+        && bytes[9] == b':'
+        && bytes[12] == b':'
+}
+
+/// Parses the lenient RFC 3164 format, `Mmm dd hh:mm:ss hostname tag[pid]: message`, where any
+/// part before the message may be missing.
+fn parse_3164(priority: u8, rest: &str) -> Message {
+    let (timestamp, rest) = match rest.get(..15).filter(|timestamp| is_3164_timestamp(timestamp)) {
+        Some(timestamp) => (Some(timestamp.to_string()), rest[15..].trim_start()),
+        None => (None, rest),
+    };
+
+    // The hostname only follows a timestamp, and is left out by some senders.
+    let (hostname, rest) = match rest.split_once(' ') {
+        Some((hostname, rest)) if timestamp.is_some() && !hostname.ends_with(':') && !hostname.contains('[') => {
+            (Some(hostname.to_string()), rest)
+        }
+        _ => (None, rest),
+    };
+
+    let (tag, message) = match rest.split_once(':') {
+        Some((tag, message)) if !tag.is_empty() && !tag.contains(' ') => {
+            (Some(tag), message.strip_prefix(' ').unwrap_or(message))
+        }
+        _ => (None, rest),
+    };
+
+    let (app_name, proc_id) = match tag.and_then(|tag| tag.split_once('[')) {
+        Some((app_name, pid)) => (Some(app_name), pid.strip_suffix(']')),
+        None => (tag, None),
+    };
+
+    Message {
+        facility: priority / 8,
+        severity: priority % 8,
+        timestamp,
+        hostname,
+        app_name: app_name.map(str::to_string),
+        proc_id: proc_id.map(str::to_string),
+        msg_id: None,
+        structured_data: vec![],
+        message: message.to_string(),
+    }
+}
+
+/// Next octet-counted (`{length} {message}`) or newline-delimited frame, `None` at the end of
+/// the stream.
+async fn read_frame<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let Some(first) = reader.fill_buf().await?.first().copied() else {
+        return Ok(None);
+    };
+
+    let invalid = |error: &str| io::Error::new(io::ErrorKind::InvalidData, error.to_string());
+    let mut frame = Vec::new();
+
+    if first.is_ascii_digit() {
+        let mut length = Vec::new();
+        (&mut *reader).take(8).read_until(b' ', &mut length).await?;
+
+        let length = std::str::from_utf8(&length)
+            .ok()
+            .and_then(|length| length.strip_suffix(' ')?.parse::<usize>().ok())
+            .ok_or_else(|| invalid("invalid frame length"))?;
+        if length > MAX_MESSAGE_BYTES {
+            return Err(invalid("frame too large"));
+        }
+
+        frame.resize(length, 0);
+        reader.read_exact(&mut frame).await?;
+    } else {
+        (&mut *reader)
+            .take(MAX_MESSAGE_BYTES as u64)
+            .read_until(b'\n', &mut frame)
+            .await?;
+    }
+
+    Ok(Some(frame))
+}
+
+/// Parses `frame` and resolves its user, counting the messages dropped.
+fn accept(config: &SyslogConfig, metrics: &Metrics, frame: &[u8]) -> Option<(i64, Message)> {
+    let frame = String::from_utf8_lossy(frame);
+    if frame.trim().is_empty() {
+        return None;
+    }
+
+    let Ok(mut message) = parse(&frame) else {
+        metrics.log_rejected("syslog_invalid");
+        return None;
+    };
+
+    let Some(user_id) = config.user_id(&message) else {
+        metrics.log_rejected("syslog_unknown_user");
+        return None;
+    };
+
+    message.remove_param(&config.user_id_param);
+    Some((user_id, message))
+}
+
+async fn serve_udp(
+    socket: UdpSocket,
+    config: Arc<SyslogConfig>,
+    metrics: Arc<Metrics>,
+    messages: mpsc::Sender<(i64, Message)>,
+) {
+    let mut buffer = vec![0; MAX_MESSAGE_BYTES];
+
+    loop {
+        let received = match socket.recv_from(&mut buffer).await {
+            Ok((received, peer)) => {
+                if !config.allows(peer.ip()) {
+                    metrics.log_rejected("syslog_forbidden_source");
+                    continue;
+                }
+                received
+            }
+            Err(e) => {
+                eprintln!("syslog udp error: {}", e);
+                continue;
+            }
+        };
+
+        if let Some(message) = accept(&config, &metrics, &buffer[..received]) {
+            if messages.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn serve_tcp(
+    listener: TcpListener,
+    config: Arc<SyslogConfig>,
+    metrics: Arc<Metrics>,
+    messages: mpsc::Sender<(i64, Message)>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, peer)) => {
+                if !config.allows(peer.ip()) {
+                    metrics.log_rejected("syslog_forbidden_source");
+                    continue;
+                }
+                stream
+            }
+            Err(e) => {
+                eprintln!("syslog tcp error: {}", e);
+                continue;
+            }
+        };
+
+        let (config, metrics, messages) = (config.clone(), metrics.clone(), messages.clone());
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(stream);
+
+            loop {
+                match read_frame(&mut reader).await {
+                    Ok(Some(frame)) => {
+                        if let Some(message) = accept(&config, &metrics, &frame) {
+                            if messages.send(message).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(e) => {
+                        eprintln!("syslog tcp error: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Binds the configured listeners. Every accepted message is sent to the returned receiver
+/// with its user id.
+pub async fn listen(config: SyslogConfig, metrics: Arc<Metrics>) -> io::Result<mpsc::Receiver<(i64, Message)>> {
+    let (sender, receiver) = mpsc::channel(QUEUE);
+    let config = Arc::new(config);
+
+    if let Some(addr) = config.udp {
+        let socket = UdpSocket::bind(addr).await?;
+        tokio::spawn(serve_udp(socket, config.clone(), metrics.clone(), sender.clone()));
+    }
+
+    if let Some(addr) = config.tcp {
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(serve_tcp(listener, config.clone(), metrics, sender));
+    }
+
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, read_frame, AppRule, SdElement, SyslogConfig, DEFAULT_USER_ID_PARAM};
+    use crate::{FieldValue, LogLevel};
+
+    #[test]
+    fn parses_rfc5424() {
+        let message = parse(
+            "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog 8710 ID47 \
+             [exampleSDID@32473 iut=\"3\" eventSource=\"App\\]lication\"][zephyr user_id=\"5\"] \u{feff}An application event",
+        )
+        .unwrap();
+
+        assert_eq!((message.facility, message.severity), (20, 5));
+        assert_eq!(message.level(), LogLevel::Debug);
+        assert_eq!(message.timestamp.as_deref(), Some("2003-10-11T22:14:15.003Z"));
+        assert_eq!(message.hostname.as_deref(), Some("mymachine.example.com"));
+        assert_eq!(message.source().pid, Some(8710));
+        assert_eq!(message.msg_id.as_deref(), Some("ID47"));
+        assert_eq!(
+            message.structured_data[0],
+            SdElement {
+                id: "exampleSDID@32473".into(),
+                params: vec![("iut".into(), "3".into()), ("eventSource".into(), "App]lication".into())],
+            }
+        );
+        assert_eq!(message.param("user_id"), Some("5"));
+        assert_eq!(message.fields()["eventSource"], FieldValue::String("App]lication".into()));
+        assert_eq!(message.message, "An application event");
+
+        let message = parse("<11>1 - - - - - -").unwrap();
+        assert_eq!((message.level(), message.hostname, message.message.as_str()), (LogLevel::Error, None, ""));
+
+        assert!(parse("no priority").is_err());
+        assert!(parse("<192>1 - - - - - -").is_err());
+        assert!(parse("<11>1 - - - - - [unterminated").is_err());
+    }
+
+    #[test]
+    fn parses_rfc3164() {
+        let message = parse("<34>Oct  1 22:14:15 mymachine su[230]: 'su root' failed for lonvick").unwrap();
+
+        assert_eq!(message.level(), LogLevel::Error);
+        assert_eq!(message.timestamp.as_deref(), Some("Oct  1 22:14:15"));
+        assert_eq!(message.hostname.as_deref(), Some("mymachine"));
+        assert_eq!(message.app_name.as_deref(), Some("su"));
+        assert_eq!(message.proc_id.as_deref(), Some("230"));
+        assert_eq!(message.message, "'su root' failed for lonvick");
+
+        let message = parse("<12>nginx: upstream timed out\n").unwrap();
+        assert_eq!(message.level(), LogLevel::Warning);
+        assert_eq!((message.hostname, message.app_name.as_deref()), (None, Some("nginx")));
+        assert_eq!(message.message, "upstream timed out");
+
+        let message = parse("<13>just a message").unwrap();
+        assert_eq!((message.app_name, message.message.as_str()), (None, "just a message"));
+    }
+
+    #[test]
+    fn resolves_users() {
+        let config = SyslogConfig {
+            udp: None,
+            tcp: None,
+            user_id_param: DEFAULT_USER_ID_PARAM.into(),
+            apps: vec![
+                AppRule { app: "billing-*".into(), user_id: 7 },
+                AppRule { app: "nginx".into(), user_id: 8 },
+            ],
+            allow_unauthenticated: false,
+            allowed_sources: Vec::new(),
+        };
+        let user_id = |line: &str| config.user_id(&parse(line).unwrap());
+
+        assert_eq!(user_id("<11>1 - - nginx - - [z user_id=\"5\"] overridden"), Some(5));
+        assert_eq!(user_id("<11>1 - - billing-api - - - prefix"), Some(7));
+        assert_eq!(user_id("<11>nginx[1]: exact"), Some(8));
+        assert_eq!(user_id("<11>nginx-proxy: no rule"), None);
+        assert_eq!(user_id("<11>1 - - - - - [z user_id=\"x\"] invalid"), None);
+    }
+
+    #[test]
+    fn restricts_sources() {
+        let mut config = SyslogConfig {
+            udp: None,
+            tcp: None,
+            user_id_param: DEFAULT_USER_ID_PARAM.into(),
+            apps: Vec::new(),
+            allow_unauthenticated: false,
+            allowed_sources: Vec::new(),
+        };
+        assert!(config.allows("203.0.113.9".parse().unwrap()));
+
+        config.allowed_sources = vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+        assert!(config.allows("10.1.2.3".parse().unwrap()));
+        assert!(config.allows("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(config.allows("fd00::1".parse().unwrap()));
+        assert!(!config.allows("203.0.113.9".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reads_tcp_frames() {
+        let mut stream = "11 <11>1 - - -\n<12>counted\n<13>last".as_bytes();
+
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut stream).await.unwrap() {
+            frames.push(String::from_utf8(frame).unwrap());
+        }
+        assert_eq!(frames, ["<11>1 - - -", "\n", "<12>counted\n", "<13>last"]);
+
+        assert!(read_frame(&mut "999999 <11>".as_bytes()).await.is_err());
+    }
+}
@@ -0,0 +1,408 @@
+//! Raw TCP ingestion protocol.
+//!
+//! A connection carries frames, each a big-endian `u32` length followed by that many bytes of
+//! bincode. The client opens with [`ClientFrame::Hello`] and its token, the server answers
+//! [`ServerFrame::Ready`]. The client then sends batches of `(user_id, log)` pairs, every log
+//! bincode encoded as in the `serialized` field of the HTTP ingest request, and may send more
+//! batches before the previous ones are acknowledged. The server acknowledges each batch in order
+//! once its logs are written, listing the ones it refused. Fatal errors are sent as
+//! [`ServerFrame::Error`] before the connection is closed.
+//!
+//! Until the client is authenticated its frames are capped at [`MAX_HELLO_BYTES`] and it has
+//! [`HANDSHAKE_TIMEOUT`] to send its hello. At most [`MAX_CONNECTIONS`] are served at once, the
+//! next ones wait in the listen backlog.
+
+use std::{future::Future, io, sync::Arc, time::Duration};
+
+use futures_util::future::BoxFuture;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+};
+
+use crate::auth::{Auth, Scope};
+
+/// Largest frame accepted, in bytes.
+pub const MAX_FRAME_BYTES: u32 = 16 * 1024 * 1024;
+
+/// Largest frame accepted before the client is authenticated, in bytes.
+pub const MAX_HELLO_BYTES: u32 = 4 * 1024;
+
+/// Time a client has to send its hello after connecting.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most connections served at once.
+pub const MAX_CONNECTIONS: usize = 1024;
+
+/// Most logs in a batch.
+pub const MAX_BATCH_LOGS: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ClientFrame {
+    /// First frame of a connection, `token` is ignored if authentication is disabled.
+    Hello { token: Option<String> },
+    /// Bincode encoded logs with the user they belong to.
+    Batch { sequence: u64, logs: Vec<(i64, Vec<u8>)> },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ServerFrame {
+    Ready,
+    Ack { sequence: u64, refused: Vec<Refused> },
+    /// The connection is closed after it.
+    Error(String),
+}
+
+/// Log of a batch that wasn't written, by its index in the batch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Refused {
+    pub index: u32,
+    /// `forbidden`, or the reason given by the ingest function, e.g. `invalid_payload`.
+    pub reason: String,
+}
+
+/// Checks and writes a bincode encoded log of a user, or gives the reason it was refused.
+pub type Ingest = Arc<dyn Fn(i64, Vec<u8>) -> BoxFuture<'static, Result<(), &'static str>> + Send + Sync>;
+
+/// Next frame, `None` if the stream ended between frames.
+pub async fn read_frame<T: DeserializeOwned, R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<T>> {
+    read_frame_within(reader, MAX_FRAME_BYTES).await
+}
+
+/// Next frame of at most `max_bytes`, refused before anything is allocated for it.
+async fn read_frame_within<T: DeserializeOwned, R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_bytes: u32,
+) -> io::Result<Option<T>> {
+    let length = match reader.read_u32().await {
+        Ok(length) => length,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if length > max_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is over {}", length, max_bytes),
+        ));
+    }
+
+    let mut frame = vec![0; length as usize];
+    reader.read_exact(&mut frame).await?;
+
+    bincode::deserialize(&frame)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes and flushes a frame.
+pub async fn write_frame<T: Serialize, W: AsyncWrite + Unpin>(writer: &mut W, frame: &T) -> io::Result<()> {
+    let frame = bincode::serialize(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let length = u32::try_from(frame.len())
+        .ok()
+        .filter(|length| *length <= MAX_FRAME_BYTES)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+
+    writer.write_u32(length).await?;
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Serves connections on `listener` until `shutdown` completes, then lets every connection finish
+/// the batch it is writing and returns once they are closed. Stops accepting while
+/// [`MAX_CONNECTIONS`] are open.
+pub async fn serve(listener: TcpListener, auth: Arc<Auth>, ingest: Ingest, shutdown: impl Future<Output = ()>) {
+    // Every connection stops when the sender is dropped.
+    let (stop, stopped) = watch::channel(());
+    let mut connections = JoinSet::new();
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept(), if connections.len() < MAX_CONNECTIONS => match accepted {
+                Ok((stream, _)) => {
+                    let (auth, ingest, mut stopped) = (auth.clone(), ingest.clone(), stopped.clone());
+                    connections.spawn(async move {
+                        if let Err(e) = serve_connection(stream, &auth, &ingest, stopped.changed()).await {
+                            eprintln!("tcp ingest error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("tcp ingest error: {}", e),
+            },
+            // Reaps the finished connections.
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(stop);
+    while connections.join_next().await.is_some() {}
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    auth: &Auth,
+    ingest: &Ingest,
+    stopped: impl Future,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+
+    tokio::pin!(stopped);
+    let hello = tokio::select! {
+        frame = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame_within(&mut reader, MAX_HELLO_BYTES)) => match frame {
+            Ok(frame) => frame?,
+            Err(_) => return write_frame(&mut writer, &ServerFrame::Error("hello timed out".into())).await,
+        },
+        _ = &mut stopped => return Ok(()),
+    };
+
+    let scope = match hello {
+        Some(ClientFrame::Hello { token }) => auth.verify(token.as_deref()),
+        Some(ClientFrame::Batch { .. }) => {
+            return write_frame(&mut writer, &ServerFrame::Error("expected hello".into())).await;
+        }
+        None => return Ok(()),
+    };
+    let Some(scope) = scope else {
+        return write_frame(&mut writer, &ServerFrame::Error("unauthorized".into())).await;
+    };
+    write_frame(&mut writer, &ServerFrame::Ready).await?;
+
+    loop {
+        // A batch cut off by the shutdown is not acknowledged, for the client to send it again
+        // once reconnected.
+        let frame = tokio::select! {
+            frame = read_frame(&mut reader) => frame?,
+            _ = &mut stopped => return Ok(()),
+        };
+
+        match frame {
+            Some(ClientFrame::Batch { sequence, logs }) => {
+                if logs.len() > MAX_BATCH_LOGS {
+                    let error = format!("batch of {} logs is over {}", logs.len(), MAX_BATCH_LOGS);
+                    return write_frame(&mut writer, &ServerFrame::Error(error)).await;
+                }
+
+                let refused = write_batch(scope, ingest, logs).await;
+                write_frame(&mut writer, &ServerFrame::Ack { sequence, refused }).await?;
+            }
+            Some(ClientFrame::Hello { .. }) => {
+                return write_frame(&mut writer, &ServerFrame::Error("unexpected hello".into())).await;
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+async fn write_batch(scope: Scope, ingest: &Ingest, logs: Vec<(i64, Vec<u8>)>) -> Vec<Refused> {
+    let mut refused = Vec::new();
+
+    for (index, (user_id, log)) in logs.into_iter().enumerate() {
+        let result = match scope.can_access(user_id) {
+            true => ingest(user_id, log).await,
+            false => Err("forbidden"),
+        };
+
+        if let Err(reason) = result {
+            refused.push(Refused {
+                index: index as u32,
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    refused
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use futures_util::FutureExt;
+    use tokio::{
+        io::BufStream,
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+    };
+
+    use super::{read_frame, serve, write_frame, ClientFrame, Ingest, Refused, ServerFrame, MAX_HELLO_BYTES};
+    use crate::auth::{Auth, Scope};
+
+    /// Server writing the logs it accepts to the returned vector, and a sender shutting it down.
+    async fn server(auth: Auth) -> (std::net::SocketAddr, Arc<Mutex<Vec<(i64, Vec<u8>)>>>, oneshot::Sender<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let written = Arc::new(Mutex::new(Vec::new()));
+
+        let ingest: Ingest = Arc::new({
+            let written = written.clone();
+            move |user_id, log| {
+                let written = written.clone();
+                async move {
+                    if log.is_empty() {
+                        return Err("invalid_payload");
+                    }
+                    written.lock().unwrap().push((user_id, log));
+                    Ok(())
+                }
+                .boxed()
+            }
+        });
+
+        let (stop, stopped) = oneshot::channel();
+        tokio::spawn(serve(listener, Arc::new(auth), ingest, stopped.map(|_| ())));
+
+        (addr, written, stop)
+    }
+
+    async fn connect(addr: std::net::SocketAddr, token: Option<String>) -> (BufStream<TcpStream>, ServerFrame) {
+        let mut stream = BufStream::new(TcpStream::connect(addr).await.unwrap());
+        write_frame(&mut stream, &ClientFrame::Hello { token }).await.unwrap();
+        let reply = read_frame(&mut stream).await.unwrap().unwrap();
+
+        (stream, reply)
+    }
+
+    #[tokio::test]
+    async fn acknowledges_batches() {
+        let auth = Auth::new("secret");
+        let token = auth.issue(Scope::User(5));
+        let (addr, written, stop) = server(auth).await;
+
+        let (mut stream, reply) = connect(addr, token).await;
+        assert_eq!(reply, ServerFrame::Ready);
+
+        // Pipelined batches are acknowledged in order.
+        for sequence in 0..2 {
+            let logs = vec![(5, vec![sequence as u8 + 1]), (6, vec![1]), (5, vec![])];
+            write_frame(&mut stream, &ClientFrame::Batch { sequence, logs }).await.unwrap();
+        }
+        for sequence in 0..2 {
+            let ack = read_frame::<ServerFrame, _>(&mut stream).await.unwrap().unwrap();
+            let refused = vec![
+                Refused { index: 1, reason: "forbidden".into() },
+                Refused { index: 2, reason: "invalid_payload".into() },
+            ];
+            assert_eq!(ack, ServerFrame::Ack { sequence, refused });
+        }
+        assert_eq!(*written.lock().unwrap(), [(5, vec![1]), (5, vec![2])]);
+
+        // Connections are closed on shutdown.
+        stop.send(()).unwrap();
+        assert_eq!(read_frame::<ServerFrame, _>(&mut stream).await.unwrap(), None);
+    }
+
+    #[cfg(feature = "sdk")]
+    #[tokio::test]
+    async fn batches_client_logs() {
+        use crate::{LogLevel, MercuryLog, TcpLoggingClient};
+
+        let auth = Auth::new("secret");
+        let token = auth.issue(Scope::User(5));
+        let (addr, written, _stop) = server(auth).await;
+
+        let mut client = TcpLoggingClient::connect(addr, token).await.unwrap().with_batch_size(2);
+        for (index, user_id) in [5, 5, 6, 5, 6].into_iter().enumerate() {
+            let number = client.send_log(user_id, LogLevel::Debug, index.to_string()).await.unwrap();
+            assert_eq!(number, index as u64);
+        }
+
+        let refused = client.flush().await.unwrap();
+        assert_eq!(refused, [(2, "forbidden".to_string()), (4, "forbidden".to_string())]);
+
+        let messages = written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, log)| bincode::deserialize::<MercuryLog>(log).unwrap().message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["0", "1", "3"]);
+        assert!(client.flush().await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "sdk")]
+    #[tokio::test]
+    async fn macro_records_calling_module() {
+        use crate::{LogLevel, MercuryLog, TcpLoggingClient};
+
+        let auth = Auth::new("secret");
+        let token = auth.issue(Scope::User(5));
+        let (addr, written, _stop) = server(auth).await;
+
+        let mut client = TcpLoggingClient::connect(addr, token).await.unwrap();
+        crate::send_log!(client, 5, LogLevel::Error, "failed".to_string()).await.unwrap();
+        assert!(client.flush().await.unwrap().is_empty());
+
+        let (_, log) = written.lock().unwrap()[0].clone();
+        let source = bincode::deserialize::<MercuryLog>(&log).unwrap().source.unwrap();
+        assert_eq!(source.target.as_deref(), Some(module_path!()));
+        assert_eq!(source.file.as_deref(), Some(file!()));
+    }
+
+    #[cfg(feature = "sdk")]
+    #[tokio::test]
+    async fn resends_unacknowledged_batches() {
+        use crate::{LogLevel, TcpLoggingClient};
+
+        // Drops the first connection after reading a batch, acknowledges it on the second one.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+
+            for attempt in 0..2 {
+                let mut stream = BufStream::new(listener.accept().await.unwrap().0);
+                read_frame::<ClientFrame, _>(&mut stream).await.unwrap();
+                write_frame(&mut stream, &ServerFrame::Ready).await.unwrap();
+
+                let Some(ClientFrame::Batch { sequence, logs }) = read_frame(&mut stream).await.unwrap() else {
+                    panic!("expected a batch")
+                };
+                received.push((sequence, logs.len()));
+                if attempt == 1 {
+                    let ack = ServerFrame::Ack { sequence, refused: vec![] };
+                    write_frame(&mut stream, &ack).await.unwrap();
+                }
+            }
+
+            received
+        });
+
+        let mut client = TcpLoggingClient::connect(addr, None).await.unwrap().with_batch_size(2);
+        for _ in 0..2 {
+            client.send_log(5, LogLevel::Debug, "retried".into()).await.unwrap();
+        }
+        assert!(client.flush().await.is_err());
+
+        client.reconnect().await.unwrap();
+        assert!(client.flush().await.unwrap().is_empty());
+        assert_eq!(server.await.unwrap(), [(0, 2), (0, 2)]);
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_tokens() {
+        let (addr, _, _stop) = server(Auth::new("secret")).await;
+
+        let (_, reply) = connect(addr, Some("5.00".into())).await;
+        assert_eq!(reply, ServerFrame::Error("unauthorized".into()));
+
+        let (mut stream, reply) = connect(addr, Auth::new("secret").issue(Scope::Admin)).await;
+        assert_eq!(reply, ServerFrame::Ready);
+        write_frame(&mut stream, &ClientFrame::Hello { token: None }).await.unwrap();
+        assert_eq!(
+            read_frame::<ServerFrame, _>(&mut stream).await.unwrap(),
+            Some(ServerFrame::Error("unexpected hello".into()))
+        );
+
+        // Large frames are refused before authentication, the connection is closed (or reset)
+        // without reading them.
+        let token = Some("x".repeat(MAX_HELLO_BYTES as usize));
+        let mut stream = BufStream::new(TcpStream::connect(addr).await.unwrap());
+        write_frame(&mut stream, &ClientFrame::Hello { token }).await.unwrap();
+        assert!(!matches!(read_frame::<ServerFrame, _>(&mut stream).await, Ok(Some(_))));
+    }
+}
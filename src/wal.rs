@@ -0,0 +1,519 @@
+//! Write-ahead log of the in-memory logger.
+//!
+//! Every write and logging toggle accepted by a [`LoggerMemory`] with a WAL is appended to the
+//! current segment file (`{sequence}.wal`, length-prefixed bincode records) before being applied.
+//! At startup the segments newer than the restored snapshot are replayed, and saving a snapshot
+//! starts a new segment and deletes the ones it covers.
+//!
+//! Records are written while the logger's state is locked, so that they are in the order the
+//! operations are applied, but synced after it is released: writers waiting for a sync share
+//! the next one instead of each syncing in turn.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{IsLog, LogLevel, LoggerMemory};
+
+const SEGMENT_EXTENSION: &str = "wal";
+
+/// How long the WAL is reported unhealthy after a failed append or sync.
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Segments are rotated once they grow past this size unless configured otherwise.
+pub const DEFAULT_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// When appended records are flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fsync {
+    /// After every record, nothing accepted is lost on a crash.
+    Always,
+    /// At most once per interval, on a crash up to one interval of records can be lost. Appends
+    /// sync once the interval has passed, the service also calls [`Wal::sync`] every interval so
+    /// that the last records are synced when no more come.
+    Periodic(Duration),
+    /// Left to the operating system.
+    Never,
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    /// `always`, `never` or the interval of periodic syncs in milliseconds, e.g. `100ms`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => s
+                .strip_suffix("ms")
+                .and_then(|millis| millis.parse().ok())
+                .filter(|millis| *millis > 0)
+                .map(|millis| Self::Periodic(Duration::from_millis(millis)))
+                .ok_or_else(|| format!("invalid fsync policy {}, expected always, never or {{n}}ms", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalOptions {
+    pub segment_bytes: u64,
+    pub fsync: Fsync,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            fsync: Fsync::Always,
+        }
+    }
+}
+
+/// A journaled operation. Written with `T = &L` and read back with `T = L`.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum WalRecord<T> {
+    Write { user_id: i64, time: i64, log: T },
+    Logging(i64),
+    NotLogging(i64),
+    /// A write stored at `level` rather than the log's own level.
+    WriteAt { user_id: i64, time: i64, level: LogLevel, log: T },
+}
+
+/// End of an appended record. Ordered by segment, then offset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Position {
+    sequence: u64,
+    offset: u64,
+}
+
+struct Segment {
+    sequence: u64,
+    file: Arc<File>,
+    /// Bytes of the complete records.
+    len: u64,
+    synced_at: Instant,
+    /// A failed append may have left part of its frame after `len`.
+    torn: bool,
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    options: WalOptions,
+    segment: Mutex<Segment>,
+    /// Every record before it is on disk. Held while syncing, so that the writers waiting for a
+    /// sync are all covered by the next one.
+    synced: Mutex<Position>,
+}
+
+fn segment_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(format!("{:016}.{}", sequence, SEGMENT_EXTENSION))
+}
+
+/// Sequence numbers of the segments in `dir`, in order.
+fn segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut sequences = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION) {
+            if let Some(sequence) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+                sequences.push(sequence)
+            }
+        }
+    }
+
+    sequences.sort_unstable();
+    Ok(sequences)
+}
+
+fn create_segment(dir: &Path, sequence: u64) -> io::Result<Segment> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, sequence))?;
+
+    Ok(Segment {
+        sequence,
+        file: Arc::new(file),
+        len: 0,
+        synced_at: Instant::now(),
+        torn: false,
+    })
+}
+
+impl Wal {
+    /// Opens the WAL in `dir`, creating it if needed. Appends go to a new segment so that a
+    /// segment torn by a crash is never written to again.
+    pub fn open(dir: impl Into<PathBuf>, options: WalOptions) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let next = segments(&dir)?.last().map_or(1, |last| last + 1);
+        let segment = create_segment(&dir, next)?;
+
+        Ok(Self {
+            dir,
+            options,
+            segment: Mutex::new(segment),
+            synced: Mutex::new(Position::default()),
+        })
+    }
+
+    /// Writes `record` to the current segment. Returns its end if it must be synced with
+    /// [`Wal::sync_to`] before being acknowledged.
+    fn append(&self, record: &[u8]) -> io::Result<Option<Position>> {
+        let mut segment = self.segment.lock().unwrap();
+
+        if segment.torn {
+            self.repair(&mut segment)?;
+        }
+
+        if segment.len > 0 && segment.len + record.len() as u64 > self.options.segment_bytes {
+            segment.file.sync_data()?;
+            *segment = create_segment(&self.dir, segment.sequence + 1)?;
+        }
+
+        let mut frame = (record.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(record);
+        if let Err(e) = (&*segment.file).write_all(&frame) {
+            segment.torn = true;
+            // Left as is, the partial frame would hide every record appended after it from replays.
+            // If this fails too, the next append tries again before writing.
+            let _ = self.repair(&mut segment);
+            return Err(e);
+        }
+        segment.len += frame.len() as u64;
+
+        let sync = match self.options.fsync {
+            Fsync::Always => true,
+            Fsync::Periodic(interval) => segment.synced_at.elapsed() >= interval,
+            Fsync::Never => false,
+        };
+        if sync {
+            segment.synced_at = Instant::now();
+        }
+
+        Ok(sync.then_some(Position {
+            sequence: segment.sequence,
+            offset: segment.len,
+        }))
+    }
+
+    /// Syncs the current segment unless a sync since the record ending at `position` was
+    /// appended already covered it. Blocks while another sync is in progress.
+    pub(crate) fn sync_to(&self, position: Position) -> io::Result<()> {
+        let mut synced = self.synced.lock().unwrap();
+        if *synced >= position {
+            return Ok(());
+        }
+
+        // Earlier segments were synced when they were rotated.
+        let (file, end) = {
+            let segment = self.segment.lock().unwrap();
+            let end = Position {
+                sequence: segment.sequence,
+                offset: segment.len,
+            };
+            (segment.file.clone(), end)
+        };
+
+        file.sync_data()?;
+        *synced = end;
+
+        Ok(())
+    }
+
+    /// Cuts the partial frame off the end of `segment`, or moves on to a new segment if it cannot
+    /// be truncated.
+    fn repair(&self, segment: &mut Segment) -> io::Result<()> {
+        if segment.file.set_len(segment.len).is_err() {
+            segment.file.sync_data()?;
+            *segment = create_segment(&self.dir, segment.sequence + 1)?;
+        }
+
+        segment.torn = false;
+        Ok(())
+    }
+
+    /// Flushes the current segment to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        let end = {
+            let segment = self.segment.lock().unwrap();
+            Position {
+                sequence: segment.sequence,
+                offset: segment.len,
+            }
+        };
+
+        self.sync_to(end)
+    }
+
+    /// Starts a new segment and returns its sequence number: every earlier record is in the
+    /// segments before it.
+    pub(crate) fn rotate(&self) -> io::Result<u64> {
+        let mut segment = self.segment.lock().unwrap();
+        segment.file.sync_data()?;
+        *segment = create_segment(&self.dir, segment.sequence + 1)?;
+
+        Ok(segment.sequence)
+    }
+
+    /// Deletes the segments before `sequence`.
+    pub(crate) fn compact(&self, sequence: u64) -> io::Result<()> {
+        for old in segments(&self.dir)?.into_iter().filter(|old| *old < sequence) {
+            fs::remove_file(segment_path(&self.dir, old))?;
+        }
+
+        Ok(())
+    }
+
+    /// Records of the segments from `sequence` on. A torn record at the end of a segment is
+    /// cut off, it was never acknowledged.
+    fn read_since<L: DeserializeOwned>(&self, sequence: u64) -> io::Result<Vec<WalRecord<L>>> {
+        let mut records = Vec::new();
+
+        for segment in segments(&self.dir)?.into_iter().filter(|segment| *segment >= sequence) {
+            let path = segment_path(&self.dir, segment);
+            let contents = fs::read(&path)?;
+            let mut offset = 0;
+
+            while let Some(frame) = read_frame(&contents[offset..]) {
+                let Ok(record) = bincode::deserialize(frame) else {
+                    break;
+                };
+
+                records.push(record);
+                offset += 4 + frame.len();
+            }
+
+            if offset < contents.len() {
+                eprintln!("wal: truncating torn record at {}:{}", path.display(), offset);
+                OpenOptions::new().write(true).open(&path)?.set_len(offset as u64)?;
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+/// The record at the start of `bytes`, `None` if it's incomplete.
+fn read_frame(bytes: &[u8]) -> Option<&[u8]> {
+    let (len, record) = bytes.split_first_chunk::<4>()?;
+    record.get(..u32::from_le_bytes(*len) as usize)
+}
+
+/// A WAL with the encoder of the logger's log type, so that journaling doesn't require
+/// `L: Serialize` on every [`LoggerMemory`] method.
+pub(crate) struct Journal<L> {
+    pub(crate) wal: Arc<Wal>,
+    encode: fn(&WalRecord<&L>) -> bincode::Result<Vec<u8>>,
+    /// Last failed append or sync and when it happened. Later successes don't clear it, so that
+    /// intermittent failures are still reported.
+    failure: Mutex<Option<(Instant, String)>>,
+}
+
+impl<L> Journal<L> {
+    /// Appends `record`, the operation must not be applied if it fails. Returns the position to
+    /// [`Journal::sync`] once the state is unlocked.
+    pub(crate) fn append(&self, record: WalRecord<&L>) -> io::Result<Option<Position>> {
+        let appended = (self.encode)(&record)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+            .and_then(|record| self.wal.append(&record));
+
+        self.record(appended)
+    }
+
+    /// Waits until the record ending at `position` is on disk.
+    pub(crate) async fn sync(&self, position: Position) -> io::Result<()> {
+        let wal = self.wal.clone();
+        let synced = tokio::task::spawn_blocking(move || wal.sync_to(position))
+            .await
+            .unwrap_or_else(|error| Err(io::Error::other(error)));
+
+        self.record(synced)
+    }
+
+    fn record<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &result {
+            eprintln!("wal error: {}", e);
+            *self.failure.lock().unwrap() = Some((Instant::now(), e.to_string()));
+        }
+
+        result
+    }
+
+    /// Fails for [`FAILURE_WINDOW`] after any failed append or sync.
+    pub(crate) fn health(&self) -> Result<(), String> {
+        match &*self.failure.lock().unwrap() {
+            Some((failed_at, error)) if failed_at.elapsed() < FAILURE_WINDOW => {
+                Err(format!("{} ({:?} ago)", error, failed_at.elapsed()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<L: IsLog + Serialize + DeserializeOwned> LoggerMemory<L> {
+    /// Journals every write and toggle in `wal`.
+    pub fn with_wal(mut self, wal: Wal) -> Self {
+        self.wal = Some(Arc::new(Journal {
+            wal: Arc::new(wal),
+            encode: |record| bincode::serialize(record),
+            failure: Mutex::new(None),
+        }));
+        self
+    }
+
+    /// Applies the journaled operations from the segment `since` on, e.g. the one recorded in the
+    /// restored snapshot. Returns the number of operations replayed.
+    pub async fn replay_wal(&self, since: u64) -> io::Result<usize> {
+        let Some(journal) = &self.wal else {
+            return Ok(0);
+        };
+
+        let records = journal.wal.read_since::<L>(since)?;
+        let replayed = records.len();
+        let mut state = self.state.lock().await;
+
+        for record in records {
+            match record {
+                WalRecord::Write { user_id, time, log } => {
+                    self.apply_write(&mut state, user_id, time, log.level(), log);
+                }
+                WalRecord::WriteAt { user_id, time, level, log } => {
+                    self.apply_write(&mut state, user_id, time, level, log);
+                }
+                WalRecord::Logging(user_id) => Self::apply_logging(&mut state, user_id, true),
+                WalRecord::NotLogging(user_id) => Self::apply_logging(&mut state, user_id, false),
+            }
+        }
+        for user_logs in state.values_mut() {
+            self.seal(user_logs)
+        }
+
+        Ok(replayed)
+    }
+
+    /// Flushes the WAL to disk, if any.
+    pub fn sync_wal(&self) -> io::Result<()> {
+        match &self.wal {
+            Some(journal) => journal.wal.sync(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Fsync, Wal, WalOptions, WalRecord};
+    use crate::{
+        test::{log, TestLog},
+        LogLevel, LoggerMemory,
+    };
+
+    #[test]
+    fn parse_fsync() {
+        assert_eq!("always".parse(), Ok(Fsync::Always));
+        assert_eq!("250ms".parse(), Ok(Fsync::Periodic(std::time::Duration::from_millis(250))));
+        assert!("0ms".parse::<Fsync>().is_err());
+        assert!("sometimes".parse::<Fsync>().is_err());
+    }
+
+    #[test]
+    fn cuts_off_failed_appends() {
+        let dir = std::env::temp_dir().join(format!("zephyr-wal-torn-{}", std::process::id()));
+        let wal = Wal::open(&dir, WalOptions::default()).unwrap();
+        let record = |user_id| bincode::serialize(&WalRecord::<&TestLog>::Logging(user_id)).unwrap();
+
+        wal.append(&record(1)).unwrap();
+
+        // An append that failed halfway through its frame.
+        {
+            let mut segment = wal.segment.lock().unwrap();
+            std::io::Write::write_all(&mut segment.file, &[9, 0, 0, 0, 1]).unwrap();
+            segment.torn = true;
+        }
+
+        // Synced by default before being acknowledged.
+        let position = wal.append(&record(2)).unwrap().unwrap();
+        wal.sync_to(position).unwrap();
+        assert!(*wal.synced.lock().unwrap() >= position);
+
+        let replayed = wal
+            .read_since::<TestLog>(0)
+            .unwrap()
+            .into_iter()
+            .map(|record| match record {
+                WalRecord::Logging(user_id) => user_id,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(replayed, [1, 2]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failures_stay_reported() {
+        let dir = std::env::temp_dir().join(format!("zephyr-wal-failure-{}", std::process::id()));
+        let logger = LoggerMemory::<TestLog>::new().with_wal(Wal::open(&dir, WalOptions::default()).unwrap());
+        let journal = logger.wal.as_ref().unwrap();
+        assert!(journal.health().is_ok());
+
+        let _ = journal.record::<()>(Err(std::io::Error::other("disk full")));
+        journal.append(WalRecord::Logging(1)).unwrap();
+        assert!(journal.health().unwrap_err().starts_with("disk full"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_after_snapshot_and_compacts() {
+        let dir = std::env::temp_dir().join(format!("zephyr-wal-{}", std::process::id()));
+        let snapshot = dir.join("logs.snapshot");
+        let options = WalOptions {
+            segment_bytes: 64,
+            fsync: Fsync::Never,
+        };
+
+        let open = || LoggerMemory::<TestLog>::new().with_wal(Wal::open(&dir, options.clone()).unwrap());
+
+        let logger = open();
+        logger.write_log(1, log(LogLevel::Debug, "creates the group")).await.unwrap();
+        logger.is_logging(1).await.unwrap();
+        logger.write_log(1, log(LogLevel::Error, "before snapshot")).await.unwrap();
+        logger.save_snapshot(&snapshot).await.unwrap();
+        logger.write_log(1, log(LogLevel::Error, "after snapshot")).await.unwrap();
+        // Replayed at the forced level.
+        logger.write_warning(1, log(LogLevel::Debug, "in another segment")).await.unwrap();
+        logger.sync_wal().unwrap();
+
+        // Crash: a half-written record at the end of the last segment.
+        let last = super::segments(&dir).unwrap().into_iter().last().unwrap();
+        let mut segment = std::fs::OpenOptions::new()
+            .append(true)
+            .open(super::segment_path(&dir, last))
+            .unwrap();
+        std::io::Write::write_all(&mut segment, &[9, 0, 0, 0, 1]).unwrap();
+
+        let restored = open();
+        let checkpoint = restored.restore_snapshot(&snapshot).await.unwrap().unwrap().wal_segment;
+        assert_eq!(restored.replay_wal(checkpoint).await.unwrap(), 2);
+
+        let errors = restored.read_errros(1).await;
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1].inner.message, "after snapshot");
+        assert_eq!(restored.read_warning(1).await.len(), 1);
+        assert!(restored.read_debug(1).await.is_empty());
+        assert!(super::segments(&dir).unwrap().iter().all(|segment| *segment >= checkpoint));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}